@@ -1,6 +1,14 @@
 use super::*;
 
+use std::collections::BTreeMap;
+
+use super::serde::de::{self, Deserializer};
+
+use imap_extention::search::SEARCH;
+use imap_extention::bodystructure::BodyPart;
+
 static CONFIG_FILE: &'static str = "default.toml";
+pub static DEFAULT_ACCOUNT: &'static str = "default";
 
 lazy_static! {
     pub static ref CONFIG: Config = {
@@ -22,19 +30,35 @@ fn read_config(config_file: &str, config: Config) -> Config {
 pub struct Config {
     pub service: bool,
     pub sleep_time: u64,
+    idle: Option<bool>, // Should be true by default
     mark_mail_as_seen: Option<bool>, // Should be true by default
+    keyword: Option<String>, // Should be None by default, e.g. $Imap2Slack
     debug: Option<bool>, // Should be false default
     debug_imap: Option<bool>, // Should be false default
-    pub mail: Mail,
+    state_file: Option<String>, // Should be state.sqlite by default
+    fetch_batch_size: Option<usize>, // Should be 50 by default
+    max_fetch_bytes: Option<u64>, // Should be 1048576 by default
+    mail: Accounts,
     pub slack: Slack,
+    reconnect: Option<Reconnect>,
+    proxy: Option<Proxy>,
     pub publish: Vec<Publish>,
 }
 
 impl Config {
+    pub fn idle(&self) -> bool {
+        self.idle.unwrap_or(true)
+    }
+
     pub fn mark_mail_as_seen(&self) -> bool {
         self.mark_mail_as_seen.unwrap_or(true)
     }
 
+    /// The keyword to mark posted mails with instead of `\Seen`.
+    pub fn keyword(&self) -> Option<&str> {
+        self.keyword.as_ref().map(|k| k.as_str())
+    }
+
     pub fn debug(&self) -> bool {
         self.debug.unwrap_or(false)
     }
@@ -42,14 +66,194 @@ impl Config {
     pub fn debug_imap(&self) -> bool {
         self.debug_imap.unwrap_or(false)
     }
+
+    pub fn state_file(&self) -> &str {
+        match &self.state_file {
+            &Some(ref state_file) => state_file.as_str(),
+            &None => "state.sqlite",
+        }
+    }
+
+    pub fn fetch_batch_size(&self) -> usize {
+        self.fetch_batch_size.unwrap_or(50).max(1)
+    }
+
+    /// The most bytes fetched of the header and of the text of a mail, 0 means no limit.
+    pub fn max_fetch_bytes(&self) -> u64 {
+        self.max_fetch_bytes.unwrap_or(1048576)
+    }
+
+    /// All the accounts from the config file. A single `[mail]` section is the account `default`.
+    pub fn accounts(&self) -> Vec<(&str, &Mail)> {
+        match &self.mail {
+            &Accounts::Single(ref mail) => vec![(DEFAULT_ACCOUNT, mail)],
+            &Accounts::Named(ref accounts) => accounts.iter().map(|(name, mail)| (name.as_str(), mail)).collect(),
+        }
+    }
+
+    pub fn account(&self, name: &str) -> Option<&Mail> {
+        self.accounts().into_iter().find(|&(n, _)| n == name).map(|(_, mail)| mail)
+    }
+
+    pub fn reconnect(&self) -> Reconnect {
+        self.reconnect.clone().unwrap_or(Reconnect {
+            initial_delay: None,
+            max_delay: None,
+            multiplier: None,
+            jitter: None,
+        })
+    }
+
+    /// The proxy to reach `host` through, `None` to connect directly.
+    pub fn proxy(&self, host: &str) -> Option<&ProxyServer> {
+        let proxy = match &self.proxy {
+            &Some(ref proxy) => proxy,
+            &None => return None,
+        };
+
+        let server = proxy.destination.as_ref()
+            .and_then(|destination| destination.iter().find(|&(name, _)| name.eq_ignore_ascii_case(host)))
+            .map(|(_, server)| server)
+            .unwrap_or(&proxy.server);
+
+        if server.url.eq_ignore_ascii_case("direct") {
+            None
+        } else {
+            Some(server)
+        }
+    }
+
+    /// Every proxy in the config file, to check them at startup.
+    pub fn proxies(&self) -> Vec<&ProxyServer> {
+        match &self.proxy {
+            &Some(ref proxy) => {
+                let mut r = vec![&proxy.server];
+                if let Some(ref destination) = proxy.destination {
+                    r.extend(destination.values());
+                }
+                r.into_iter().filter(|server| !server.url.eq_ignore_ascii_case("direct")).collect()
+            },
+            &None => Vec::new(),
+        }
+    }
+}
+
+/// Either a single `[mail]` section, or named accounts as `[mail.<name>]` sections.
+#[derive(Serialize,Clone,Debug)]
+#[serde(untagged)]
+pub enum Accounts {
+    Single(Mail),
+    Named(BTreeMap<String, Mail>),
+}
+
+/// Not derived with `untagged`, because then every mistake in `[mail]` is reported as not matching any
+/// variant. The choice is made here, so the error of the `Mail` itself reaches `error_handler`.
+impl<'de> Deserialize<'de> for Accounts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Accounts, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+
+        // `[mail.<name>]` has only tables, `[mail]` has the settings, and maybe the tables `tls` and `oauth2`
+        let named = match &value {
+            &toml::Value::Table(ref table) => !table.is_empty() && table.values().all(|v| v.is_table())
+                && !table.contains_key("tls") && !table.contains_key("oauth2"),
+            _ => false,
+        };
+
+        match value {
+            toml::Value::Table(table) if named => {
+                let mut accounts = BTreeMap::new();
+                for (name, mail) in table {
+                    let mail = mail.try_into()
+                        .map_err(|e| de::Error::custom(format!("{} for the account '{}'", error_message(e), name)))?;
+                    accounts.insert(name, mail);
+                }
+                Ok(Accounts::Named(accounts))
+            },
+            value => value.try_into().map(Accounts::Single).map_err(|e| de::Error::custom(error_message(e))),
+        }
+    }
+}
+
+/// The message of an error, without the position which `error_handler` adds itself.
+fn error_message(e: toml::de::Error) -> String {
+    if e.inner.message.is_empty() { e.to_string() } else { e.inner.message.clone() }
 }
 
 #[derive(Deserialize,Serialize,Clone,Debug)]
 pub struct Mail {
     pub imap: String,
     pub port: u16,
+    security: Option<Security>, // Should be tls by default
+    allow_plaintext_login: Option<bool>, // Should be false by default
+    auth: Option<Auth>, // Should be login by default
     pub username: String,
     pub password: String,
+    pub tls: Option<Tls>,
+    pub oauth2: Option<OAuth2>,
+}
+
+impl Mail {
+    pub fn security(&self) -> Security {
+        self.security.clone().unwrap_or(Security::Tls)
+    }
+
+    pub fn allow_plaintext_login(&self) -> bool {
+        self.allow_plaintext_login.unwrap_or(false)
+    }
+
+    pub fn auth(&self) -> Auth {
+        self.auth.clone().unwrap_or(Auth::Login)
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct Tls {
+    pub ca_file: Option<String>,
+    pub pinned_sha256: Option<Vec<String>>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub client_cert_password: Option<String>,
+    danger_accept_invalid_certs: Option<bool>, // Should be false by default
+}
+
+impl Tls {
+    pub fn danger_accept_invalid_certs(&self) -> bool {
+        self.danger_accept_invalid_certs.unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    Login,
+    Xoauth2,
+    Oauthbearer,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct OAuth2 {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    token_file: Option<String>, // Should be oauth2_token.toml (or oauth2_token_<account>.toml) by default
+}
+
+impl OAuth2 {
+    pub fn token_file(&self, account: &str) -> String {
+        match &self.token_file {
+            &Some(ref token_file) => token_file.clone(),
+            &None if account == DEFAULT_ACCOUNT => "oauth2_token.toml".to_string(),
+            &None => format!("oauth2_token_{}.toml", account),
+        }
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    Tls,
+    Starttls,
+    Plain,
 }
 
 #[derive(Deserialize,Serialize,Clone,Debug)]
@@ -57,16 +261,196 @@ pub struct Slack {
     pub webhook: String,
     pub username: String,
     pub emoji: String,
+    pub token: Option<String>, // Only needed to upload attachments
+    api_url: Option<String>, // Should be https://slack.com/api by default
+}
+
+impl Slack {
+    pub fn api_url(&self) -> &str {
+        self.api_url.as_ref().map_or("https://slack.com/api", |u| u.trim_end_matches('/'))
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct Reconnect {
+    initial_delay: Option<u64>, // Should be 5 seconds by default
+    max_delay: Option<u64>, // Should be 600 seconds by default
+    multiplier: Option<f64>, // Should be 2 by default
+    jitter: Option<f64>, // Should be 0.2 by default
+}
+
+impl Reconnect {
+    pub fn initial_delay(&self) -> u64 {
+        self.initial_delay.unwrap_or(5)
+    }
+
+    pub fn max_delay(&self) -> u64 {
+        self.max_delay.unwrap_or(600)
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier.unwrap_or(2.0).max(1.0)
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter.unwrap_or(0.2).max(0.0).min(1.0)
+    }
+}
+
+/// `[proxy]` is used for every connection, unless the host has its own `[proxy.destination."<host>"]`.
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct Proxy {
+    #[serde(flatten)]
+    server: ProxyServer,
+    destination: Option<BTreeMap<String, ProxyServer>>,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct ProxyServer {
+    pub url: String, // `socks5://host:port`, `http://host:port` or `direct`
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+impl ProxyServer {
+    /// The kind, host and port of the proxy from its url.
+    pub fn address(&self) -> (ProxyKind, &str, u16) {
+        let (scheme, rest) = match self.url.find("://") {
+            Some(i) => (self.url[..i].to_lowercase(), &self.url[i + 3..]),
+            None => self.invalid(),
+        };
+
+        let kind = match scheme.as_ref() {
+            "socks5" | "socks5h" => ProxyKind::Socks5,
+            "http" => ProxyKind::Http,
+            _ => self.invalid(),
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.rfind(':') {
+            Some(i) => match rest[i + 1..].parse::<u16>() {
+                Ok(port) => (&rest[..i], port),
+                Err(_) => self.invalid(),
+            },
+            None => (rest, if kind == ProxyKind::Socks5 { 1080 } else { 8080 }),
+        };
+
+        if host.is_empty() {
+            self.invalid();
+        }
+        (kind, host.trim_start_matches('[').trim_end_matches(']'), port)
+    }
+
+    fn invalid(&self) -> ! {
+        println!("The proxy '{}' is not valid, it should look like 'socks5://host:port' or 'http://host:port'", &self.url);
+        ::std::process::exit(1);
+    }
 }
 
 #[derive(Deserialize,Serialize,Clone,Debug)]
 pub struct Publish {
+    account: Option<String>,
     pub mailbox: String,
+    subscribed_only: Option<bool>, // Should be false by default
+    search: Option<String>, // Should be UNSEEN by default
     pub channel: Vec<String>,
     filter: Option<String>,
+    prefer_html: Option<bool>, // Should be false by default
+    pub attachments: Option<Attachments>,
+    pub actions: Option<Vec<PostAction>>,
+}
+
+/// What to do with the attachments of a mail.
+#[derive(Deserialize,Serialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentPolicy {
+    None,
+    List,
+    Upload,
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct Attachments {
+    pub policy: AttachmentPolicy,
+    max_size: Option<u64>, // Should be 10 MB by default
+    mime_types: Option<Vec<String>>, // Should be every type by default
+}
+
+impl Attachments {
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(10485760)
+    }
+
+    /// If the attachment may be uploaded: it is not larger than `max_size` and its type is in `mime_types`,
+    /// where `image/*` matches every image.
+    pub fn allows(&self, part: &BodyPart) -> bool {
+        if part.decoded_size() > self.max_size() {
+            return false;
+        }
+
+        match &self.mime_types {
+            &Some(ref mime_types) => mime_types.iter().any(|t| {
+                let t = t.trim().to_lowercase();
+                t == "*/*" || t == part.mimetype || (t.ends_with("/*") && part.mimetype.starts_with(&t[..t.len() - 1]))
+            }),
+            &None => true,
+        }
+    }
+}
+
+/// What to do with a mail on the server, after it has been posted to every channel.
+#[derive(Deserialize,Serialize,Clone,Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum PostAction {
+    Move { mailbox: String },
+    Copy { mailbox: String },
+    Keyword { keyword: String },
+    Delete,
 }
 
 impl Publish {
+    pub fn subscribed_only(&self) -> bool {
+        self.subscribed_only.unwrap_or(false)
+    }
+
+    pub fn prefer_html(&self) -> bool {
+        self.prefer_html.unwrap_or(false)
+    }
+
+    pub fn attachment_policy(&self) -> AttachmentPolicy {
+        self.attachments.as_ref().map_or(AttachmentPolicy::None, |a| a.policy)
+    }
+
+    pub fn search(&self) -> Vec<SEARCH> {
+        match &self.search {
+            &Some(ref search) => {
+                match SEARCH::parse(search) {
+                    Ok(search) => search,
+                    Err(e) => {
+                        println!("The search '{}' of the mailbox '{}' is not valid: {}", search, &self.mailbox, e);
+                        ::std::process::exit(1);
+                    }
+                }
+            },
+            // The keyword marks the posted mails, so mails which have been read already are posted too
+            &None if CONFIG.keyword().is_some() => vec![SEARCH::ALL],
+            &None => vec![SEARCH::UNSEEN],
+        }
+    }
+
+    pub fn account(&self) -> &str {
+        match &self.account {
+            &Some(ref account) => account.as_str(),
+            &None => DEFAULT_ACCOUNT,
+        }
+    }
+
     pub fn filter(&self) -> Option<&filter::Filter> {
         match &self.filter {
             &Some(ref f) => {
@@ -76,6 +460,7 @@ impl Publish {
                         let mut config = FILTER.clone();
                         config.filter.insert(f.to_string(), filter::Filter {
                             case_sensitive: false,
+                            field: None,
                             contains: Some(vec!["".to_string()]),
                             does_not_contains: Some(vec!["".to_string()]),
                         });
@@ -108,29 +493,67 @@ fn config_template() -> Config {
     Config {
         service: true,
         sleep_time: 5,
+        idle: Some(true),
         mark_mail_as_seen: Some(true),
+        keyword: None,
         debug: Some(false),
         debug_imap: Some(false),
-        mail: Mail {
+        state_file: Some("state.sqlite".to_string()),
+        fetch_batch_size: Some(50),
+        max_fetch_bytes: Some(1048576),
+        mail: Accounts::Single(Mail {
             imap: "imap.domain.com".to_string(),
             port: 993,
+            security: Some(Security::Tls),
+            allow_plaintext_login: Some(false),
+            auth: Some(Auth::Login),
             username: "my@mail.com".to_string(),
             password: "*******".to_string(),
-        },
+            tls: None,
+            oauth2: None,
+        }),
         slack: Slack {
             webhook: "https://hooks.slack.com/services/xxx/yyy/zzz".to_string(),
             username: "BOT".to_string(),
             emoji: "+1".to_string(),
+            token: None,
+            api_url: None,
         },
+        reconnect: Some(Reconnect {
+            initial_delay: Some(5),
+            max_delay: Some(600),
+            multiplier: Some(2.0),
+            jitter: Some(0.2),
+        }),
+        proxy: None,
         publish: vec![
             Publish {
+                account: None,
                 mailbox: "Inbox".to_string(),
+                subscribed_only: None,
+                search: Some("UNSEEN".to_string()),
                 channel: vec!["#testing_1".to_string(), "#testing_2".to_string()],
                 filter: None,
+                prefer_html: None,
+                attachments: None,
+                actions: None,
             }, Publish {
+                account: None,
                 mailbox: "Archive".to_string(),
+                subscribed_only: None,
+                search: Some("UNSEEN NOT FROM \"noreply@domain.com\"".to_string()),
                 channel: vec!["#general".to_string()],
                 filter: Some("Filter_1".to_string()),
+                prefer_html: Some(true),
+                attachments: Some(Attachments {
+                    policy: AttachmentPolicy::List,
+                    max_size: None,
+                    mime_types: None,
+                }),
+                actions: Some(vec![
+                    PostAction::Keyword { keyword: "$Slacked".to_string() },
+                    PostAction::Move { mailbox: "Archive/Slacked".to_string() },
+                ]),
             }],
     }
 }
@@ -30,6 +30,7 @@ pub struct Config {
 #[derive(Deserialize,Serialize,Clone,Debug)]
 pub struct Filter {
     pub case_sensitive: bool,
+    pub field: Option<String>, // Should be "subject" by default
     pub contains: Option<Vec<String>>,
     pub does_not_contains: Option<Vec<String>>,
 }
@@ -48,6 +49,11 @@ impl WriteConfig for Config {
 }
 
 impl Filter {
+    /// The field of the mail the filter checks, see `Mail::field`.
+    pub fn field(&self) -> &str {
+        self.field.as_ref().map_or("subject", |f| f.as_str())
+    }
+
     pub fn check(&self, text: &String) -> bool {
         let mut r_contains: bool = true;
         let mut r_does_not_contains = true;
@@ -91,6 +97,7 @@ fn config_template() -> Config {
         filter: { let mut t = BTreeMap::new();
             t.insert("Filter_1".to_string(),Filter {
                 case_sensitive: false,
+                field: None,
                 contains: Some(vec!["[Something]".to_string()]),
                 does_not_contains: Some(vec!["TEST".to_string(), "REMINDER".to_string()]),
             });
@@ -63,7 +63,7 @@ fn path_config_dir() -> PathBuf {
     path_config_dir
 }
 
-fn path_config_file(filename: &str) -> PathBuf {
+pub fn path_config_file(filename: &str) -> PathBuf {
     let mut path_config_file = path_config_dir();
     path_config_file.push(filename);
     path_config_file
@@ -0,0 +1,345 @@
+extern crate rand;
+extern crate sha2;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::Duration;
+
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use self::sha2::{Digest, Sha256};
+use imap::client::{Client, Session};
+use imap::error::{Error, Result};
+use imap::extensions::idle::SetReadTimeout;
+
+use config::DEFAULT;
+use config::default::{Auth, Mail, Security, Tls};
+use oauth2::{access_token, OAuth2Authenticator};
+use proxy;
+
+pub type ImapSession = Session<ImapStream>;
+
+/// The stream below the IMAP client, so that every connection mode ends up with the same `Session` type.
+pub enum ImapStream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for ImapStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut ImapStream::Plain(ref mut stream) => stream.read(buf),
+            &mut ImapStream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ImapStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut ImapStream::Plain(ref mut stream) => stream.write(buf),
+            &mut ImapStream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut ImapStream::Plain(ref mut stream) => stream.flush(),
+            &mut ImapStream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl SetReadTimeout for ImapStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let tcp = match self {
+            &mut ImapStream::Plain(ref mut stream) => stream,
+            &mut ImapStream::Tls(ref mut stream) => stream.get_mut(),
+        };
+        tcp.set_read_timeout(timeout).map_err(Error::Io)
+    }
+}
+
+/// Connects and logs in to the IMAP server of the account, using the
+/// connection mode from `security`.
+pub fn connect(account: &str, mail: &Mail) -> Result<ImapSession> {
+    let domain: &str = &mail.imap;
+    let port: u16 = mail.port.clone();
+    let security = mail.security();
+
+    if security == Security::Plain && !mail.allow_plaintext_login() {
+        return Err(fatal(format!("Refusing to send the password unencrypted to '{}'. Set `allow_plaintext_login = true` for the account, if you really want to", domain)));
+    }
+
+    let mut tcp = proxy::connect(domain, port).map_err(Error::Io)?;
+
+    let stream = match security {
+        Security::Plain => ImapStream::Plain(tcp),
+        Security::Tls => ImapStream::Tls(tls_handshake(domain, tcp, &mail.tls)?),
+        Security::Starttls => {
+            starttls(&mut tcp)?;
+            ImapStream::Tls(tls_handshake(domain, tcp, &mail.tls)?)
+        },
+    };
+
+    let mut imap_socket = Client::new(stream);
+    imap_socket.debug = DEFAULT.debug_imap();
+
+    // The greeting has already been read, before the STARTTLS command was sent
+    if security != Security::Starttls {
+        imap_socket.read_greeting()?;
+    }
+
+    let session = match mail.auth() {
+        Auth::Login => imap_socket.login(&mail.username, &mail.password),
+        auth => {
+            let oauth2 = match &mail.oauth2 {
+                &Some(ref oauth2) => oauth2,
+                &None => return Err(fatal("You have to add an oauth2 section for the account to the config file, when logging in with OAuth2".to_string())),
+            };
+
+            let authenticator = OAuth2Authenticator::new(auth, &mail.username, access_token(account, oauth2)?);
+            let mechanism = authenticator.mechanism();
+            imap_socket.authenticate(mechanism, authenticator)
+        },
+    };
+
+    match session {
+        Ok(session) => Ok(session),
+        Err((e, _)) => Err(e),
+    }
+}
+
+fn tls_handshake(domain: &str, tcp: TcpStream, tls: &Option<Tls>) -> Result<TlsStream<TcpStream>> {
+    let ssl_connector = tls_connector(tls)?;
+
+    let stream = match ssl_connector.connect(domain, tcp) {
+        Ok(stream) => stream,
+        Err(e) => return Err(Error::TlsHandshake(e)),
+    };
+
+    if let &Some(ref tls) = tls {
+        if let &Some(ref pins) = &tls.pinned_sha256 {
+            check_pinned_certificate(domain, &stream, pins)?;
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Builds the `TlsConnector` from the `[mail.tls]` section. Without the section only the
+/// root certificates of the system are trusted.
+fn tls_connector(tls: &Option<Tls>) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+
+    let tls = match tls {
+        &Some(ref tls) => tls,
+        &None => return builder.build().map_err(Error::Tls),
+    };
+
+    if let &Some(ref ca_file) = &tls.ca_file {
+        for certificate in read_pem_certificates(ca_file)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let &Some(ref client_cert) = &tls.client_cert {
+        let data = read_tls_file(client_cert)?;
+        let identity = match &tls.client_key {
+            // PEM certificate (chain) with a separate PKCS#8 PEM key
+            &Some(ref client_key) => Identity::from_pkcs8(&data, &read_tls_file(client_key)?),
+            // PKCS#12 archive with both the certificate and the key
+            &None => Identity::from_pkcs12(&data, tls.client_cert_password.as_ref().map_or("", |p| p.as_str())),
+        };
+        let identity = identity.map_err(|e| fatal(format!("The client certificate '{}' is not valid: {}", client_cert, e)))?;
+        builder.identity(identity);
+    }
+
+    if tls.danger_accept_invalid_certs() {
+        println!("WARNING: `danger_accept_invalid_certs` is enabled, the certificate of the IMAP server is NOT validated");
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    builder.build().map_err(Error::Tls)
+}
+
+/// A CA file can contain more than one certificate, so every PEM block is added on its own.
+fn read_pem_certificates(path: &str) -> Result<Vec<Certificate>> {
+    let data = String::from_utf8_lossy(&read_tls_file(path)?).into_owned();
+    let mut certificates = Vec::new();
+
+    let end_marker = "-----END CERTIFICATE-----";
+    let mut rest = data.as_str();
+    while let Some(start) = rest.find("-----BEGIN CERTIFICATE-----") {
+        let end = match rest[start..].find(end_marker) {
+            Some(end) => start + end + end_marker.len(),
+            None => break,
+        };
+        certificates.push(Certificate::from_pem(rest[start..end].as_bytes())
+            .map_err(|e| fatal(format!("The CA file '{}' contains an invalid certificate: {}", path, e)))?);
+        rest = &rest[end..];
+    }
+
+    if certificates.is_empty() {
+        return Err(fatal(format!("No PEM certificates found in the CA file '{}'", path)));
+    }
+
+    Ok(certificates)
+}
+
+fn read_tls_file(path: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => Ok(data),
+        Err(e) => Err(fatal(format!("Failed to read the file '{}' from the [mail.tls] section: {}", path, e))),
+    }
+}
+
+/// Compares the SHA-256 fingerprint of the server certificate (DER) with the pinned fingerprints.
+/// A mismatch stops the account, because retrying will not make the certificate match.
+fn check_pinned_certificate(domain: &str, stream: &TlsStream<TcpStream>, pins: &Vec<String>) -> Result<()> {
+    let certificate = match stream.peer_certificate() {
+        Ok(Some(certificate)) => certificate,
+        Ok(None) => return Err(Error::BadResponse(vec![format!("The server '{}' did not send a certificate", domain)])),
+        Err(e) => return Err(Error::Tls(e)),
+    };
+    let der = certificate.to_der().map_err(Error::Tls)?;
+
+    let fingerprint: String = Sha256::digest(&der).iter().map(|b| format!("{:02x}", b)).collect();
+    let matches = pins.iter().any(|pin| pin.replace(":", "").to_lowercase() == fingerprint);
+
+    if !matches {
+        return Err(fatal(format!("The certificate of '{}' has the SHA-256 fingerprint {}, which is not in `pinned_sha256`", domain, fingerprint)));
+    }
+
+    Ok(())
+}
+
+/// Reads the greeting and upgrades the plaintext connection with STARTTLS (RFC 3501, section 6.2.1).
+/// This is done by hand, so that the upgraded stream can be handed to `Client` like any other.
+fn starttls(tcp: &mut TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(tcp.try_clone().map_err(Error::Io)?);
+
+    let mut greeting = String::new();
+    reader.read_line(&mut greeting).map_err(Error::Io)?;
+    if greeting.is_empty() {
+        return Err(Error::ConnectionLost);
+    }
+    if DEFAULT.debug_imap() {
+        print!("S: {}", greeting);
+    }
+    if !greeting.to_uppercase().starts_with("* OK") {
+        return Err(Error::BadResponse(vec![greeting.trim().to_string()]));
+    }
+
+    tcp.write_all(b"s0 STARTTLS\r\n").map_err(Error::Io)?;
+    if DEFAULT.debug_imap() {
+        println!("C: s0 STARTTLS");
+    }
+
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(Error::Io)?;
+        if line.is_empty() {
+            return Err(Error::ConnectionLost);
+        }
+        if DEFAULT.debug_imap() {
+            print!("S: {}", line);
+        }
+
+        let upper = line.to_uppercase();
+        if upper.starts_with("S0 OK") {
+            return Ok(());
+        } else if upper.starts_with("S0 NO") {
+            return Err(Error::NoResponse(vec![line.trim().to_string()]));
+        } else if upper.starts_with("S0 ") {
+            return Err(Error::BadResponse(vec![line.trim().to_string()]));
+        }
+    }
+}
+
+/// An error which retrying will not fix, e.g. a mistake in the config. It stops only the account,
+/// not the other accounts.
+pub fn fatal(message: String) -> Error {
+    Error::BadResponse(vec![message])
+}
+
+/// Errors which are worth retrying, because the next attempt might succeed.
+/// Everything else (like a NO response to LOGIN because of bad credentials) is fatal.
+pub fn is_transient(error: &Error) -> bool {
+    match error {
+        // An `io::Error` that occurred while trying to read or write to a network stream.
+        &Error::Io(_) => true,
+        // An error from the `native_tls` library during the TLS handshake.
+        &Error::TlsHandshake(_) => true,
+        // An error from the `native_tls` library while managing the socket.
+        &Error::Tls(_) => true,
+        // The connection was terminated unexpectedly.
+        &Error::ConnectionLost => true,
+        // Error parsing a server response, most likely a response cut short.
+        &Error::Parse(_) => true,
+        // A BAD or NO response from the IMAP server, e.g. to LOGIN.
+        &Error::BadResponse(_) => false,
+        &Error::NoResponse(_) => false,
+        // Error validating input data
+        &Error::Validate(_) => false,
+        // Error appending a mail
+        &Error::Append => false,
+    }
+}
+
+/// Exponential backoff with jitter between reconnect attempts, configured by `[reconnect]`.
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { attempt: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let reconnect = DEFAULT.reconnect();
+
+        let delay = reconnect.initial_delay() as f64 * reconnect.multiplier().powi(self.attempt as i32);
+        let delay = delay.min(reconnect.max_delay() as f64);
+        let jitter = reconnect.jitter() * (2.0 * rand::random::<f64>() - 1.0);
+        let delay = (delay * (1.0 + jitter)).max(0.0);
+
+        self.attempt = self.attempt.saturating_add(1);
+        Duration::from_millis((delay * 1000.0) as u64)
+    }
+
+    pub fn wait(&mut self) {
+        let delay = self.next_delay();
+        println!("Reconnecting in {:.1} seconds", delay.as_secs() as f64 + delay.subsec_nanos() as f64 / 1e9);
+        sleep(delay);
+    }
+}
+
+/// Runs `work` until it returns `Ok`. Transient errors are retried with backoff,
+/// while fatal errors give up, because retrying will not fix them.
+/// Returns `false` if it gave up.
+pub fn supervise<F>(name: &str, mut work: F) -> bool where F: FnMut(&mut Backoff) -> Result<()> {
+    let mut backoff = Backoff::new();
+
+    loop {
+        match work(&mut backoff) {
+            Ok(()) => return true,
+            Err(ref e) if is_transient(e) => {
+                println!("{}: lost the connection to the server: {}", name, e);
+                backoff.wait();
+            },
+            Err(e) => {
+                println!("{}: fatal error, giving up: {}", name, e);
+                return false;
+            },
+        }
+    }
+}
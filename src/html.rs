@@ -0,0 +1,853 @@
+//! Renders HTML mails as Slack mrkdwn (https://api.slack.com/reference/surfaces/formatting).
+//! Only what Slack can show is kept: bold, italic, strike, code, links, lists, quotes and tables as code blocks.
+
+use std::vec::Vec;
+use std::string::String;
+
+/// Elements which never have children.
+static VOID: &'static [&'static str] = &["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr"];
+
+/// Elements whose content is text until their end tag, even if it looks like tags.
+static RAW_TEXT: &'static [&'static str] = &["script", "style", "title", "textarea"];
+
+/// Elements which are not shown.
+static SKIP: &'static [&'static str] = &["head", "script", "style", "title", "template", "noscript", "meta", "link", "button", "select", "input", "textarea"];
+
+/// Elements which start on a line of their own.
+static LINE: &'static [&'static str] = &["div", "tr", "td", "th", "dt", "dd", "dl", "section", "article", "header", "footer", "main", "nav",
+                                           "aside", "address", "center", "form", "fieldset", "figure", "figcaption", "caption", "tbody", "thead", "tfoot"];
+
+/// Elements which are separated from the rest by an empty line.
+static PARAGRAPH: &'static [&'static str] = &["p", "h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// Start tags nested deeper are ignored, so a mail with thousands of nested elements does not overflow
+/// the stack while rendering. Their content is kept.
+static MAX_DEPTH: usize = 256;
+
+/// Renders the HTML of a mail as Slack mrkdwn.
+///
+/// Tables used for the layout (most newsletters) are rendered as their content, while tables with data are
+/// rendered as a code block with aligned columns. Images become links, hidden elements and tracking pixels are skipped.
+pub fn to_mrkdwn(html: &str) -> String {
+    let nodes = parse(html);
+    let mut writer = Writer::new();
+    writer.nodes(&nodes);
+    writer.out
+}
+
+#[derive(Debug)]
+enum Node {
+    /// With the entities decoded
+    Text(String),
+    Element(Element),
+}
+
+#[derive(Debug)]
+struct Element {
+    /// Lowercased
+    name: String,
+    /// Lowercased names, decoded values
+    attrs: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    fn new(name: &str, attrs: Vec<(String, String)>) -> Element {
+        Element { name: name.to_string(), attrs: attrs, children: Vec::new() }
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.as_str())
+    }
+
+    fn elements(&self) -> Vec<&Element> {
+        self.children.iter().filter_map(|n| match n {
+            &Node::Element(ref e) => Some(e),
+            _ => None,
+        }).collect()
+    }
+
+    /// `hidden`, `display: none` and `visibility: hidden`, which newsletters use for the preview text.
+    fn is_hidden(&self) -> bool {
+        let style = self.attr("style").unwrap_or("").to_lowercase().replace(" ", "");
+        self.attr("hidden").is_some() || style.contains("display:none") || style.contains("visibility:hidden")
+    }
+
+    /// Every descendant with the name, not looking into nested tables.
+    fn find<'a>(&'a self, name: &str, r: &mut Vec<&'a Element>) {
+        for child in self.elements() {
+            if child.name == name {
+                r.push(child);
+            } else if child.name != "table" {
+                child.find(name, r);
+            }
+        }
+    }
+
+    fn contains(&self, names: &[&str]) -> bool {
+        self.elements().iter().any(|e| names.contains(&e.name.as_str()) || e.contains(names))
+    }
+}
+
+/// Builds the element tree like a browser would, but simpler: unknown end tags are ignored and
+/// some elements (`p`, `li`, `td`, ...) are closed by the start of the next one.
+fn parse(html: &str) -> Vec<Node> {
+    let lower = html.to_ascii_lowercase();
+    let bytes = html.as_bytes();
+    let mut stack: Vec<Element> = vec![Element::new("#root", Vec::new())];
+    let mut text_start = 0;
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        if bytes[pos] != b'<' {
+            pos += 1;
+            continue;
+        }
+
+        let next = bytes.get(pos + 1).cloned().unwrap_or(b' ');
+        let end_tag = next == b'/' && bytes.get(pos + 2).map(|b| b.is_ascii_alphabetic()).unwrap_or(false);
+        if !(next.is_ascii_alphabetic() || next == b'!' || next == b'?' || end_tag) {
+            pos += 1;
+            continue;
+        }
+
+        add_text(&mut stack, &html[text_start..pos]);
+
+        if lower[pos..].starts_with("<!--") {
+            pos = lower[pos + 4..].find("-->").map_or(bytes.len(), |i| pos + 4 + i + 3);
+        } else if next == b'!' || next == b'?' {
+            pos = lower[pos..].find('>').map_or(bytes.len(), |i| pos + i + 1);
+        } else if end_tag {
+            let (name, _, _, end) = parse_tag(html, pos + 2);
+            close(&mut stack, &name);
+            pos = end;
+        } else {
+            let (name, attrs, self_closing, end) = parse_tag(html, pos + 1);
+            pos = end;
+
+            if RAW_TEXT.contains(&name.as_str()) && !self_closing {
+                let close_tag = format!("</{}", name);
+                let text_end = lower[pos..].find(&close_tag).map_or(bytes.len(), |i| pos + i);
+                let mut element = Element::new(&name, attrs);
+                element.children.push(Node::Text(decode_entities(&html[pos..text_end])));
+                stack.last_mut().unwrap().children.push(Node::Element(element));
+                pos = lower[text_end..].find('>').map_or(bytes.len(), |i| text_end + i + 1);
+            } else {
+                open(&mut stack, &name, attrs, self_closing);
+            }
+        }
+
+        text_start = pos;
+    }
+
+    add_text(&mut stack, &html[text_start.min(bytes.len())..]);
+
+    while stack.len() > 1 {
+        pop(&mut stack);
+    }
+    stack.pop().unwrap().children
+}
+
+/// `name attr="value" attr=value attr>`, starting after the `<` or `</`. Returns the lowercased name,
+/// the attributes, if the tag is self-closing and the position after the `>`.
+fn parse_tag(html: &str, start: usize) -> (String, Vec<(String, String)>, bool, usize) {
+    let bytes = html.as_bytes();
+    let mut pos = start;
+    let until = |pos: &mut usize, stop: &dyn Fn(u8) -> bool| {
+        let start = *pos;
+        while *pos < bytes.len() && !stop(bytes[*pos]) {
+            *pos += 1;
+        }
+        html[start..*pos].to_string()
+    };
+
+    let name = until(&mut pos, &|b| b.is_ascii_whitespace() || b == b'>' || b == b'/').to_ascii_lowercase();
+    let mut attrs: Vec<(String, String)> = Vec::new();
+    let mut self_closing = false;
+
+    loop {
+        until(&mut pos, &|b| !b.is_ascii_whitespace());
+        match bytes.get(pos) {
+            None => return (name, attrs, self_closing, pos),
+            Some(&b'>') => return (name, attrs, self_closing, pos + 1),
+            Some(&b'/') => {
+                self_closing = true;
+                pos += 1;
+                continue;
+            },
+            _ => (),
+        }
+        self_closing = false;
+
+        let attr = until(&mut pos, &|b| b.is_ascii_whitespace() || b == b'=' || b == b'>' || b == b'/').to_ascii_lowercase();
+        until(&mut pos, &|b| !b.is_ascii_whitespace());
+        let mut value = String::new();
+        if bytes.get(pos) == Some(&b'=') {
+            pos += 1;
+            until(&mut pos, &|b| !b.is_ascii_whitespace());
+            match bytes.get(pos).cloned() {
+                Some(quote) if quote == b'"' || quote == b'\'' => {
+                    pos += 1;
+                    value = until(&mut pos, &|b| b == quote);
+                    pos = (pos + 1).min(bytes.len());
+                },
+                _ => value = until(&mut pos, &|b| b.is_ascii_whitespace() || b == b'>'),
+            }
+        }
+        // A value without a name, e.g. `<a ="b">`
+        if !attr.is_empty() {
+            attrs.push((attr, decode_entities(&value)));
+        }
+    }
+}
+
+fn add_text(stack: &mut Vec<Element>, text: &str) {
+    if !text.is_empty() {
+        stack.last_mut().unwrap().children.push(Node::Text(decode_entities(text)));
+    }
+}
+
+/// The position of the open element with one of the names, if it comes before any of the `boundaries`.
+fn find_open(stack: &[Element], names: &[&str], boundaries: &[&str]) -> Option<usize> {
+    for (i, element) in stack.iter().enumerate().skip(1).rev() {
+        if names.contains(&element.name.as_str()) {
+            return Some(i);
+        }
+        if boundaries.contains(&element.name.as_str()) {
+            return None;
+        }
+    }
+    None
+}
+
+fn open(stack: &mut Vec<Element>, name: &str, attrs: Vec<(String, String)>, self_closing: bool) {
+    let implied = match name {
+        "li" => find_open(stack, &["li"], &["ul", "ol", "table"]),
+        "dt" | "dd" => find_open(stack, &["dt", "dd"], &["dl", "table"]),
+        "tr" => find_open(stack, &["tr"], &["table"]),
+        "td" | "th" => find_open(stack, &["td", "th"], &["tr", "table"]),
+        "p" | "div" | "ul" | "ol" | "table" | "blockquote" | "pre" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "hr" =>
+            find_open(stack, &["p"], &["div", "td", "th", "li", "blockquote", "table", "body"]),
+        _ => None,
+    };
+    if let Some(i) = implied {
+        while stack.len() > i {
+            pop(stack);
+        }
+    }
+
+    let element = Element::new(name, attrs);
+    if self_closing || VOID.contains(&name) {
+        stack.last_mut().unwrap().children.push(Node::Element(element));
+    } else if stack.len() <= MAX_DEPTH {
+        stack.push(element);
+    }
+}
+
+fn close(stack: &mut Vec<Element>, name: &str) {
+    // Browsers treat `</br>` as `<br>`
+    if name == "br" {
+        open(stack, name, Vec::new(), true);
+        return;
+    }
+
+    if let Some(i) = stack.iter().rposition(|e| e.name == name) {
+        if i > 0 {
+            while stack.len() > i {
+                pop(stack);
+            }
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Element>) {
+    let element = stack.pop().unwrap();
+    stack.last_mut().unwrap().children.push(Node::Element(element));
+}
+
+/// `&amp;`, `&#228;`, `&#xE4;` and the most common named entities. Anything else is left as it is.
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut r = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        r.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let end = rest.char_indices().take(12).find(|&(_, c)| c == ';').map(|(i, _)| i);
+        let decoded = end.and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                r.push(c);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                r.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if entity.starts_with("#x") || entity.starts_with("#X") {
+        return u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32);
+    }
+    if entity.starts_with('#') {
+        return entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32);
+    }
+
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "shy" => '\u{ad}',
+        "zwnj" => '\u{200c}',
+        "zwj" => '\u{200d}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "bull" => '•',
+        "middot" => '·',
+        "euro" => '€',
+        "pound" => '£',
+        "deg" => '°',
+        "times" => '×',
+        "aelig" => 'æ',
+        "AElig" => 'Æ',
+        "oslash" => 'ø',
+        "Oslash" => 'Ø',
+        "aring" => 'å',
+        "Aring" => 'Å',
+        "auml" => 'ä',
+        "Auml" => 'Ä',
+        "ouml" => 'ö',
+        "Ouml" => 'Ö',
+        "uuml" => 'ü',
+        "Uuml" => 'Ü',
+        "szlig" => 'ß',
+        "eacute" => 'é',
+        "Eacute" => 'É',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Slack needs `&`, `<` and `>` escaped, everything else is taken as it is.
+fn escape(text: &str) -> String {
+    text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
+}
+
+/// Characters which are only used as padding, e.g. after the preview text of newsletters.
+fn is_invisible(c: char) -> bool {
+    c == '\u{ad}' || c == '\u{200b}' || c == '\u{200c}' || c == '\u{200d}' || c == '\u{2060}' || c == '\u{feff}' || c == '\u{34f}'
+}
+
+/// The words of the text, separated by single spaces.
+fn collapse(text: &str) -> String {
+    let text: String = text.chars().filter(|&c| !is_invisible(c)).collect();
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// The text of an element, without formatting. Images are replaced by their alt text.
+fn plain_text(element: &Element, r: &mut String) {
+    for child in &element.children {
+        match child {
+            &Node::Text(ref text) => r.push_str(text),
+            &Node::Element(ref e) if e.is_hidden() || SKIP.contains(&e.name.as_str()) => (),
+            &Node::Element(ref e) if e.name == "br" => r.push('\n'),
+            &Node::Element(ref e) if e.name == "img" => {
+                r.push(' ');
+                r.push_str(e.attr("alt").unwrap_or(""));
+                r.push(' ');
+            },
+            &Node::Element(ref e) => {
+                if LINE.contains(&e.name.as_str()) || PARAGRAPH.contains(&e.name.as_str()) {
+                    r.push(' ');
+                }
+                plain_text(e, r);
+                if LINE.contains(&e.name.as_str()) || PARAGRAPH.contains(&e.name.as_str()) {
+                    r.push(' ');
+                }
+            },
+        }
+    }
+}
+
+fn collapsed_text(element: &Element) -> String {
+    let mut text = String::new();
+    plain_text(element, &mut text);
+    collapse(&text)
+}
+
+/// A table with data, as opposed to a table used for the layout of the mail. Layout tables have nested
+/// tables, a single column, or images and paragraphs in their cells.
+fn is_data_table(table: &Element) -> bool {
+    if table.attr("role") == Some("presentation") || table.contains(&["table", "img", "p", "div", "ul", "ol", "h1", "h2", "h3", "h4", "h5", "h6"]) {
+        return false;
+    }
+
+    let mut rows: Vec<&Element> = Vec::new();
+    table.find("tr", &mut rows);
+    rows.iter().any(|row| row.elements().iter().filter(|c| c.name == "td" || c.name == "th").count() >= 2)
+}
+
+/// Writes mrkdwn, taking care of the whitespace between words and blocks, the prefixes of quotes and
+/// list items, and the formatting markers, which only work within a line in Slack.
+struct Writer {
+    out: String,
+    /// Written at the start of every line, e.g. `> ` in a quote or the indentation of a list item
+    prefix: Vec<String>,
+    /// Line breaks to write before the next word
+    newlines: usize,
+    /// A space to write before the next word
+    space: bool,
+    /// Nothing has been written on the current line, not even the prefix
+    line_start: bool,
+    /// The prefix written at the start of the current line
+    line_prefix: String,
+    /// Only the marker of a list item has been written on the current line
+    marker: bool,
+    /// The active formatting (`*`, `_`, `~`), may contain the same more than once when nested
+    formats: Vec<char>,
+    /// Opening markers to write before the next word, so `<b> bold </b>` becomes `*bold*` and not `* bold *`
+    open: String,
+    /// The markers which have been written and not closed yet
+    written: Vec<char>,
+    /// The open lists, with the next number of ordered lists
+    lists: Vec<Option<u64>>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            out: String::new(),
+            prefix: Vec::new(),
+            newlines: 0,
+            space: false,
+            line_start: true,
+            line_prefix: String::new(),
+            marker: false,
+            formats: Vec::new(),
+            open: String::new(),
+            written: Vec::new(),
+            lists: Vec::new(),
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                &Node::Text(ref text) => self.text(text),
+                &Node::Element(ref element) => self.element(element),
+            }
+        }
+    }
+
+    fn element(&mut self, e: &Element) {
+        if e.is_hidden() || SKIP.contains(&e.name.as_str()) {
+            return;
+        }
+
+        match e.name.as_str() {
+            "br" => self.line_break(),
+            "b" | "strong" => self.format('*', e),
+            "i" | "em" => self.format('_', e),
+            "s" | "strike" | "del" => self.format('~', e),
+            "code" | "kbd" | "samp" | "tt" => {
+                let text = collapsed_text(e);
+                if !text.is_empty() {
+                    self.word(&format!("`{}`", escape(&text.replace("`", "'"))));
+                }
+            },
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.block(2);
+                self.format('*', e);
+                self.block(2);
+            },
+            "pre" => {
+                let mut text = String::new();
+                pre_text(e, &mut text);
+                self.block(2);
+                self.code_block(&text);
+                self.block(2);
+            },
+            "hr" => {
+                self.block(2);
+                self.word("──────────");
+                self.block(2);
+            },
+            "a" => self.link(e),
+            "img" => self.image(e),
+            "ul" | "ol" => {
+                let number = if e.name == "ol" { Some(e.attr("start").and_then(|s| s.trim().parse().ok()).unwrap_or(1)) } else { None };
+                let separation = if self.lists.is_empty() { 2 } else { 1 };
+                self.block(separation);
+                self.lists.push(number);
+                self.nodes(&e.children);
+                self.lists.pop();
+                self.block(separation);
+            },
+            "li" => self.list_item(e),
+            "blockquote" => {
+                self.block(2);
+                // Slack has no nested quotes
+                let quote = !self.prefix.iter().any(|p| p == "> ");
+                if quote {
+                    self.prefix.push("> ".to_string());
+                }
+                self.nodes(&e.children);
+                self.block(2);
+                if quote {
+                    self.prefix.pop();
+                }
+            },
+            "table" if is_data_table(e) => {
+                self.block(2);
+                self.table(e);
+                self.block(2);
+            },
+            "table" => {
+                self.block(1);
+                self.nodes(&e.children);
+                self.block(1);
+            },
+            name if PARAGRAPH.contains(&name) => {
+                self.block(2);
+                self.nodes(&e.children);
+                self.block(2);
+            },
+            name if LINE.contains(&name) => {
+                self.block(1);
+                self.nodes(&e.children);
+                self.block(1);
+            },
+            _ => self.nodes(&e.children),
+        }
+    }
+
+    fn format(&mut self, marker: char, e: &Element) {
+        if !self.formats.contains(&marker) {
+            self.open.push(marker);
+        }
+        self.formats.push(marker);
+
+        self.nodes(&e.children);
+
+        if let Some(i) = self.formats.iter().rposition(|&m| m == marker) {
+            self.formats.remove(i);
+        }
+        if self.formats.contains(&marker) {
+            return;
+        }
+        if let Some(i) = self.open.find(marker) {
+            // Nothing was written, so no markers are needed
+            self.open.remove(i);
+        } else if let Some(i) = self.written.iter().rposition(|&m| m == marker) {
+            self.written.remove(i);
+            self.out.push(marker);
+        }
+    }
+
+    fn link(&mut self, e: &Element) {
+        let href: String = e.attr("href").unwrap_or("").chars().filter(|c| !c.is_whitespace()).collect();
+        let text = collapsed_text(e);
+
+        // Relative links lead nowhere outside of the mail
+        let lower = href.to_lowercase();
+        if !href.contains(':') || lower.starts_with("javascript:") {
+            self.text(&text);
+            return;
+        }
+
+        let url = href.replace("|", "%7C").replace("<", "%3C").replace(">", "%3E");
+        if text.is_empty() || text == href || lower == format!("mailto:{}", text.to_lowercase()) {
+            self.word(&format!("<{}>", url));
+        } else {
+            self.word(&format!("<{}|{}>", url, escape(&text)));
+        }
+    }
+
+    fn image(&mut self, e: &Element) {
+        // Tracking pixels
+        let tiny = |size: Option<&str>| size.map(|s| s.trim() == "0" || s.trim() == "1").unwrap_or(false);
+        if tiny(e.attr("width")) || tiny(e.attr("height")) {
+            return;
+        }
+
+        let alt = collapse(e.attr("alt").unwrap_or(""));
+        let src = e.attr("src").unwrap_or("").trim();
+        let lower = src.to_lowercase();
+        // Images are often put right next to each other
+        self.space = true;
+        if lower.starts_with("http://") || lower.starts_with("https://") {
+            let url = src.replace("|", "%7C").replace("<", "%3C").replace(">", "%3E");
+            if alt.is_empty() {
+                self.word(&format!("<{}>", url));
+            } else {
+                self.word(&format!("<{}|{}>", url, escape(&alt)));
+            }
+        } else {
+            // Embedded images (`cid:` and `data:`) can not be linked to
+            self.text(&alt);
+        }
+        self.space = true;
+    }
+
+    fn list_item(&mut self, e: &Element) {
+        let marker = match self.lists.last_mut() {
+            Some(&mut Some(ref mut number)) => {
+                *number += 1;
+                format!("{}. ", *number - 1)
+            },
+            _ => "• ".to_string(),
+        };
+
+        self.block(1);
+        self.flush();
+        self.out.push_str(&marker);
+        self.marker = true;
+        self.space = false;
+
+        self.prefix.push(" ".repeat(marker.chars().count()));
+        self.nodes(&e.children);
+        self.prefix.pop();
+        self.marker = false;
+        self.block(1);
+    }
+
+    fn table(&mut self, table: &Element) {
+        let mut rows: Vec<&Element> = Vec::new();
+        table.find("tr", &mut rows);
+
+        let rows: Vec<Vec<String>> = rows.iter()
+            .map(|row| row.elements().iter().filter(|c| c.name == "td" || c.name == "th").map(|c| collapsed_text(c)).collect())
+            .filter(|row: &Vec<String>| row.iter().any(|c| !c.is_empty()))
+            .collect();
+
+        let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| rows.iter().filter_map(|r| r.get(i)).map(|c| c.chars().count()).max().unwrap_or(0))
+            .collect();
+
+        let lines: Vec<String> = rows.iter().map(|row| {
+            let cells: Vec<String> = row.iter().enumerate()
+                .map(|(i, cell)| format!("{}{}", cell, " ".repeat(widths[i] - cell.chars().count())))
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        }).collect();
+
+        self.code_block(&lines.join("\n"));
+    }
+
+    fn code_block(&mut self, text: &str) {
+        let text = text.trim_matches('\n');
+        if text.trim().is_empty() {
+            return;
+        }
+
+        self.raw_line("```");
+        for line in text.lines() {
+            self.raw_line(&escape(line.trim_end()));
+        }
+        self.raw_line("```");
+    }
+
+    /// A line as it is, without formatting.
+    fn raw_line(&mut self, line: &str) {
+        self.flush();
+        self.out.push_str(line);
+        self.newlines = 1;
+        self.space = false;
+        self.marker = false;
+    }
+
+    /// Starts a new line (`lines` = 1) or a new paragraph (`lines` = 2) before the next word.
+    /// Within lists there are no empty lines.
+    fn block(&mut self, lines: usize) {
+        if self.out.is_empty() || self.marker {
+            return;
+        }
+        let lines = if self.lists.is_empty() { lines } else { 1 };
+        self.newlines = self.newlines.max(lines);
+        self.space = false;
+    }
+
+    fn line_break(&mut self) {
+        if self.out.is_empty() {
+            return;
+        }
+        self.newlines += 1;
+        self.space = false;
+    }
+
+    /// Writes the pending line breaks and the prefix of a new line. Returns true at the start of a line.
+    fn flush(&mut self) -> bool {
+        if self.newlines > 0 && !self.out.is_empty() {
+            // Formatting does not work across lines in Slack, so it is closed and opened again on the next line
+            for &marker in self.written.iter().rev() {
+                self.out.push(marker);
+            }
+            let reopen: String = self.written.drain(..).collect();
+            self.open = reopen + &self.open;
+
+            // An empty line only keeps the part of the prefix, which both lines around it have, e.g. within a quote
+            let prefix = self.prefix.concat();
+            let common: String = prefix.chars().zip(self.line_prefix.chars()).take_while(|&(a, b)| a == b).map(|(a, _)| a).collect();
+            for i in 0..self.newlines.min(2) {
+                if i > 0 {
+                    self.out.push_str(common.trim_end());
+                }
+                self.out.push('\n');
+            }
+            self.line_start = true;
+        }
+        self.newlines = 0;
+
+        if self.line_start {
+            self.line_prefix = self.prefix.concat();
+            self.out.push_str(&self.line_prefix);
+            self.line_start = false;
+            return true;
+        }
+        false
+    }
+
+    /// Writes text which has already been escaped, as a single word.
+    fn word(&mut self, word: &str) {
+        let line_start = self.flush();
+        if self.space && !line_start && !self.marker && !self.out.ends_with(' ') {
+            self.out.push(' ');
+        }
+        self.space = false;
+        self.marker = false;
+
+        self.written.extend(self.open.chars());
+        let open = ::std::mem::replace(&mut self.open, String::new());
+        self.out.push_str(&open);
+        self.out.push_str(word);
+    }
+
+    /// Writes text, with the whitespace collapsed like a browser does.
+    fn text(&mut self, text: &str) {
+        let text: String = text.chars().filter(|&c| !is_invisible(c)).collect();
+        if text.starts_with(char::is_whitespace) {
+            self.space = true;
+        }
+        for (i, word) in text.split_whitespace().enumerate() {
+            if i > 0 {
+                self.space = true;
+            }
+            self.word(&escape(word));
+        }
+        if text.ends_with(char::is_whitespace) {
+            self.space = true;
+        }
+    }
+}
+
+/// The text of a `pre` element, with the whitespace kept.
+fn pre_text(element: &Element, r: &mut String) {
+    for child in &element.children {
+        match child {
+            &Node::Text(ref text) => r.push_str(text),
+            &Node::Element(ref e) if e.name == "br" => r.push('\n'),
+            &Node::Element(ref e) if !SKIP.contains(&e.name.as_str()) => pre_text(e, r),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_formatting() {
+        assert_eq!(to_mrkdwn("<p>Some <b>bold</b>, <i> italic </i> and <s>old</s> <code>code</code></p>"), "Some *bold*, _italic_ and ~old~ `code`");
+        assert_eq!(to_mrkdwn("<b>Across<br>lines</b>"), "*Across*\n*lines*");
+        assert_eq!(to_mrkdwn("<h1>Title</h1><p>First</p><p>Second</p>"), "*Title*\n\nFirst\n\nSecond");
+    }
+
+    #[test]
+    fn renders_lists() {
+        assert_eq!(to_mrkdwn("<ul><li>One<li>Two</ul>"), "• One\n• Two");
+        assert_eq!(to_mrkdwn("<ol start=\"3\"><li>Three</li><li>Four</li></ol>"), "3. Three\n4. Four");
+        assert_eq!(to_mrkdwn("<ul><li>One<ol><li>Nested</li></ol></li><li>Two</li></ul>"), "• One\n  1. Nested\n• Two");
+    }
+
+    #[test]
+    fn renders_blockquotes() {
+        assert_eq!(to_mrkdwn("<p>Wrote:</p><blockquote><p>First</p><p>Second</p></blockquote>"), "Wrote:\n\n> First\n>\n> Second");
+        // Slack has no nested quotes
+        assert_eq!(to_mrkdwn("<blockquote>Outer<blockquote>Inner</blockquote></blockquote>"), "> Outer\n>\n> Inner");
+    }
+
+    #[test]
+    fn renders_data_tables_as_code_blocks() {
+        let html = "<table><tr><th>Name</th><th>Amount</th></tr><tr><td>Coffee</td><td>3</td></tr><tr><td>Tea</td><td>12</td></tr></table>";
+        assert_eq!(to_mrkdwn(html), "```\nName    Amount\nCoffee  3\nTea     12\n```");
+    }
+
+    #[test]
+    fn renders_the_content_of_layout_tables() {
+        let html = "<table role=\"presentation\"><tr><td>Left</td><td>Right</td></tr></table>";
+        assert_eq!(to_mrkdwn(html), "Left\nRight");
+        let html = "<table><tr><td><table><tr><td><p>Newsletter</p></td></tr></table></td></tr></table>";
+        assert_eq!(to_mrkdwn(html), "Newsletter");
+    }
+
+    #[test]
+    fn renders_links() {
+        assert_eq!(to_mrkdwn("<a href=\"https://example.com/a|b\">Example &amp; co</a>"), "<https://example.com/a%7Cb|Example &amp; co>");
+        assert_eq!(to_mrkdwn("<a href=\"https://example.com\">https://example.com</a>"), "<https://example.com>");
+        assert_eq!(to_mrkdwn("<a href=\"mailto:bob@example.com\">bob@example.com</a>"), "<mailto:bob@example.com>");
+        // Relative and javascript links lead nowhere
+        assert_eq!(to_mrkdwn("<a href=\"/unsubscribe\">Unsubscribe</a> <a href=\"javascript:void(0)\">here</a>"), "Unsubscribe here");
+    }
+
+    #[test]
+    fn renders_images() {
+        assert_eq!(to_mrkdwn("<img src=\"https://example.com/logo.png\" alt=\"Logo\">"), "<https://example.com/logo.png|Logo>");
+        assert_eq!(to_mrkdwn("<img src=\"cid:logo@example.com\" alt=\"Logo\">"), "Logo");
+        assert_eq!(to_mrkdwn("Text<img src=\"https://example.com/open.gif\" width=\"1\" height=\"1\">"), "Text");
+    }
+
+    #[test]
+    fn skips_hidden_text() {
+        let html = "<head><title>Newsletter</title><style>p { color: red }</style></head>\
+                    <div style=\"display: none\">Preview text&zwnj;&nbsp;&zwnj;</div>\
+                    <span hidden>Hidden</span><p>Visible</p><script>alert(\"<p>\")</script>";
+        assert_eq!(to_mrkdwn(html), "Visible");
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(to_mrkdwn("S&oslash;ren &lt;3 caf&#233; &#x2713; &unknown; AT&T"), "Søren &lt;3 café ✓ &amp;unknown; AT&amp;T");
+    }
+
+    #[test]
+    fn ignores_elements_nested_too_deep() {
+        let html = format!("{}Deep{}", "<div>".repeat(100_000), "</div>".repeat(100_000));
+        assert_eq!(to_mrkdwn(&html), "Deep");
+        let html = format!("{}Deep", "<b>".repeat(100_000));
+        assert_eq!(to_mrkdwn(&html), "*Deep*");
+    }
+}
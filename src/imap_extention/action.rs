@@ -0,0 +1,55 @@
+use imap::error::{Error, Result};
+use std::io::{Read,Write};
+use imap::client::Session;
+use super::path::Path;
+
+/// What can be done with a mail after it has been posted. All of them work on UIDs
+/// in the selected mailbox.
+pub trait MailAction {
+    /// Moves the mail with MOVE (RFC 6851) if `has_move`, otherwise with COPY and UID EXPUNGE,
+    /// which needs UIDPLUS (see `delete_mail`).
+    fn move_mail(&mut self, uid: u32, to: &Path, has_move: bool, has_uidplus: bool) -> Result<()>;
+    fn copy_mail(&mut self, uid: u32, to: &Path) -> Result<()>;
+    fn add_keyword(&mut self, uid: u32, keyword: &str) -> Result<()>;
+    /// Marks the mail as `\Deleted` and expunges it with UID EXPUNGE. Without UIDPLUS (RFC 4315) only a plain
+    /// EXPUNGE is possible, which would also expunge every other mail in the mailbox marked as `\Deleted`,
+    /// so the mail is left alone and an error is returned.
+    fn delete_mail(&mut self, uid: u32, has_uidplus: bool) -> Result<()>;
+}
+
+impl<T: Read + Write> MailAction for Session<T> {
+    fn move_mail(&mut self, uid: u32, to: &Path, has_move: bool, has_uidplus: bool) -> Result<()> {
+        if has_move {
+            return self.run_command_and_read_response(&format!("UID MOVE {} {}", uid, to.quoted())).map(|_| ());
+        }
+
+        // Checked before the copy, so the mail is not left in both mailboxes
+        if !has_uidplus {
+            return Err(no_uidplus());
+        }
+
+        self.copy_mail(uid, to)?;
+        self.delete_mail(uid, has_uidplus)
+    }
+
+    fn copy_mail(&mut self, uid: u32, to: &Path) -> Result<()> {
+        self.run_command_and_read_response(&format!("UID COPY {} {}", uid, to.quoted())).map(|_| ())
+    }
+
+    fn add_keyword(&mut self, uid: u32, keyword: &str) -> Result<()> {
+        self.run_command_and_read_response(&format!("UID STORE {} +FLAGS.SILENT ({})", uid, keyword)).map(|_| ())
+    }
+
+    fn delete_mail(&mut self, uid: u32, has_uidplus: bool) -> Result<()> {
+        if !has_uidplus {
+            return Err(no_uidplus());
+        }
+
+        self.run_command_and_read_response(&format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", uid))?;
+        self.run_command_and_read_response(&format!("UID EXPUNGE {}", uid)).map(|_| ())
+    }
+}
+
+fn no_uidplus() -> Error {
+    Error::NoResponse(vec!["The server does not support UIDPLUS, and EXPUNGE would also expunge every other mail marked as \\Deleted".to_string()])
+}
@@ -0,0 +1,201 @@
+use std::vec::Vec;
+use std::string::String;
+use super::encoding::decode_text;
+use super::header::decode_words;
+use super::response::Value;
+
+/// A single (non multipart) part of a mail from a BODYSTRUCTURE response (RFC 3501, section 7.4.2).
+#[derive(Debug,Clone)]
+pub struct BodyPart {
+    /// The part specifier to fetch the part with, e.g. `1.2` for `BODY[1.2]`
+    pub section: String,
+    /// Lowercased, e.g. `text/plain`
+    pub mimetype: String,
+    /// The body parameters with lowercased names, e.g. `charset`
+    pub params: Vec<(String, String)>,
+    /// The Content-ID, which HTML parts use to show embedded images
+    pub id: Option<String>,
+    /// Lowercased Content-Transfer-Encoding, e.g. `base64`
+    pub encoding: String,
+    /// The size in bytes, still transfer encoded
+    pub size: u64,
+    /// Lowercased Content-Disposition, e.g. `attachment`
+    pub disposition: Option<String>,
+    pub disposition_params: Vec<(String, String)>,
+}
+
+impl BodyPart {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.as_str())
+    }
+
+    pub fn is_attachment(&self) -> bool {
+        self.disposition.as_ref().map(|d| d == "attachment").unwrap_or(false)
+    }
+
+    /// The file name from the Content-Disposition, or the `name` of the Content-Type.
+    pub fn filename(&self) -> Option<String> {
+        param_value(&self.disposition_params, "filename").or_else(|| param_value(&self.params, "name"))
+    }
+
+    /// The file name, or a made up name if the part has none.
+    pub fn name(&self) -> String {
+        self.filename().unwrap_or(format!("attachment-{}", self.section))
+    }
+
+    /// About the size after the Content-Transfer-Encoding has been decoded.
+    /// BASE64 is usually written in lines of 76 characters, which are 57 bytes decoded.
+    pub fn decoded_size(&self) -> u64 {
+        if self.encoding == "base64" {
+            self.size * 57 / 78
+        } else {
+            self.size
+        }
+    }
+}
+
+/// A parameter, which may be encoded and split as in RFC 2231, e.g. `filename*0*=utf-8''%C3%A6; filename*1=.pdf`,
+/// or contain encoded words (RFC 2047), which is not allowed but common.
+fn param_value(params: &[(String, String)], name: &str) -> Option<String> {
+    if let Some(&(_, ref value)) = params.iter().find(|&&(ref n, _)| n == name) {
+        return Some(decode_words(value));
+    }
+
+    // (index, encoded, value)
+    let mut segments: Vec<(u32, bool, &str)> = Vec::new();
+    for &(ref n, ref value) in params {
+        if !n.starts_with(name) || !n[name.len()..].starts_with('*') {
+            continue;
+        }
+        let rest = &n[name.len() + 1..];
+        let encoded = rest.is_empty() || rest.ends_with('*');
+        let index = match rest.trim_end_matches('*') {
+            "" => 0,
+            index => match index.parse() {
+                Ok(index) => index,
+                Err(_) => continue,
+            },
+        };
+        segments.push((index, encoded, value));
+    }
+    if segments.is_empty() {
+        return None;
+    }
+    segments.sort_by_key(|&(index, _, _)| index);
+
+    let mut charset: Option<&str> = None;
+    let mut bytes: Vec<u8> = Vec::new();
+    for (i, &(_, encoded, value)) in segments.iter().enumerate() {
+        if !encoded {
+            bytes.extend_from_slice(value.as_bytes());
+            continue;
+        }
+        // The first segment starts with `charset'language'`
+        let value = if i == 0 && value.matches('\'').count() >= 2 {
+            let mut split = value.splitn(3, '\'');
+            charset = split.next().filter(|c| !c.is_empty());
+            split.nth(1).unwrap_or("")
+        } else {
+            value
+        };
+        let value = value.as_bytes();
+        let mut j = 0;
+        while j < value.len() {
+            let hex = if value[j] == b'%' && j + 2 < value.len() {
+                ::std::str::from_utf8(&value[j + 1..j + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+            } else {
+                None
+            };
+            match hex {
+                Some(byte) => {
+                    bytes.push(byte);
+                    j += 3;
+                },
+                None => {
+                    bytes.push(value[j]);
+                    j += 1;
+                },
+            }
+        }
+    }
+    Some(decode_text(&bytes, charset).0)
+}
+
+/// Flattens a BODYSTRUCTURE into its parts. Attached mails (`message/rfc822`) are a single part,
+/// their own parts are not included.
+pub fn parse_bodystructure(value: &Value) -> Vec<BodyPart> {
+    let mut parts: Vec<BodyPart> = Vec::new();
+    match value.as_list() {
+        // The body of a mail which is not multipart is section 1
+        Some(list) if !is_multipart(list) => {
+            if let Some(part) = parse_part(list, "1".to_string()) {
+                parts.push(part);
+            }
+        },
+        Some(list) => parse_multipart(list, "", &mut parts),
+        None => (),
+    }
+    parts
+}
+
+fn is_multipart(list: &Vec<Value>) -> bool {
+    list.first().map(|v| v.as_list().is_some()).unwrap_or(false)
+}
+
+/// `(part)(part)... "subtype" extension...`
+fn parse_multipart(list: &Vec<Value>, prefix: &str, parts: &mut Vec<BodyPart>) {
+    let children = list.iter().take_while(|v| v.as_list().is_some());
+    for (i, child) in children.enumerate() {
+        let section = format!("{}{}", prefix, i + 1);
+        let child = child.as_list().unwrap();
+
+        if is_multipart(child) {
+            parse_multipart(child, &format!("{}.", section), parts);
+        } else if let Some(part) = parse_part(child, section) {
+            parts.push(part);
+        }
+    }
+}
+
+/// `"type" "subtype" (params) id description "encoding" size ...`, followed by the lines for `text/*`
+/// and by the envelope, body and lines for `message/rfc822`, before the extension data: md5 and disposition.
+fn parse_part(list: &Vec<Value>, section: String) -> Option<BodyPart> {
+    let mimetype = format!("{}/{}", list.get(0)?.as_string()?, list.get(1)?.as_string()?).to_lowercase();
+
+    let extension = if mimetype.starts_with("text/") {
+        8
+    } else if mimetype == "message/rfc822" {
+        10
+    } else {
+        7
+    };
+    // The first extension field is the md5
+    let (disposition, disposition_params) = match list.get(extension + 1).and_then(|v| v.as_list()) {
+        Some(d) => (d.get(0).and_then(|v| v.as_string()).map(|s| s.to_lowercase()), d.get(1).map(parse_params).unwrap_or(Vec::new())),
+        None => (None, Vec::new()),
+    };
+
+    Some(BodyPart {
+        section: section,
+        mimetype: mimetype,
+        params: list.get(2).map(parse_params).unwrap_or(Vec::new()),
+        id: list.get(3).and_then(|v| v.as_string()),
+        encoding: list.get(5).and_then(|v| v.as_string()).unwrap_or("7bit".to_string()).to_lowercase(),
+        size: list.get(6).and_then(|v| v.as_number()).unwrap_or(0),
+        disposition: disposition,
+        disposition_params: disposition_params,
+    })
+}
+
+/// `("name" "value" "name" "value")` or NIL
+fn parse_params(value: &Value) -> Vec<(String, String)> {
+    let mut params: Vec<(String, String)> = Vec::new();
+    if let Some(list) = value.as_list() {
+        for pair in list.chunks(2) {
+            if let (Some(name), Some(value)) = (pair.get(0).and_then(|v| v.as_string()), pair.get(1).and_then(|v| v.as_string())) {
+                params.push((name.to_lowercase(), value));
+            }
+        }
+    }
+    params
+}
@@ -0,0 +1,55 @@
+use imap::error::Result;
+use std::vec::Vec;
+use std::string::String;
+use std::io::{Read,Write};
+use imap::client::Session;
+
+pub trait Capability {
+    fn capability_list(&mut self) -> Result<Vec<String>>;
+    fn has_capability(&mut self, capability: &str) -> Result<bool>;
+    fn enable(&mut self, capability: &str) -> Result<bool>;
+}
+
+impl<T: Read + Write> Capability for Session<T> {
+    fn capability_list(&mut self) -> Result<Vec<String>> {
+        match self.run_command_and_read_response("CAPABILITY") {
+            Ok(response) => {
+                let response = String::from_utf8_lossy(&response);
+                let mut capabilities: Vec<String> = Vec::new();
+
+                for line in response.lines() {
+                    let line = line.trim();
+                    if line.to_uppercase().starts_with("* CAPABILITY ") {
+                        for capability in line["* CAPABILITY ".len()..].split_whitespace() {
+                            capabilities.push(capability.to_uppercase());
+                        }
+                    }
+                }
+
+                Ok(capabilities)
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    fn has_capability(&mut self, capability: &str) -> Result<bool> {
+        let capability = capability.to_uppercase();
+        self.capability_list().map(|list| list.iter().any(|c| c == &capability))
+    }
+
+    /// Runs ENABLE (RFC 5161) and returns `true` if the server confirms it with `* ENABLED`.
+    fn enable(&mut self, capability: &str) -> Result<bool> {
+        match self.run_command_and_read_response(&format!("ENABLE {}", capability)) {
+            Ok(response) => {
+                let response = String::from_utf8_lossy(&response);
+                let capability = capability.to_uppercase();
+
+                Ok(response.lines().any(|line| {
+                    let line = line.trim().to_uppercase();
+                    line.starts_with("* ENABLED ") && line["* ENABLED ".len()..].split_whitespace().any(|c| c == capability)
+                }))
+            },
+            Err(e) => Err(e)
+        }
+    }
+}
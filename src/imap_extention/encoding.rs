@@ -0,0 +1,263 @@
+extern crate encoding_rs;
+
+use std::str;
+use std::vec::Vec;
+use std::string::String;
+use quoted_printable::{self, ParseMode};
+
+use self::encoding_rs::{Encoding, ISO_2022_JP, REPLACEMENT, UTF_8, WINDOWS_1252};
+
+/// Decodes text in `charset`, the label of a Content-Type or an encoded word. It never fails, but
+/// returns a warning when the text could not be decoded as it claims to be encoded, e.g. an unknown
+/// charset or invalid bytes, which are replaced with U+FFFD.
+///
+/// ISO-8859-1 is decoded as Windows-1252, because that is what most mailers really mean by it.
+/// Without a charset (or with US-ASCII, which is often not) the charset is guessed, see `guess`.
+pub fn decode_text(bytes: &[u8], charset: Option<&str>) -> (String, Option<String>) {
+    let label = charset.map(|c| c.trim().trim_matches('"').to_lowercase()).unwrap_or_default();
+    let encoding = match label.as_ref() {
+        "" | "us-ascii" | "ascii" | "unknown-8bit" | "x-unknown" => return guess(bytes),
+        // UTF-7 is not supported by encoding_rs, and is hardly used any more
+        label => match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) if encoding != REPLACEMENT => encoding,
+            _ => {
+                let (text, _) = guess(bytes);
+                return (text, Some(format!("unknown charset '{}'", label)));
+            },
+        },
+    };
+
+    // A single byte charset accepts anything, but UTF-8 is rarely anything else
+    if encoding.is_single_byte() && !bytes.is_ascii() {
+        if let Ok(text) = str::from_utf8(bytes) {
+            return (text.to_string(), Some(format!("claims to be {}, but is UTF-8", label)));
+        }
+    }
+
+    if let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+        return (text.into_owned(), None);
+    }
+
+    // Invalid UTF-8 is either broken UTF-8 or another charset
+    if encoding == UTF_8 {
+        return guess(bytes);
+    }
+
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    (text.into_owned(), Some(format!("invalid {}", label)))
+}
+
+/// Guesses the charset of text without one: ISO-2022-JP if it has its escape sequences, UTF-8 if
+/// it is (mostly) valid UTF-8, and Windows-1252 otherwise, which is a superset of ISO-8859-1.
+fn guess(bytes: &[u8]) -> (String, Option<String>) {
+    if bytes.is_ascii() && bytes.contains(&0x1b) {
+        if let Some(text) = ISO_2022_JP.decode_without_bom_handling_and_without_replacement(bytes) {
+            return (text.into_owned(), None);
+        }
+    }
+
+    if let Ok(text) = str::from_utf8(bytes) {
+        return (text.to_string(), None);
+    }
+
+    // Broken UTF-8 still has more valid multi byte characters than invalid bytes
+    let text = String::from_utf8_lossy(bytes).into_owned();
+    let valid = text.chars().filter(|&c| !c.is_ascii() && c != '\u{FFFD}').count();
+    let invalid = text.chars().filter(|&c| c == '\u{FFFD}').count();
+    if valid >= invalid {
+        return (text, Some("invalid UTF-8".to_string()));
+    }
+
+    let (text, _) = WINDOWS_1252.decode_without_bom_handling(bytes);
+    (text.into_owned(), Some("not UTF-8, decoded as windows-1252".to_string()))
+}
+
+/// The charset of a `<meta charset="...">` or `<meta http-equiv="Content-Type" content="...; charset=...">`
+/// near the start of a HTML part, for HTML parts without a charset in their Content-Type.
+pub fn html_charset(html: &[u8]) -> Option<String> {
+    let start = String::from_utf8_lossy(&html[..html.len().min(2048)]).to_lowercase();
+    let i = start.find("charset=")? + "charset=".len();
+    let charset: String = start[i..].trim_start_matches(['"', '\''])
+        .chars()
+        .take_while(|&c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':' || c == '.')
+        .collect();
+    if charset.is_empty() {
+        None
+    } else {
+        Some(charset)
+    }
+}
+
+/// Decodes a Content-Transfer-Encoding. Broken BASE64 and quoted-printable are decoded as far as possible,
+/// with a warning, and an unknown encoding is left as it is.
+pub fn decode_transfer(body: &[u8], encoding: &str) -> (Vec<u8>, Option<String>) {
+    match encoding.trim().to_lowercase().as_ref() {
+        "base64" => decode_base64(body),
+        "quoted-printable" => match quoted_printable::decode(body, ParseMode::Robust) {
+            Ok(decoded) => (decoded, None),
+            Err(e) => (body.to_vec(), Some(format!("invalid quoted-printable: {}", e))),
+        },
+        "" | "7bit" | "8bit" | "binary" => (body.to_vec(), None),
+        encoding => (body.to_vec(), Some(format!("unknown Content-Transfer-Encoding '{}'", encoding))),
+    }
+}
+
+static BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// BASE64 (RFC 4648, section 4) with padding and without line breaks.
+pub fn encode_base64(data: &[u8]) -> String {
+    let mut r = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                r.push(BASE64[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                r.push('=');
+            }
+        }
+    }
+    r
+}
+
+/// BASE64 (RFC 2045, section 6.8), which skips line breaks and anything else which is not BASE64,
+/// does not need the padding and continues after it, in case encoded chunks were put together.
+pub fn decode_base64(data: &[u8]) -> (Vec<u8>, Option<String>) {
+    let mut r: Vec<u8> = Vec::with_capacity(data.len() / 4 * 3);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    let mut invalid = false;
+
+    for &b in data {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                invalid = !end_base64(bits, count, &mut r) || invalid;
+                bits = 0;
+                count = 0;
+                continue;
+            },
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => {
+                invalid = true;
+                continue;
+            },
+        };
+
+        bits = (bits << 6) | value as u32;
+        count += 1;
+        if count == 4 {
+            r.push((bits >> 16) as u8);
+            r.push((bits >> 8) as u8);
+            r.push(bits as u8);
+            bits = 0;
+            count = 0;
+        }
+    }
+    invalid = !end_base64(bits, count, &mut r) || invalid;
+
+    if invalid {
+        (r, Some("invalid BASE64".to_string()))
+    } else {
+        (r, None)
+    }
+}
+
+/// The last 2 characters are a byte and 3 characters two bytes. A single character is broken.
+fn end_base64(bits: u32, count: usize, r: &mut Vec<u8>) -> bool {
+    match count {
+        1 => return false,
+        2 => r.push((bits >> 4) as u8),
+        3 => {
+            r.push((bits >> 10) as u8);
+            r.push((bits >> 2) as u8);
+        },
+        _ => (),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_text_in_its_charset() {
+        assert_eq!(decode_text(b"Caf\xe9", Some("ISO-8859-1")), ("Café".to_string(), None));
+        assert_eq!(decode_text(b"\x93Quoted\x94", Some("iso-8859-1")), ("“Quoted”".to_string(), None));
+        assert_eq!(decode_text("Café".as_bytes(), Some("\"utf-8\"")), ("Café".to_string(), None));
+    }
+
+    #[test]
+    fn decodes_iso_2022_jp() {
+        let text = b"\x1b$B$3$s$K$A$O\x1b(B";
+        assert_eq!(decode_text(text, Some("iso-2022-jp")), ("こんにちは".to_string(), None));
+        // Recognised by its escape sequences without a charset
+        assert_eq!(decode_text(text, None), ("こんにちは".to_string(), None));
+    }
+
+    #[test]
+    fn decodes_mislabelled_utf8() {
+        assert_eq!(decode_text("Café".as_bytes(), Some("iso-8859-1")), ("Café".to_string(), Some("claims to be iso-8859-1, but is UTF-8".to_string())));
+    }
+
+    #[test]
+    fn guesses_the_charset_without_a_label() {
+        assert_eq!(decode_text("Café".as_bytes(), None), ("Café".to_string(), None));
+        assert_eq!(decode_text(b"Caf\xe9", Some("us-ascii")), ("Café".to_string(), Some("not UTF-8, decoded as windows-1252".to_string())));
+        assert_eq!(decode_text(b"Caf\xc3\xa9 ol\xc3", None), ("Café ol\u{FFFD}".to_string(), Some("invalid UTF-8".to_string())));
+    }
+
+    #[test]
+    fn guesses_the_charset_of_unknown_labels() {
+        assert_eq!(decode_text("Café".as_bytes(), Some("x-foo")), ("Café".to_string(), Some("unknown charset 'x-foo'".to_string())));
+        assert_eq!(decode_text(b"Caf\xe9", Some("x-foo")), ("Café".to_string(), Some("unknown charset 'x-foo'".to_string())));
+    }
+
+    #[test]
+    fn decodes_invalid_text_with_replacement_characters() {
+        assert_eq!(decode_text(b"\x1b$B$3\x1b(B\xff", Some("iso-2022-jp")).1, Some("invalid iso-2022-jp".to_string()));
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64(b"SGVsbG8sIFdvcmxkIQ==\r\n"), (b"Hello, World!".to_vec(), None));
+        // Without padding, and chunks put together after their padding
+        assert_eq!(decode_base64(b"SGVsbG8"), (b"Hello".to_vec(), None));
+        assert_eq!(decode_base64(b"SGk=SGk="), (b"HiHi".to_vec(), None));
+    }
+
+    #[test]
+    fn decodes_broken_base64_as_far_as_possible() {
+        assert_eq!(decode_base64(b"SGVs#bG8"), (b"Hello".to_vec(), Some("invalid BASE64".to_string())));
+        assert_eq!(decode_base64(b"SGVsbG8hS"), (b"Hello!".to_vec(), Some("invalid BASE64".to_string())));
+        assert_eq!(decode_transfer(b"SGVsbG8h\r\nS", "BASE64").1, Some("invalid BASE64".to_string()));
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(encode_base64(b"user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(encode_base64(b"a"), "YQ==");
+        assert_eq!(decode_base64(encode_base64(b"\x00\xff\x10").as_bytes()), (b"\x00\xff\x10".to_vec(), None));
+    }
+
+    #[test]
+    fn decodes_transfer_encodings() {
+        assert_eq!(decode_transfer(b"Caf=C3=A9 =\r\nol=C3=A9", "quoted-printable"), ("Café olé".as_bytes().to_vec(), None));
+        assert_eq!(decode_transfer(b"as it is", "8bit"), (b"as it is".to_vec(), None));
+        assert_eq!(decode_transfer(b"as it is", "x-uuencode"), (b"as it is".to_vec(), Some("unknown Content-Transfer-Encoding 'x-uuencode'".to_string())));
+    }
+
+    #[test]
+    fn finds_the_charset_of_html() {
+        assert_eq!(html_charset(b"<html><head><meta charset=\"ISO-8859-1\">"), Some("iso-8859-1".to_string()));
+        assert_eq!(html_charset(b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1252\">"), Some("windows-1252".to_string()));
+        assert_eq!(html_charset(b"<html>"), None);
+    }
+}
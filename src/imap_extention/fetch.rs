@@ -1,169 +1,509 @@
 use imap::client::Client;
-use imap::error::Result;
+use imap::error::{Error, Result};
 use regex::Regex;
+use std::collections::HashSet;
 use std::vec::Vec;
 use std::string::String;
 use std::str::FromStr;
 use std::io::{Read,Write};
-use super::mailparse::{self, ParsedContentType, ParsedMail};
-use std::collections::HashMap;
+use super::mailparse;
+use super::bodystructure::{BodyPart, parse_bodystructure};
+use super::encoding::{decode_text, decode_transfer, html_charset};
+use super::flag::{Flag, parse_flags};
+use super::header::{Address, Headers, ListId, format_addresses, parse_address_list, parse_list_id, parse_msg_ids};
+use super::response::parse_fetch_responses;
 use config::DEFAULT;
+use html::to_mrkdwn;
 use imap::client::Session;
 
 #[derive(Debug)]
 pub struct Mail {
     pub uid: u32,
-    pub flags: String,
-    pub from: String,
-    pub to: String,
-    pub cc: String,
-    pub bcc: String,
-    pub reply_to: String,
+    pub flags: Vec<Flag>,
+    /// INTERNALDATE, when the mail arrived at the server, as a unix timestamp
+    pub internal_date: Option<i64>,
+    /// RFC822.SIZE, the size of the whole mail in bytes
+    pub size: u64,
+    /// Every header field, in the order of the mail
+    pub headers: Headers,
+    pub from: Vec<Address>,
+    pub sender: Vec<Address>,
+    pub reply_to: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub bcc: Vec<Address>,
     pub subject: String,
     pub date: String,
+    /// With the angle brackets, e.g. `<1234@example.com>`
+    pub message_id: String,
+    pub in_reply_to: Vec<String>,
+    pub references: Vec<String>,
+    pub list_id: Option<ListId>,
     pub text: String,
+    /// The parts which are not the text of the mail, without their content (see `Folder::fetch_attachment`)
+    pub attachments: Vec<BodyPart>,
+    /// What could not be decoded properly, e.g. `Subject: unknown charset 'x-foo'`. The mail is still
+    /// posted, with U+FFFD in place of what could not be decoded.
+    pub warnings: Vec<String>,
 }
 
-struct MailBodyPart {
-    headers: HashMap<String, String>,
-    ctype: ParsedContentType,
-    body: String,
-}
+#[allow(dead_code)]
+impl Mail {
+    pub fn print(&self) {
+        let flags: Vec<String> = self.flags.iter().map(|f| f.to_string()).collect();
+        println!("uid: {}\nflags: {}\ninternal_date: {:?}\nsize: {}\nfrom: {}\nto: {}\ncc: {}\nbcc: {}\nreply_to: {}\nsubject: {}\ndate: {}\nmessage_id: {}\nwarnings: {}\ntext: {}\n",
+                 self.uid, flags.join(" "), self.internal_date, self.size, format_addresses(&self.from), format_addresses(&self.to), format_addresses(&self.cc),
+                 format_addresses(&self.bcc), format_addresses(&self.reply_to), self.subject, self.date, self.message_id, self.warnings.join("; "), self.text);
+    }
 
-fn find_mail_body_parts(mbp: &mut Vec<MailBodyPart>, mail_body: ParsedMail) {
-    let ctype = mail_body.ctype.mimetype.to_lowercase();
+    pub fn print_debug(&self) {
+        println!("uid: {:?}\nflags: {:?}\ninternal_date: {:?}\nsize: {:?}\nheaders: {:?}\nfrom: {:?}\nsender: {:?}\nto: {:?}\ncc: {:?}\nbcc: {:?}\nreply_to: {:?}\nsubject: {:?}\ndate: {:?}\nmessage_id: {:?}\nin_reply_to: {:?}\nreferences: {:?}\nlist_id: {:?}\ntext: {:?}\nattachments: {:?}\nwarnings: {:?}\n",
+                 self.uid, self.flags, self.internal_date, self.size, self.headers, self.from, self.sender, self.to, self.cc, self.bcc, self.reply_to, self.subject, self.date,
+                 self.message_id, self.in_reply_to, self.references, self.list_id, self.text, self.attachments, self.warnings);
+    }
 
-    if ctype.contains("multipart/") {
-        for subpart in mail_body.subparts {
-            find_mail_body_parts(mbp, subpart);
-        }
-    } else if ctype.contains("text/plain") {
-        let mut headers = HashMap::new();
-        for header in &mail_body.headers {
-            headers.insert(header.get_key().unwrap(), header.get_value().unwrap());
+    /// The text of a field, e.g. for a filter: `subject`, `text`, an address field (`from`, `sender`, `reply_to`,
+    /// `to`, `cc`, `bcc`) with every display name and address, `list_id`, or the decoded value of any other header.
+    pub fn field(&self, name: &str) -> String {
+        match name.to_lowercase().replace("-", "_").as_ref() {
+            "subject" => self.subject.clone(),
+            "text" => self.text.clone(),
+            "from" => format_addresses(&self.from),
+            "sender" => format_addresses(&self.sender),
+            "reply_to" => format_addresses(&self.reply_to),
+            "to" => format_addresses(&self.to),
+            "cc" => format_addresses(&self.cc),
+            "bcc" => format_addresses(&self.bcc),
+            "list_id" => self.list_id.as_ref().map_or(String::new(), |l| match &l.name {
+                &Some(ref name) => format!("{} <{}>", name, l.id),
+                &None => format!("<{}>", l.id),
+            }),
+            _ => self.headers.get_all(name).join("\n"),
         }
-
-        mbp.push(MailBodyPart{
-            headers: headers,
-            body: mail_body.get_body().unwrap(),
-            ctype: mail_body.ctype,
-        });
     }
-}
 
-#[allow(dead_code)]
-impl Mail {
-    pub fn print(&self) {
-        println!("uid: {}\nflags: {}\nfrom: {}\nto: {}\ncc: {}\nbcc: {}\nreply_to: {}\nsubject: {}\ndate: {}\ntext: {}\n",
-                 self.uid, self.flags, self.from, self.to, self.cc, self.bcc, self.reply_to, self.subject, self.date, self.text);
+    /// `flag` is a system flag like `\Seen` or a keyword, both case-insensitive.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        let flag = Flag::new(flag);
+        self.flags.iter().any(|f| f == &flag)
     }
 
-    pub fn print_debug(&self) {
-        println!("uid: {:?}\nflags: {:?}\nfrom: {:?}\nto: {:?}\ncc: {:?}\nbcc: {:?}\nreply_to: {:?}\nsubject: {:?}\ndate: {:?}\ntext: {:?}\n",
-                 self.uid, self.flags, self.from, self.to, self.cc, self.bcc, self.reply_to, self.subject, self.date, self.text);
+    pub fn keywords(&self) -> Vec<&str> {
+        self.flags.iter().filter_map(|f| match f {
+            &Flag::Keyword(ref keyword) => Some(keyword.as_str()),
+            _ => None,
+        }).collect()
     }
 }
 
 pub trait Folder {
-    fn fetch_mail(&mut self, sequence_set: &Vec<usize>) -> Result<Vec<Mail>>;
+    /// Fetches the mails with `UID FETCH`, so `uid_set` has to be UIDs and not message sequence numbers.
+    /// Only the header and the text part of every mail is downloaded, each cut off after `max_fetch_bytes`.
+    /// The text is the plain text part, or the HTML part rendered as mrkdwn if there is no plain text part or `prefer_html`.
+    /// Keep `uid_set` to a reasonable size (see `fetch_batch_size`), because all the mails are kept in memory.
+    /// The mails are returned in arrival order (INTERNALDATE, then UID).
+    fn fetch_mail(&mut self, uid_set: &[u32], prefer_html: bool) -> Result<Vec<Mail>>;
+
+    /// Sorts UIDs in arrival order by fetching only their INTERNALDATE, so mails fetched in
+    /// several batches can be posted in arrival order too.
+    fn arrival_order(&mut self, uid_set: &[u32]) -> Result<Vec<u32>>;
+
+    /// Fetches a whole attachment of a mail and decodes its Content-Transfer-Encoding.
+    fn fetch_attachment(&mut self, uid: u32, part: &BodyPart) -> Result<Vec<u8>>;
 }
 
 impl<T: Read + Write> Folder for Session<T> {
-    fn fetch_mail(&mut self, sequence_set: &Vec<usize>) -> Result<Vec<Mail>> {
-        let mut r: Vec<Mail> = Vec::new();
-
-        for sequence in sequence_set {
-
-//            let fetch = self.fetch(&sequence.to_string(), "(FLAGS BODY.PEEK[HEADER] BODY.PEEK[TEXT])");
-            let fetch = self.fetch(&sequence.to_string(), "(FLAGS BODY.PEEK[])");
-//            println!("test_1: {:?}", fetch);
-            match fetch {
-                Ok(mut responses) => {
-                    if (*responses).len() > 1 {
-                        println!("WARNING response size is bigger then 1 and you need it find a way to deal with it");
-                    }
-
-                    let response = &(*responses)[0];
-
-                    let mut uid = response.message;
-                    let mut flags = String::new();
-                    let mut from = String::new();
-                    let mut to = String::new();
-                    let mut cc = String::new();
-                    let mut bcc = String::new();
-                    let mut reply_to = String::new();
-                    let mut subject = String::new();
-                    let mut date = String::new();
-                    let mut text: String = String::new();
-
-
-                    let mut counter: usize = 0;
-
-//                    println!("====================================================");
-//                    println!("test_2: {:?}", &response);
-//                    println!("====================================================");
-//                    println!("====================================================");
-//                    println!("test_2: {}", String::from_utf8_lossy((*responses)[0].body().unwrap()));
-//                    println!("====================================================");
-
-                    let mut mail_buffer: Vec<u8> = response.body().unwrap().to_vec();
-
-                    let mail = mailparse::parse_mail(&mail_buffer).unwrap();
-
-                    for header in &mail.headers {
-//                        println!("HEADER -> {}: {}", header.get_key().unwrap(), header.get_value().unwrap());
-                        //                        if mailheader.get_key().unwrap().to_lowercase() ==  "subject" {
-                        //                            subject = mailheader.get_value().unwrap();
-                        //                        }
-
-                        match header.get_key().unwrap().to_lowercase().as_ref() {
-                            "from" => from = header.get_value().unwrap(),
-                            "to" => to = header.get_value().unwrap(),
-                            "cc" => cc = header.get_value().unwrap(),
-                            "bcc" => bcc = header.get_value().unwrap(),
-                            "reply_to" => reply_to = header.get_value().unwrap(),
-                            "subject" => subject = header.get_value().unwrap(),
-                            "date" => date = header.get_value().unwrap(),
-                            _ => (),
-                        }
-                    }
-
-                    let mut mail_body_parts: Vec<MailBodyPart> = Vec::new();
-                    find_mail_body_parts(&mut mail_body_parts, mail);
-
-                    if DEFAULT.debug() {
-                        for i in 0..mail_body_parts.len() {
-                            println!("---===( subpart {} )===---", i);
-                            println!("headers: {:?}", &mail_body_parts[i].headers);
-                            println!("ctype: {:?}", &mail_body_parts[i].ctype);
-                            println!("body.len: {}", &mail_body_parts[i].body.len());
-                            println!("");
-                        }
-                    }
-
-                    for mail_body_part in mail_body_parts {
-                        if mail_body_part.ctype.mimetype.contains("text/plain") {
-                            text = mail_body_part.body;
-                        }
-                    }
-
-                    r.push(Mail {
-                        uid: uid,
-                        flags: flags,
-                        from: from,
-                        to: to,
-                        cc: cc,
-                        bcc: bcc,
-                        reply_to: reply_to,
-                        subject: subject,
-                        date: date,
-                        text: text,
-                    });
-                },
-                Err(e) => return Err(e)
+    fn fetch_mail(&mut self, uid_set: &[u32], prefer_html: bool) -> Result<Vec<Mail>> {
+        if uid_set.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let max = DEFAULT.max_fetch_bytes();
+        let response = self.run_command_and_read_response(&format!("UID FETCH {} (UID FLAGS INTERNALDATE RFC822.SIZE BODYSTRUCTURE BODY.PEEK[HEADER]{})", compress_uid_set(uid_set), partial(max)))?;
+
+        // Every mail with the part which is its text
+        let mut mails: Vec<(Mail, Option<BodyPart>)> = Vec::new();
+
+        for response in parse_fetch_responses(&response) {
+            // The server may also send FETCH responses for other mails, e.g. when their flags change
+            let uid = match response.uid() {
+                Some(uid) if uid_set.contains(&uid) => uid,
+                _ => continue,
+            };
+            let header = match response.body_section("HEADER") {
+                Some(header) => header,
+                None => {
+                    println!("WARNING the server did not send the header of the mail {}", uid);
+                    continue;
+                }
+            };
+
+            let mut mail = parse_mail(uid, header);
+            mail.flags = response.get("FLAGS").map(parse_flags).unwrap_or(Vec::new());
+            mail.internal_date = response.get("INTERNALDATE").and_then(|d| d.as_string()).and_then(|d| parse_internal_date(&d));
+            mail.size = response.get("RFC822.SIZE").and_then(|s| s.as_number()).unwrap_or(0);
+
+            let parts = response.get("BODYSTRUCTURE").map(parse_bodystructure).unwrap_or(Vec::new());
+            if DEFAULT.debug() {
+                for part in &parts {
+                    println!("---===( part {} )===---\n{:?}\n", part.section, part);
+                }
             }
+
+            // The last text/plain and text/html parts, which are not attachments
+            let text_part = |mimetype: &str| parts.iter().filter(|p| p.mimetype == mimetype && !p.is_attachment()).last();
+            let part = match (text_part("text/plain"), text_part("text/html")) {
+                (Some(_), Some(html)) if prefer_html => Some(html),
+                (Some(plain), _) => Some(plain),
+                (None, html) => html,
+            };
+
+            // Everything else, except the other alternatives of the text and the images embedded in the HTML part
+            mail.attachments = parts.iter()
+                .filter(|p| part.map_or(true, |part| part.section != p.section))
+                .filter(|p| p.is_attachment() || !(p.mimetype == "text/plain" || p.mimetype == "text/html" || (p.mimetype.starts_with("image/") && p.id.is_some())))
+                .cloned()
+                .collect();
+
+            mails.push((mail, part.cloned()));
         }
 
+        // The text parts are fetched with one command per section, e.g. `1` for most of the mails,
+        // instead of one command per mail
+        let mut sections: Vec<String> = mails.iter().filter_map(|&(_, ref part)| part.as_ref().map(|p| p.section.clone())).collect();
+        sections.sort();
+        sections.dedup();
+
+        for section in sections {
+            let uids: Vec<u32> = mails.iter()
+                .filter(|&&(_, ref part)| part.as_ref().map_or(false, |p| p.section == section))
+                .map(|&(ref mail, _)| mail.uid)
+                .collect();
+            let mut bodies = self.fetch_sections(&uids, &section, max)?;
+
+            for &mut (ref mut mail, ref part) in &mut mails {
+                let part = match part {
+                    &Some(ref part) if part.section == section => part,
+                    _ => continue,
+                };
+                let body = bodies.iter().position(|&(uid, _)| uid == mail.uid).map(|i| bodies.swap_remove(i).1);
+
+                let (text, truncated) = decode_text_part(mail.uid, part, body, max, &mut mail.warnings);
+                mail.text = if part.mimetype == "text/html" { to_mrkdwn(&text) } else { text };
+                if truncated {
+                    mail.text.push_str("\n…");
+                }
+            }
+        }
+
+        let mut r: Vec<Mail> = mails.into_iter().map(|(mail, _)| mail).collect();
+        for mail in &r {
+            for warning in &mail.warnings {
+                println!("WARNING the mail {} could not be decoded properly, {}", mail.uid, warning);
+            }
+        }
+
+        // Like `arrival_order`, mails without a date last
+        r.sort_by_key(|mail| (mail.internal_date.is_none(), mail.internal_date, mail.uid));
         Ok(r)
     }
-}
\ No newline at end of file
+
+    fn arrival_order(&mut self, uid_set: &[u32]) -> Result<Vec<u32>> {
+        if uid_set.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let response = self.run_command_and_read_response(&format!("UID FETCH {} (UID INTERNALDATE)", compress_uid_set(uid_set)))?;
+
+        let dates: Vec<(u32, Option<i64>)> = parse_fetch_responses(&response).iter()
+            .filter_map(|response| response.uid().map(|uid| (uid, response.get("INTERNALDATE").and_then(|d| d.as_string()).and_then(|d| parse_internal_date(&d)))))
+            .collect();
+        Ok(sort_by_date(uid_set, dates))
+    }
+
+    fn fetch_attachment(&mut self, uid: u32, part: &BodyPart) -> Result<Vec<u8>> {
+        let response = self.run_command_and_read_response(&format!("UID FETCH {} (BODY.PEEK[{}])", uid, part.section))?;
+
+        let body = parse_fetch_responses(&response).into_iter()
+            .filter(|response| response.uid() == Some(uid))
+            .filter_map(|response| response.body_section(&part.section).map(|b| b.to_vec()))
+            .next();
+        let body = match body {
+            Some(body) => body,
+            None => return Err(Error::NoResponse(vec![format!("The server did not send the part {} of the mail {}", part.section, uid)])),
+        };
+
+        // Broken attachments are uploaded anyway, like a mail program would show them
+        let (data, warning) = decode_transfer(&body, &part.encoding);
+        if let Some(warning) = warning {
+            println!("WARNING the part {} of the mail {} could not be decoded properly, {}", part.section, uid, warning);
+        }
+        Ok(data)
+    }
+}
+
+/// Sorts `uid_set` by the dates, then by UID. Mails without a date come last, e.g. the ones the server did not
+/// send a date for because it expunged them in between, in UID order. Dates of other UIDs are ignored.
+fn sort_by_date(uid_set: &[u32], dates: Vec<(u32, Option<i64>)>) -> Vec<u32> {
+    let wanted: HashSet<u32> = uid_set.iter().cloned().collect();
+    let mut sorted: Vec<(Option<i64>, u32)> = Vec::with_capacity(uid_set.len());
+    let mut found: HashSet<u32> = HashSet::with_capacity(uid_set.len());
+
+    for (uid, date) in dates {
+        if wanted.contains(&uid) && date.is_some() && found.insert(uid) {
+            sorted.push((date, uid));
+        }
+    }
+    for &uid in uid_set {
+        if found.insert(uid) {
+            sorted.push((None, uid));
+        }
+    }
+
+    // `None` is less than every date, so it is sorted on whether there is a date first
+    sorted.sort_by_key(|&(date, uid)| (date.is_none(), date, uid));
+    sorted.into_iter().map(|(_, uid)| uid).collect()
+}
+
+/// Parses an INTERNALDATE like `17-Jul-1996 02:44:25 -0700` (RFC 3501, section 9) into a unix timestamp.
+fn parse_internal_date(date: &str) -> Option<i64> {
+    let date = date.trim();
+    // Same as the date of a mail header, only with dashes in the date
+    let (day, time) = match date.find(' ') {
+        Some(i) => date.split_at(i),
+        None => return None,
+    };
+    match mailparse::dateparse(&format!("{}{}", day.replace("-", " "), time)) {
+        Ok(timestamp) => Some(timestamp),
+        Err(e) => {
+            println!("WARNING failed to parse the INTERNALDATE '{}': {}", date, e);
+            None
+        }
+    }
+}
+
+/// `<0.max>` to only fetch the first `max` bytes, nothing if `max` is 0.
+fn partial(max: u64) -> String {
+    if max == 0 {
+        String::new()
+    } else {
+        format!("<0.{}>", max)
+    }
+}
+
+trait FetchText {
+    fn fetch_sections(&mut self, uid_set: &[u32], section: &str, max: u64) -> Result<Vec<(u32, Vec<u8>)>>;
+}
+
+impl<T: Read + Write> FetchText for Session<T> {
+    /// Fetches the same part of several mails with a single `UID FETCH`, each cut off after `max` bytes.
+    /// Returns the undecoded parts by UID, without the mails the server did not send the part of.
+    fn fetch_sections(&mut self, uid_set: &[u32], section: &str, max: u64) -> Result<Vec<(u32, Vec<u8>)>> {
+        let response = self.run_command_and_read_response(&format!("UID FETCH {} (BODY.PEEK[{}]{})", compress_uid_set(uid_set), section, partial(max)))?;
+
+        Ok(parse_fetch_responses(&response).into_iter()
+            .filter_map(|response| match (response.uid(), response.body_section(section)) {
+                (Some(uid), Some(body)) if uid_set.contains(&uid) => Some((uid, body.to_vec())),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Decodes a text part according to its Content-Transfer-Encoding and charset. Also returns if the part
+/// was cut off after `max` bytes. Decoding problems are added to `warnings`.
+fn decode_text_part(uid: u32, part: &BodyPart, body: Option<Vec<u8>>, max: u64, warnings: &mut Vec<String>) -> (String, bool) {
+    let mut body = match body {
+        Some(body) => body,
+        None => {
+            println!("WARNING the server did not send the part {} of the mail {}", part.section, uid);
+            return (String::new(), false);
+        }
+    };
+
+    // Cut at the last line break, so the transfer encoding (BASE64 or quoted-printable) is not broken in the middle
+    let truncated = max != 0 && part.size > max;
+    if truncated {
+        if let Some(i) = body.iter().rposition(|&b| b == b'\n') {
+            body.truncate(i + 1);
+        }
+    }
+
+    (decode_part(part, &body, warnings), truncated)
+}
+
+/// Decodes the Content-Transfer-Encoding and the charset of a text part. A HTML part without a charset
+/// in its Content-Type may have it in a `<meta>` tag.
+fn decode_part(part: &BodyPart, body: &[u8], warnings: &mut Vec<String>) -> String {
+    let (bytes, transfer_warning) = decode_transfer(body, &part.encoding);
+
+    let charset = match part.param("charset") {
+        Some(charset) => Some(charset.to_string()),
+        None if part.mimetype == "text/html" => html_charset(&bytes),
+        None => None,
+    };
+    let (text, charset_warning) = decode_text(&bytes, charset.as_ref().map(|c| c.as_str()));
+
+    for warning in transfer_warning.into_iter().chain(charset_warning) {
+        warnings.push(format!("part {}: {}", part.section, warning));
+    }
+    text
+}
+
+/// Compresses UIDs into an IMAP sequence set, e.g. `[1, 2, 3, 4, 72]` becomes `1:4,72`.
+pub fn compress_uid_set(uids: &[u32]) -> String {
+    let mut uids = uids.to_vec();
+    uids.sort();
+    uids.dedup();
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < uids.len() {
+        let start = uids[i];
+        let mut end = start;
+        while i + 1 < uids.len() && uids[i + 1] == end + 1 {
+            end = uids[i + 1];
+            i += 1;
+        }
+
+        if start == end {
+            ranges.push(start.to_string());
+        } else {
+            ranges.push(format!("{}:{}", start, end));
+        }
+        i += 1;
+    }
+
+    ranges.join(",")
+}
+
+/// Parses the header of a mail. The text is fetched on its own.
+fn parse_mail(uid: u32, header: &[u8]) -> Mail {
+    let headers = Headers::parse(header);
+
+    // A broken mail may have a field more than once, so the addresses of every one of them are used
+    let addresses = |name: &str| -> Vec<Address> {
+        headers.get_all_raw(name).iter().flat_map(|raw| parse_address_list(raw)).collect()
+    };
+    let msg_ids = |name: &str| -> Vec<String> {
+        headers.get_all_raw(name).iter().flat_map(|raw| parse_msg_ids(raw)).collect()
+    };
+
+    // Not every Message-ID is valid, but it is still better than nothing for recognising the mail
+    let message_id = match msg_ids("Message-ID").into_iter().next() {
+        Some(id) => id,
+        None => headers.get("Message-ID").unwrap_or("").trim().to_string(),
+    };
+
+    Mail {
+        uid: uid,
+        flags: Vec::new(),
+        internal_date: None,
+        size: 0,
+        from: addresses("From"),
+        sender: addresses("Sender"),
+        reply_to: addresses("Reply-To"),
+        to: addresses("To"),
+        cc: addresses("Cc"),
+        bcc: addresses("Bcc"),
+        subject: headers.get("Subject").unwrap_or("").to_string(),
+        date: headers.get("Date").unwrap_or("").to_string(),
+        message_id: message_id,
+        in_reply_to: msg_ids("In-Reply-To"),
+        references: msg_ids("References"),
+        list_id: headers.get_all_raw("List-Id").iter().filter_map(|raw| parse_list_id(raw)).next(),
+        text: String::new(),
+        attachments: Vec::new(),
+        warnings: headers.warnings(),
+        headers: headers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_part(charset: Option<&str>, encoding: &str) -> BodyPart {
+        BodyPart {
+            section: "1".to_string(),
+            mimetype: "text/plain".to_string(),
+            params: charset.map(|c| ("charset".to_string(), c.to_string())).into_iter().collect(),
+            id: None,
+            encoding: encoding.to_string(),
+            size: 0,
+            disposition: None,
+            disposition_params: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_the_header_of_a_mail() {
+        let mail = parse_mail(7, b"From: Alice <alice@example.com>\r\nTo: bob@example.com\r\nSubject: =?utf-8?q?Caf=C3=A9?=\r\n\
+                                  Message-ID: <1@example.com>\r\nReferences: <0@example.com>\r\n\r\n");
+        assert_eq!(mail.uid, 7);
+        assert_eq!(format_addresses(&mail.from), "Alice <alice@example.com>");
+        assert_eq!(mail.subject, "Café");
+        assert_eq!(mail.message_id, "<1@example.com>");
+        assert_eq!(mail.references, vec!["<0@example.com>"]);
+        assert!(mail.warnings.is_empty());
+    }
+
+    #[test]
+    fn keeps_every_warning_of_the_header() {
+        let mail = parse_mail(1, b"Subject: Caf\xe9 =?x-foo?q?ol=C3=A9?=\r\nFrom: =?utf-8?b?w6#?= <bob@example.com>\r\n\r\n");
+        assert_eq!(mail.subject, "Café olé");
+        assert_eq!(mail.warnings, vec![
+            "Subject: not UTF-8, decoded as windows-1252",
+            "Subject: unknown charset 'x-foo'",
+            "From: invalid BASE64",
+            "From: not UTF-8, decoded as windows-1252",
+        ]);
+    }
+
+    #[test]
+    fn decodes_parts_with_warnings() {
+        let mut warnings = Vec::new();
+        assert_eq!(decode_part(&text_part(Some("utf-8"), "base64"), b"SGVq*IGRl\r\nciE", &mut warnings), "Hej der!");
+        assert_eq!(decode_part(&text_part(Some("x-mystery"), "8bit"), "Still readable: æøå".as_bytes(), &mut warnings), "Still readable: æøå");
+        assert_eq!(decode_part(&text_part(Some("iso-8859-1"), "quoted-printable"), b"Caf=E9", &mut warnings), "Café");
+        assert_eq!(warnings, vec!["part 1: invalid BASE64", "part 1: unknown charset 'x-mystery'"]);
+    }
+
+    #[test]
+    fn decodes_parts_with_the_charset_of_their_html() {
+        let mut part = text_part(None, "8bit");
+        part.mimetype = "text/html".to_string();
+        let mut warnings = Vec::new();
+        assert_eq!(decode_part(&part, b"<meta charset=\"iso-8859-1\"><p>Caf\xe9</p>", &mut warnings), "<meta charset=\"iso-8859-1\"><p>Café</p>");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn cuts_truncated_parts_at_a_line_break() {
+        let mut part = text_part(Some("utf-8"), "base64");
+        part.size = 100;
+        let mut warnings = Vec::new();
+        // The BASE64 of the second line is cut off in the middle
+        assert_eq!(decode_text_part(1, &part, Some(b"SGVqIGRlciE=\r\nSGVq".to_vec()), 16, &mut warnings), ("Hej der!".to_string(), true));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn sorts_by_date_then_by_uid() {
+        let dates = vec![(3, Some(200)), (1, Some(300)), (2, Some(200)), (4, None), (9, Some(100))];
+        assert_eq!(sort_by_date(&[1, 2, 3, 4, 5], dates), vec![2, 3, 1, 4, 5]);
+    }
+
+    #[test]
+    fn sorts_mails_without_a_date_last() {
+        // The date of a mail sent twice is the first one with a date
+        let dates = vec![(5, None), (5, Some(50)), (6, Some(10)), (7, None)];
+        assert_eq!(sort_by_date(&[8, 7, 6, 5], dates), vec![6, 5, 7, 8]);
+    }
+
+    #[test]
+    fn compresses_uid_sets() {
+        assert_eq!(compress_uid_set(&[72, 1, 2, 3, 4, 3]), "1:4,72");
+        assert_eq!(compress_uid_set(&[5]), "5");
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::vec::Vec;
+use std::string::String;
+
+use super::response::Value;
+
+/// A flag of a mail (RFC 3501, section 2.3.2). Everything which is not a system flag is a keyword.
+#[derive(Debug,Clone)]
+pub enum Flag {
+    Seen,
+    Answered,
+    Flagged,
+    Deleted,
+    Draft,
+    Recent,
+    Keyword(String),
+}
+
+impl Flag {
+    pub fn new(flag: &str) -> Flag {
+        match flag.to_lowercase().as_ref() {
+            "\\seen" => Flag::Seen,
+            "\\answered" => Flag::Answered,
+            "\\flagged" => Flag::Flagged,
+            "\\deleted" => Flag::Deleted,
+            "\\draft" => Flag::Draft,
+            "\\recent" => Flag::Recent,
+            _ => Flag::Keyword(flag.to_string()),
+        }
+    }
+}
+
+/// Is `keyword` an atom (RFC 3501, section 9), which is what a keyword has to be? So no spaces, no control
+/// characters and none of `(){%*"\]`, which also rules out system flags.
+pub fn is_keyword(keyword: &str) -> bool {
+    !keyword.is_empty() && !keyword.chars().any(|c| c < '\u{21}' || c > '\u{7e}' || "(){%*\"\\]".contains(c))
+}
+
+/// Flags are case-insensitive, so `$Forwarded` and `$forwarded` are the same keyword.
+impl PartialEq for Flag {
+    fn eq(&self, other: &Flag) -> bool {
+        match (self, other) {
+            (&Flag::Keyword(ref a), &Flag::Keyword(ref b)) => a.eq_ignore_ascii_case(b),
+            (a, b) => a.to_string() == b.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Flag::Seen => write!(f, "\\Seen"),
+            &Flag::Answered => write!(f, "\\Answered"),
+            &Flag::Flagged => write!(f, "\\Flagged"),
+            &Flag::Deleted => write!(f, "\\Deleted"),
+            &Flag::Draft => write!(f, "\\Draft"),
+            &Flag::Recent => write!(f, "\\Recent"),
+            &Flag::Keyword(ref keyword) => write!(f, "{}", keyword),
+        }
+    }
+}
+
+/// The flags of a `FLAGS (...)` data item.
+pub fn parse_flags(value: &Value) -> Vec<Flag> {
+    value.as_list()
+        .map(|list| list.iter().filter_map(|v| v.as_string()).map(|f| Flag::new(&f)).collect())
+        .unwrap_or(Vec::new())
+}
@@ -1,31 +1,221 @@
-use imap::client::Client;
 use imap::error::Result;
 use std::vec::Vec;
+use std::string::String;
 use std::io::{Read,Write};
+use imap::client::Session;
+
+/// A mailbox from a LIST or LSUB response.
+#[derive(Debug,Clone)]
+pub struct FolderInfo {
+    pub name: String,
+    pub delimiter: Option<String>,
+    pub attributes: Vec<String>,
+}
+
+impl FolderInfo {
+    /// `\Noselect` and `\NonExistent` mailboxes only exist as a part of the hierarchy and cannot be selected.
+    pub fn is_selectable(&self) -> bool {
+        !self.attributes.iter().any(|a| {
+            let a = a.to_lowercase();
+            a == "\\noselect" || a == "\\nonexistent"
+        })
+    }
+}
 
 pub trait Folder {
+    fn list_folder_info(&mut self, dir: &str, filter_sub_folders: &str, subscribed: bool) -> Result<Vec<FolderInfo>>;
     fn list_folders(&mut self, dir: &str, filter_sub_folders: &str) -> Result<Vec<String>>;
     fn list_root_folders(&mut self) -> Result<Vec<String>>;
     fn list_sub_folders(&mut self, dir: &str, ) -> Result<Vec<String>>;
+    fn hierarchy_delimiter(&mut self) -> Result<Option<String>>;
+}
+
+impl<T: Read + Write> Folder for Session<T> {
+    /// Runs LIST (or LSUB if `subscribed`). `filter_sub_folders` may contain the wildcards `*` and `%`,
+    /// which are matched by the server (RFC 3501, section 6.3.8).
+    fn list_folder_info(&mut self, dir: &str, filter_sub_folders: &str, subscribed: bool) -> Result<Vec<FolderInfo>> {
+        let command = if subscribed { "LSUB" } else { "LIST" };
+        match self.run_command_and_read_response(&format!("{} {} {}", command, quote(dir), quote(filter_sub_folders))) {
+            Ok(response) => Ok(parse_list_response(&response, command)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_folders(&mut self, dir: &str, filter_sub_folders: &str) -> Result<Vec<String>> {
+        self.list_folder_info(dir, filter_sub_folders, false).map(|folders| folders.into_iter().map(|f| f.name).collect())
+    }
+
+    #[allow(dead_code)]
+    fn list_root_folders(&mut self) -> Result<Vec<String>> {
+        self.list_folders("", "%")
+    }
+
+    #[allow(dead_code)]
+    fn list_sub_folders(&mut self, dir: &str, ) -> Result<Vec<String>> {
+        let delimiter = match self.list_folder_info(dir, "", false) {
+            Ok(ref folders) if !folders.is_empty() => folders[0].delimiter.clone().unwrap_or(String::new()),
+            Ok(_) => String::new(),
+            Err(e) => return Err(e),
+        };
+        self.list_folders("", &format!("{}{}%", dir, delimiter))
+    }
+
+    /// `LIST "" ""` returns the hierarchy delimiter of the server (RFC 3501, section 6.3.8).
+    fn hierarchy_delimiter(&mut self) -> Result<Option<String>> {
+        match self.list_folder_info("", "", false) {
+            Ok(folders) => Ok(folders.into_iter().next().and_then(|f| f.delimiter)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Does the mailbox name contain a LIST wildcard?
+pub fn has_wildcard(mailbox: &str) -> bool {
+    mailbox.contains('*') || mailbox.contains('%')
+}
+
+/// Quotes a string for an IMAP command (RFC 3501, section 4.3).
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
 }
 
-//impl<T: Read + Write> Folder for Client<T> {
-//    fn list_folders(&mut self, dir: &str, filter_sub_folders: &str) -> Result<Vec<String>> {
-//        match self.run_command_and_read_response(&format!("LIST \"{}\" \"{}\"", dir, filter_sub_folders)) {
-//            Ok(lines) => {
-//                return Ok(lines);
-//            }
-//            Err(e) => return Err(e),
-//        }
-//    }
-//
-//    #[allow(dead_code)]
-//    fn list_root_folders(&mut self) -> Result<Vec<String>> {
-//        self.list_folders("", "%")
-//    }
-//
-//    #[allow(dead_code)]
-//    fn list_sub_folders(&mut self, dir: &str, ) -> Result<Vec<String>> {
-//        self.list_folders(dir, "%")
-//    }
-//}
\ No newline at end of file
+/// Parses the untagged `* LIST (attributes) "delimiter" name` lines of a response.
+/// The name can be an atom, a quoted string or a literal.
+pub fn parse_list_response(response: &[u8], command: &str) -> Vec<FolderInfo> {
+    let prefix = format!("* {} ", command).into_bytes();
+    let mut folders: Vec<FolderInfo> = Vec::new();
+    let mut pos = 0;
+
+    while pos < response.len() {
+        let line_end = find_line_end(response, pos);
+        let line = &response[pos..line_end];
+
+        if line.len() > prefix.len() && line[..prefix.len()].eq_ignore_ascii_case(&prefix) {
+            let mut parser = Parser { data: response, pos: pos + prefix.len() };
+            if let Some(folder) = parser.list_entry() {
+                folders.push(folder);
+            }
+            pos = parser.pos;
+        }
+
+        pos = find_line_end(response, pos) + 2;
+    }
+
+    folders
+}
+
+fn find_line_end(data: &[u8], from: usize) -> usize {
+    let mut i = from;
+    while i + 1 < data.len() {
+        if data[i] == b'\r' && data[i + 1] == b'\n' {
+            return i;
+        }
+        i += 1;
+    }
+    data.len()
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn list_entry(&mut self) -> Option<FolderInfo> {
+        let attributes = self.attributes()?;
+        self.skip_spaces();
+        let delimiter = self.nstring()?;
+        self.skip_spaces();
+        let name = self.astring()?;
+
+        Some(FolderInfo {
+            name: name,
+            delimiter: delimiter,
+            attributes: attributes,
+        })
+    }
+
+    fn attributes(&mut self) -> Option<Vec<String>> {
+        if self.peek()? != b'(' {
+            return None;
+        }
+        self.pos += 1;
+
+        let start = self.pos;
+        while self.peek()? != b')' {
+            self.pos += 1;
+        }
+        let attributes = String::from_utf8_lossy(&self.data[start..self.pos]).split_whitespace().map(|a| a.to_string()).collect();
+        self.pos += 1;
+
+        Some(attributes)
+    }
+
+    fn nstring(&mut self) -> Option<Option<String>> {
+        if self.data[self.pos..].len() >= 3 && self.data[self.pos..self.pos + 3].eq_ignore_ascii_case(b"NIL") {
+            self.pos += 3;
+            return Some(None);
+        }
+        self.astring().map(Some)
+    }
+
+    fn astring(&mut self) -> Option<String> {
+        match self.peek()? {
+            b'"' => self.quoted(),
+            b'{' => self.literal(),
+            _ => {
+                let start = self.pos;
+                while self.pos < self.data.len() && self.data[self.pos] != b' ' && self.data[self.pos] != b'\r' {
+                    self.pos += 1;
+                }
+                Some(String::from_utf8_lossy(&self.data[start..self.pos]).into_owned())
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut s: Vec<u8> = Vec::new();
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.pos += 1;
+                    return Some(String::from_utf8_lossy(&s).into_owned());
+                },
+                b'\\' => {
+                    self.pos += 1;
+                    s.push(self.peek()?);
+                },
+                c => s.push(c),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self) -> Option<String> {
+        self.pos += 1;
+        let start = self.pos;
+        while self.peek()? != b'}' {
+            self.pos += 1;
+        }
+        let size: usize = String::from_utf8_lossy(&self.data[start..self.pos]).parse().ok()?;
+        // Skip `}\r\n`
+        self.pos += 3;
+        if self.pos + size > self.data.len() {
+            return None;
+        }
+        let s = String::from_utf8_lossy(&self.data[self.pos..self.pos + size]).into_owned();
+        self.pos += size;
+        Some(s)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.pos < self.data.len() && self.data[self.pos] == b' ' {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+}
@@ -0,0 +1,722 @@
+use std::fmt;
+use std::vec::Vec;
+use std::string::String;
+use super::encoding::{decode_base64, decode_text};
+
+/// A header field, unfolded.
+#[derive(Debug,Clone)]
+pub struct HeaderField {
+    pub name: String,
+    /// As in the mail, encoded words are not decoded
+    pub raw: String,
+    /// With encoded words (RFC 2047) decoded
+    pub value: String,
+    /// What could not be decoded properly, e.g. an unknown charset
+    pub warnings: Vec<String>,
+}
+
+/// The header fields of a mail in the order of the mail (RFC 5322, section 2.2).
+/// Names are case-insensitive and a field can occur more than once.
+#[derive(Debug,Clone,Default)]
+pub struct Headers {
+    fields: Vec<HeaderField>,
+}
+
+impl Headers {
+    /// Header fields should be ASCII, but raw 8 bit text is common, so every line is decoded on its own,
+    /// see `decode_text`.
+    pub fn parse(header: &[u8]) -> Headers {
+        let mut fields: Vec<HeaderField> = Vec::new();
+
+        for line in header.split(|&b| b == b'\n') {
+            let line = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                break;
+            }
+            let (line, warning) = decode_text(line, None);
+
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some(field) = fields.last_mut() {
+                    field.raw.push_str(&line);
+                    add_warnings(&mut field.warnings, warning);
+                }
+            } else if let Some(colon) = line.find(':') {
+                fields.push(HeaderField { name: line[..colon].trim().to_string(), raw: line[colon + 1..].to_string(), value: String::new(), warnings: warning.into_iter().collect() });
+            }
+            // A line without a colon is not a header field, so it is skipped
+        }
+
+        for field in &mut fields {
+            field.raw = field.raw.trim().to_string();
+            let (value, warnings) = decode_words_checked(&field.raw);
+            field.value = value;
+            add_warnings(&mut field.warnings, warnings);
+        }
+
+        Headers { fields: fields }
+    }
+
+    /// The decoded value of the first field with the name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.value.as_str())
+    }
+
+    /// The raw value of every field with the name.
+    pub fn get_all_raw(&self, name: &str) -> Vec<&str> {
+        self.fields.iter().filter(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.raw.as_str()).collect()
+    }
+
+    /// The decoded value of every field with the name.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields.iter().filter(|f| f.name.eq_ignore_ascii_case(name)).map(|f| f.value.as_str()).collect()
+    }
+
+    /// Every warning of the fields which could not be decoded properly, e.g. `Subject: unknown charset 'x-foo'`.
+    pub fn warnings(&self) -> Vec<String> {
+        self.fields.iter().flat_map(|f| f.warnings.iter().map(move |w| format!("{}: {}", f.name, w))).collect()
+    }
+}
+
+/// Adds the warnings which are not there yet, e.g. every line of a folded field in the same wrong charset is one warning.
+fn add_warnings<I: IntoIterator<Item = String>>(warnings: &mut Vec<String>, new: I) {
+    for warning in new {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+}
+
+/// Decodes the encoded words (RFC 2047) of a header value, e.g. `=?iso-8859-1?q?J=F8rgen?=`.
+pub fn decode_words(raw: &str) -> String {
+    decode_words_checked(raw).0
+}
+
+/// Same as `decode_words`, but also returns a warning for everything which could not be decoded properly.
+///
+/// Broken encoded words are common, so they are decoded anyway: inside quotes or words, with white space
+/// inside them, without padding, or with a character split over two encoded words. Unknown charsets are
+/// guessed like text without a charset, see `decode_text`.
+pub fn decode_words_checked(raw: &str) -> (String, Vec<String>) {
+    if !raw.contains("=?") {
+        return (raw.to_string(), Vec::new());
+    }
+
+    let mut r = String::new();
+    let mut warnings: Vec<String> = Vec::new();
+    // The bytes of the encoded words in a row with the same charset, which are decoded together
+    let mut pending: Option<(String, Vec<u8>)> = None;
+
+    let mut rest = raw;
+    while let Some(word) = encoded_word(rest) {
+        // White space between two encoded words is not shown (RFC 2047, section 6.2)
+        let between = &rest[..word.start];
+        if pending.is_none() || !between.trim().is_empty() {
+            warnings.extend(flush_words(&mut pending, &mut r));
+            r.push_str(between);
+        }
+
+        match pending {
+            Some((ref charset, ref mut bytes)) if charset.eq_ignore_ascii_case(&word.charset) => bytes.extend_from_slice(&word.bytes),
+            _ => {
+                warnings.extend(flush_words(&mut pending, &mut r));
+                pending = Some((word.charset, word.bytes));
+            },
+        }
+        warnings.extend(word.warning);
+
+        rest = &rest[word.end..];
+    }
+    warnings.extend(flush_words(&mut pending, &mut r));
+    r.push_str(rest);
+
+    let mut unique: Vec<String> = Vec::new();
+    add_warnings(&mut unique, warnings);
+    (r, unique)
+}
+
+/// Decodes the pending encoded words in their charset and adds them to `r`.
+fn flush_words(pending: &mut Option<(String, Vec<u8>)>, r: &mut String) -> Option<String> {
+    let (charset, bytes) = pending.take()?;
+    let (text, warning) = decode_text(&bytes, Some(&charset));
+    r.push_str(&text);
+    warning
+}
+
+/// An encoded word `=?charset?encoding?text?=`, with the text decoded to bytes in the charset.
+struct EncodedWord {
+    start: usize,
+    end: usize,
+    /// Without a language (RFC 2231, section 5)
+    charset: String,
+    bytes: Vec<u8>,
+    /// If the text is broken, e.g. invalid BASE64
+    warning: Option<String>,
+}
+
+/// The first encoded word in `s`.
+fn encoded_word(s: &str) -> Option<EncodedWord> {
+    let mut from = 0;
+    while let Some(i) = s[from..].find("=?") {
+        let start = from + i;
+        from = start + 2;
+
+        let word = &s[start + 2..];
+        let charset_end = match word.find('?') {
+            Some(end) if end > 0 && !word[..end].contains(char::is_whitespace) => end,
+            _ => continue,
+        };
+        // Only `B` or `Q`, so the encoding is a single byte, even in a raw 8 bit header
+        let encoding = match word.as_bytes().get(charset_end + 1..charset_end + 3) {
+            Some(&[c, b'?']) if c.eq_ignore_ascii_case(&b'b') || c.eq_ignore_ascii_case(&b'q') => c.to_ascii_lowercase(),
+            _ => continue,
+        };
+        let text_start = charset_end + 3;
+        let text_end = match word[text_start..].find("?=") {
+            Some(end) => text_start + end,
+            None => continue,
+        };
+
+        let text = &word.as_bytes()[text_start..text_end];
+        let (bytes, warning) = match encoding {
+            b'b' => decode_base64(text),
+            b'q' => (decode_q(text), None),
+            _ => continue,
+        };
+        let charset = word[..charset_end].split('*').next().unwrap_or("").to_string();
+        return Some(EncodedWord { start: start, end: start + 2 + text_end + 2, charset: charset, bytes: bytes, warning: warning });
+    }
+    None
+}
+
+/// The Q encoding (RFC 2047, section 4.2): quoted-printable with `_` for a space. A `=` which is not
+/// followed by two hex digits is kept.
+fn decode_q(text: &[u8]) -> Vec<u8> {
+    let mut r: Vec<u8> = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let hex = if text[i] == b'=' && i + 2 < text.len() && text[i + 1].is_ascii_hexdigit() && text[i + 2].is_ascii_hexdigit() {
+            ::std::str::from_utf8(&text[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        match (text[i], hex) {
+            (_, Some(byte)) => {
+                r.push(byte);
+                i += 3;
+            },
+            (b'_', None) => {
+                r.push(b' ');
+                i += 1;
+            },
+            (b, None) => {
+                r.push(b);
+                i += 1;
+            },
+        }
+    }
+    r
+}
+
+/// A mailbox (RFC 5322, section 3.4), e.g. `"Bob Smith" <bob@example.com>`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Mailbox {
+    /// The display name, decoded
+    pub name: Option<String>,
+    pub local: String,
+    /// Empty if the address has no domain, e.g. `undisclosed-recipients` or the null address `<>`
+    pub domain: String,
+}
+
+impl Mailbox {
+    /// `local@domain`, with the local part quoted if needed.
+    pub fn address(&self) -> String {
+        let dot_atom = !self.local.is_empty() && self.local.split('.').all(|atom| !atom.is_empty() && atom.chars().all(is_atext));
+        let local = if dot_atom || self.local.is_empty() {
+            self.local.clone()
+        } else {
+            format!("\"{}\"", self.local.replace("\\", "\\\\").replace("\"", "\\\""))
+        };
+
+        if self.domain.is_empty() {
+            local
+        } else {
+            format!("{}@{}", local, self.domain)
+        }
+    }
+}
+
+impl fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.name, self.local.is_empty()) {
+            (&Some(ref name), _) => write!(f, "{} <{}>", name, self.address()),
+            (&None, true) => write!(f, "<>"),
+            (&None, false) => write!(f, "{}", self.address()),
+        }
+    }
+}
+
+/// An address is either a mailbox or a named group of mailboxes, e.g. `Team: alice@example.com, bob@example.com;`.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Address {
+    Mailbox(Mailbox),
+    Group { name: String, members: Vec<Mailbox> },
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Address::Mailbox(ref mailbox) => write!(f, "{}", mailbox),
+            &Address::Group { ref name, ref members } => {
+                let members: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+                write!(f, "{}: {};", name, members.join(", "))
+            },
+        }
+    }
+}
+
+/// An address list as text, e.g. `Alice <alice@example.com>, bob@example.com`.
+pub fn format_addresses(addresses: &[Address]) -> String {
+    let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+    addresses.join(", ")
+}
+
+/// The `List-Id` of a mailing list (RFC 2919), e.g. `Rust users <users.rust-lang.org>`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ListId {
+    pub name: Option<String>,
+    /// Without the angle brackets
+    pub id: String,
+}
+
+pub fn parse_list_id(raw: &str) -> Option<ListId> {
+    let start = raw.find('<')?;
+    let end = start + raw[start..].find('>')?;
+    let id = raw[start + 1..end].trim().to_string();
+    if id.is_empty() {
+        return None;
+    }
+
+    let name = decode_words(raw[..start].trim().trim_matches('"').trim());
+    Some(ListId { name: if name.is_empty() { None } else { Some(name) }, id: id })
+}
+
+/// The message IDs of a `Message-ID`, `In-Reply-To` or `References` field, with the angle brackets.
+/// Comments and whitespace inside the IDs are dropped.
+pub fn parse_msg_ids(raw: &str) -> Vec<String> {
+    let mut r: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+
+    for token in Lexer::new(raw) {
+        match (token, &mut current) {
+            (Token::Char('<'), current) => *current = Some("<".to_string()),
+            (Token::Char('>'), current) => {
+                if let Some(mut id) = current.take() {
+                    id.push('>');
+                    r.push(id);
+                }
+            },
+            (token, &mut Some(ref mut id)) => id.push_str(&token.text()),
+            _ => (),
+        }
+    }
+
+    r
+}
+
+/// Parses an address list (RFC 5322, section 3.4) from a raw header value. Broken addresses are skipped as
+/// well as possible, so the rest of the list is still parsed.
+pub fn parse_address_list(raw: &str) -> Vec<Address> {
+    let tokens: Vec<Token> = Lexer::new(raw).collect();
+    let mut parser = AddressParser { tokens: tokens, pos: 0 };
+    let mut r: Vec<Address> = Vec::new();
+
+    while parser.peek().is_some() {
+        if let Some(address) = parser.address() {
+            r.push(address);
+        }
+        // Skip to the next address, in case this one was broken
+        while let Some(token) = parser.next_token() {
+            if token == Token::Char(',') {
+                break;
+            }
+        }
+    }
+
+    r
+}
+
+fn is_atext(c: char) -> bool {
+    c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    /// An atom or a quoted string, without the quotes
+    Word(String, bool),
+    /// `[192.168.0.1]`, with the brackets
+    DomainLiteral(String),
+    /// The text of a comment, which is only used as a display name by the obsolete `bob@example.com (Bob)`
+    Comment(String),
+    Char(char),
+}
+
+impl Token {
+    fn text(&self) -> String {
+        match self {
+            &Token::Word(ref s, _) | &Token::DomainLiteral(ref s) => s.clone(),
+            &Token::Comment(_) => String::new(),
+            &Token::Char(c) => c.to_string(),
+        }
+    }
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Lexer {
+    fn new(raw: &str) -> Lexer {
+        Lexer { chars: raw.chars().collect(), pos: 0 }
+    }
+
+    /// Until the closing `delimiter`, with quoted pairs unescaped. Comments may be nested.
+    fn until(&mut self, open: char, delimiter: char) -> String {
+        let mut s = String::new();
+        let mut depth = 0;
+        while self.pos < self.chars.len() {
+            let c = self.chars[self.pos];
+            self.pos += 1;
+            match c {
+                '\\' if self.pos < self.chars.len() => {
+                    s.push(self.chars[self.pos]);
+                    self.pos += 1;
+                },
+                c if c == delimiter && depth == 0 => return s,
+                c => {
+                    if c == open && open != delimiter {
+                        depth += 1;
+                    } else if c == delimiter {
+                        depth -= 1;
+                    }
+                    s.push(c);
+                },
+            }
+        }
+        s
+    }
+}
+
+impl Iterator for Lexer {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+
+        let c = *self.chars.get(self.pos)?;
+        self.pos += 1;
+        match c {
+            '"' => Some(Token::Word(self.until('"', '"'), true)),
+            '(' => Some(Token::Comment(self.until('(', ')'))),
+            '[' => Some(Token::DomainLiteral(format!("[{}]", self.until('[', ']')))),
+            '<' | '>' | ',' | ':' | ';' | '@' | '.' | ')' | ']' => Some(Token::Char(c)),
+            _ => {
+                let start = self.pos - 1;
+                while self.pos < self.chars.len() {
+                    let c = self.chars[self.pos];
+                    if c.is_whitespace() || "\"()<>[]:;@\\,.".contains(c) {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                Some(Token::Word(self.chars[start..self.pos].iter().collect(), false))
+            },
+        }
+    }
+}
+
+struct AddressParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl AddressParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn skip_comments(&mut self) -> Option<String> {
+        let mut comment = None;
+        while let Some(&Token::Comment(ref c)) = self.peek() {
+            comment = Some(c.clone());
+            self.pos += 1;
+        }
+        comment
+    }
+
+    /// Words and dots, until one of the characters `<>,:;@` or the end.
+    fn phrase(&mut self) -> (Vec<Token>, Option<String>) {
+        let mut words: Vec<Token> = Vec::new();
+        let mut comment = None;
+        loop {
+            match self.peek() {
+                Some(&Token::Word(..)) | Some(&Token::Char('.')) | Some(&Token::DomainLiteral(_)) => words.push(self.next_token().unwrap()),
+                Some(&Token::Comment(_)) => comment = self.skip_comments(),
+                _ => return (words, comment),
+            }
+        }
+    }
+
+    fn address(&mut self) -> Option<Address> {
+        let (phrase, comment) = self.phrase();
+
+        match self.peek().cloned() {
+            Some(Token::Char(':')) => {
+                self.pos += 1;
+                let mut members: Vec<Mailbox> = Vec::new();
+                loop {
+                    match self.peek() {
+                        None => break,
+                        Some(&Token::Char(';')) => {
+                            self.pos += 1;
+                            break;
+                        },
+                        Some(&Token::Char(',')) => {
+                            self.pos += 1;
+                            continue;
+                        },
+                        _ => (),
+                    }
+
+                    let (phrase, comment) = self.phrase();
+                    match self.mailbox(phrase, comment) {
+                        Some(mailbox) => members.push(mailbox),
+                        // Skip the broken member
+                        None => while let Some(token) = self.peek().cloned() {
+                            if token == Token::Char(',') || token == Token::Char(';') {
+                                break;
+                            }
+                            self.pos += 1;
+                        },
+                    }
+                }
+                Some(Address::Group { name: display_name(&phrase).unwrap_or(String::new()), members: members })
+            },
+            _ => self.mailbox(phrase, comment).map(Address::Mailbox),
+        }
+    }
+
+    /// `name <local@domain>`, `local@domain` or only `local`. The phrase in front has already been read.
+    fn mailbox(&mut self, phrase: Vec<Token>, comment: Option<String>) -> Option<Mailbox> {
+        match self.peek().cloned() {
+            Some(Token::Char('<')) => {
+                self.pos += 1;
+                self.skip_comments();
+                // Obsolete source route, e.g. `<@relay.example.com:bob@example.com>`
+                if self.peek() == Some(&Token::Char('@')) {
+                    while let Some(token) = self.next_token() {
+                        if token == Token::Char(':') {
+                            break;
+                        }
+                    }
+                }
+
+                let (local, _) = self.phrase();
+                let domain = if self.peek() == Some(&Token::Char('@')) {
+                    self.pos += 1;
+                    join(&self.phrase().0, "")
+                } else {
+                    String::new()
+                };
+                self.skip_comments();
+                // A missing `>` at the end is forgiven, anything else in its place means the address is broken
+                match self.peek() {
+                    Some(&Token::Char('>')) => self.pos += 1,
+                    None => (),
+                    Some(_) => return None,
+                }
+
+                Some(Mailbox { name: display_name(&phrase), local: join(&local, ""), domain: domain })
+            },
+            // An addr-spec needs a local part
+            Some(Token::Char('@')) if phrase.is_empty() => None,
+            Some(Token::Char('@')) => {
+                self.pos += 1;
+                let (domain, after) = self.phrase();
+                let domain = join(&domain, "");
+                let comment = after.or(comment);
+                Some(Mailbox { name: comment.map(|c| decode_words(c.trim())).filter(|c| !c.is_empty()), local: join(&phrase, ""), domain: domain })
+            },
+            _ if !phrase.is_empty() => Some(Mailbox { name: None, local: join(&phrase, ""), domain: String::new() }),
+            _ => None,
+        }
+    }
+}
+
+/// Joins words, with `separator` in front of every word but the first, e.g. `John Q. Public`.
+fn join(tokens: &[Token], separator: &str) -> String {
+    let mut s = String::new();
+    for token in tokens {
+        if !s.is_empty() && *token != Token::Char('.') {
+            s.push_str(separator);
+        }
+        s.push_str(&token.text());
+    }
+    s
+}
+
+/// The phrase of a name-addr or group, with encoded words decoded. Quoted words are decoded too,
+/// because some mailers put encoded words in quotes.
+fn display_name(phrase: &[Token]) -> Option<String> {
+    let name = decode_words(join(phrase, " ").trim());
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailbox(name: Option<&str>, local: &str, domain: &str) -> Address {
+        Address::Mailbox(Mailbox { name: name.map(|n| n.to_string()), local: local.to_string(), domain: domain.to_string() })
+    }
+
+    #[test]
+    fn parses_name_addr_and_addr_spec() {
+        assert_eq!(parse_address_list("\"Bob Smith\" <bob@example.com>, alice@example.com"),
+                   vec![mailbox(Some("Bob Smith"), "bob", "example.com"), mailbox(None, "alice", "example.com")]);
+        assert_eq!(parse_address_list("John Q. Public <john.q.public@example.com>"),
+                   vec![mailbox(Some("John Q. Public"), "john.q.public", "example.com")]);
+    }
+
+    #[test]
+    fn parses_groups() {
+        let addresses = parse_address_list("Team: alice@example.com, Bob <bob@example.com>;, carol@example.com");
+        assert_eq!(addresses, vec![
+            Address::Group { name: "Team".to_string(), members: vec![
+                Mailbox { name: None, local: "alice".to_string(), domain: "example.com".to_string() },
+                Mailbox { name: Some("Bob".to_string()), local: "bob".to_string(), domain: "example.com".to_string() },
+            ] },
+            mailbox(None, "carol", "example.com"),
+        ]);
+        assert_eq!(format_addresses(&addresses), "Team: alice@example.com, Bob <bob@example.com>;, carol@example.com");
+    }
+
+    #[test]
+    fn parses_empty_groups() {
+        let addresses = parse_address_list("undisclosed-recipients:;");
+        assert_eq!(addresses, vec![Address::Group { name: "undisclosed-recipients".to_string(), members: Vec::new() }]);
+        assert_eq!(format_addresses(&addresses), "undisclosed-recipients: ;");
+    }
+
+    #[test]
+    fn parses_quoted_local_parts() {
+        let addresses = parse_address_list("\"john smith\"@example.com, \"a\\\"b\\\\c\"@example.com");
+        assert_eq!(addresses, vec![mailbox(None, "john smith", "example.com"), mailbox(None, "a\"b\\c", "example.com")]);
+        // Quoted again when written, because they are not a dot-atom
+        assert_eq!(format_addresses(&addresses), "\"john smith\"@example.com, \"a\\\"b\\\\c\"@example.com");
+    }
+
+    #[test]
+    fn parses_escaped_display_names() {
+        assert_eq!(parse_address_list("\"Smith, John \\\"JJ\\\"\" <john@example.com>"),
+                   vec![mailbox(Some("Smith, John \"JJ\""), "john", "example.com")]);
+    }
+
+    #[test]
+    fn uses_comments_as_display_names() {
+        assert_eq!(parse_address_list("bob@example.com (Bob Smith), alice@example.com (=?utf-8?q?Al=C3=AFce?=)"),
+                   vec![mailbox(Some("Bob Smith"), "bob", "example.com"), mailbox(Some("Alïce"), "alice", "example.com")]);
+        // Only without a display name
+        assert_eq!(parse_address_list("Bob (the builder) <bob@example.com>"), vec![mailbox(Some("Bob"), "bob", "example.com")]);
+    }
+
+    #[test]
+    fn skips_obsolete_routes() {
+        assert_eq!(parse_address_list("Bob <@relay1.example.com,@relay2.example.com:bob@example.com>"),
+                   vec![mailbox(Some("Bob"), "bob", "example.com")]);
+    }
+
+    #[test]
+    fn parses_the_null_address() {
+        let addresses = parse_address_list("<>");
+        assert_eq!(addresses, vec![mailbox(None, "", "")]);
+        assert_eq!(format_addresses(&addresses), "<>");
+    }
+
+    #[test]
+    fn decodes_encoded_display_names() {
+        assert_eq!(parse_address_list("=?iso-8859-1?q?J=F8rgen?= <jorgen@example.com>"),
+                   vec![mailbox(Some("Jørgen"), "jorgen", "example.com")]);
+    }
+
+    #[test]
+    fn skips_broken_addresses_in_a_list() {
+        assert_eq!(parse_address_list("alice@example.com, <<>>@@, , bob@example.com"),
+                   vec![mailbox(None, "alice", "example.com"), mailbox(None, "bob", "example.com")]);
+        assert_eq!(parse_address_list("Team: alice@example.com, @;, bob@example.com"), vec![
+            Address::Group { name: "Team".to_string(), members: vec![Mailbox { name: None, local: "alice".to_string(), domain: "example.com".to_string() }] },
+            mailbox(None, "bob", "example.com"),
+        ]);
+    }
+
+    #[test]
+    fn parses_msg_ids() {
+        assert_eq!(parse_msg_ids("<1234@example.com>"), vec!["<1234@example.com>"]);
+        assert_eq!(parse_msg_ids("<a@example.com> (a comment)\r\n <b@example.com>\t<c@example.com>"),
+                   vec!["<a@example.com>", "<b@example.com>", "<c@example.com>"]);
+        assert!(parse_msg_ids("no id").is_empty());
+    }
+
+    #[test]
+    fn parses_every_references_field() {
+        let headers = Headers::parse(b"References: <a@example.com>\r\n <b@example.com>\r\nSubject: Re: Hi\r\nReferences: <c@example.com>\r\n\r\n");
+        let references: Vec<String> = headers.get_all_raw("references").iter().flat_map(|raw| parse_msg_ids(raw)).collect();
+        assert_eq!(references, vec!["<a@example.com>", "<b@example.com>", "<c@example.com>"]);
+    }
+
+    #[test]
+    fn parses_list_ids() {
+        assert_eq!(parse_list_id("Rust users <users.rust-lang.org>"), Some(ListId { name: Some("Rust users".to_string()), id: "users.rust-lang.org".to_string() }));
+        assert_eq!(parse_list_id("<users.rust-lang.org>"), Some(ListId { name: None, id: "users.rust-lang.org".to_string() }));
+        assert_eq!(parse_list_id("<>"), None);
+    }
+
+    #[test]
+    fn returns_every_warning() {
+        let headers = Headers::parse(b"Subject: =?x-foo?q?a?= b =?utf-8?b?w6#?=\r\nFrom: =?x-foo?q?Bob?= <bob@example.com>\r\n\r\n");
+        assert_eq!(headers.warnings(), vec![
+            "Subject: unknown charset 'x-foo'",
+            "Subject: invalid BASE64",
+            // The half of `é` which is left
+            "Subject: not UTF-8, decoded as windows-1252",
+            "From: unknown charset 'x-foo'",
+        ]);
+    }
+
+    #[test]
+    fn returns_a_warning_once_for_a_folded_field() {
+        let headers = Headers::parse(b"Subject: Caf\xe9\r\n ol\xe9\r\n\r\n");
+        assert_eq!(headers.get("Subject"), Some("Café olé"));
+        assert_eq!(headers.warnings(), vec!["Subject: not UTF-8, decoded as windows-1252"]);
+    }
+
+    #[test]
+    fn unfolds_header_fields() {
+        let headers = Headers::parse(b"Subject: a long\r\n\tsubject\r\nX-Empty:\r\n\r\nBody: not a header\r\n");
+        assert_eq!(headers.get("subject"), Some("a long\tsubject"));
+        assert_eq!(headers.get("X-Empty"), Some(""));
+        assert_eq!(headers.get("Body"), None);
+    }
+}
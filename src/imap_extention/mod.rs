@@ -7,7 +7,14 @@
 
 extern crate mailparse;
 
+pub mod action;
+pub mod bodystructure;
+pub mod encoding;
+pub mod capability;
+pub mod flag;
 pub mod folder;
+pub mod header;
 pub mod path;
+pub mod response;
 pub mod search;
 pub mod fetch;
\ No newline at end of file
@@ -1,59 +1,248 @@
 use std::string::String;
 use imap::error::Result;
 use regex::Regex;
-use imap::client::Client;
 use std::io::{Read,Write};
 use std::str::FromStr;
 use imap::Mailbox;
 use imap::client::Session;
+use super::encoding::{decode_base64, encode_base64};
 
-#[allow(dead_code)]#[derive(Debug)]
+#[allow(dead_code)]#[derive(Debug,Clone,PartialEq)]
 pub struct Status {
     pub messages: u32,
     pub recent: u32,
     pub unseen: u32,
+    pub uid_next: Option<u32>,
+    pub uid_validity: Option<u32>,
+    pub highest_modseq: Option<u64>,
 }
 
+/// A mailbox name as written in the config file: UTF-8 with `/` between the levels of the hierarchy.
+/// On the server the name is modified UTF-7 (RFC 3501, section 5.1.3) and uses the hierarchy
+/// delimiter of the server, which can be found with LIST.
 pub struct Path {
     inner: String,
+    delimiter: Option<String>,
 }
 
 impl Path {
     pub fn new(path: &str) -> Path {
-        Path {inner: String::from(path)}
-    }
-
-//    #[allow(dead_code)]
-//    pub fn status<T: Read + Write>(&self, client: &mut Client<T>) -> Result<Status> {
-//        match client.run_command_and_read_response(&format!("STATUS \"{}\" (MESSAGES UNSEEN RECENT)", self.inner)) {
-//            Ok(response) => {
-//                let response: String = response.into_iter().collect();
-//
-//                let messages = Regex::new(r"MESSAGES (\d+)").unwrap().captures(&response);
-//                let recent   = Regex::new(r"RECENT (\d+)").unwrap().captures(&response);
-//                let unseen   = Regex::new(r"UNSEEN (\d+)").unwrap().captures(&response);
-//
-//                Ok(Status {
-//                    messages: messages.map_or(0, |s| s.get(1).map_or(0, |ss| u32::from_str(ss.as_str()).unwrap())),
-//                    recent:     recent.map_or(0, |s| s.get(1).map_or(0, |ss| u32::from_str(ss.as_str()).unwrap())),
-//                    unseen:     unseen.map_or(0, |s| s.get(1).map_or(0, |ss| u32::from_str(ss.as_str()).unwrap()))
-//                })
-//            },
-//            Err(e) => return Err(e)
-//        }
-//    }
+        Path {inner: String::from(path), delimiter: None}
+    }
+
+    /// A mailbox name as returned by the server, e.g. from LIST.
+    pub fn from_server(name: &str, delimiter: Option<&str>) -> Path {
+        let mut inner = decode_utf7(name);
+        if let Some(delimiter) = delimiter {
+            if delimiter != "/" {
+                inner = inner.replace(delimiter, "/");
+            }
+        }
+
+        Path {inner: inner, delimiter: delimiter.map(|d| d.to_string())}
+    }
+
+    /// Use the hierarchy delimiter of the server instead of `/`.
+    pub fn with_delimiter(mut self, delimiter: Option<&str>) -> Path {
+        self.delimiter = delimiter.map(|d| d.to_string());
+        self
+    }
+
+    /// Runs STATUS on the mailbox, which does not change the selected mailbox (RFC 3501, section 6.3.10).
+    /// It must not be the selected mailbox, see `PathFrom::unselect`.
+    /// HIGHESTMODSEQ is only asked for with `condstore`, because other servers reply BAD to it.
+    pub fn status<T: Read + Write>(&self, session: &mut Session<T>, condstore: bool) -> Result<Status> {
+        let items = if condstore { "MESSAGES UNSEEN RECENT UIDNEXT UIDVALIDITY HIGHESTMODSEQ" } else { "MESSAGES UNSEEN RECENT UIDNEXT UIDVALIDITY" };
+        match session.run_command_and_read_response(&format!("STATUS {} ({})", self.quoted(), items)) {
+            Ok(response) => Ok(parse_status_response(&String::from_utf8_lossy(&response))),
+            Err(e) => Err(e)
+        }
+    }
 
     pub fn as_str(&self) -> &str {
         self.inner.as_str()
     }
+
+    pub fn delimiter(&self) -> Option<&str> {
+        self.delimiter.as_ref().map(|d| d.as_str())
+    }
+
+    /// The name as the server knows it: with the hierarchy delimiter of the server and encoded as modified UTF-7.
+    pub fn encoded(&self) -> String {
+        match &self.delimiter {
+            &Some(ref delimiter) if delimiter != "/" => encode_utf7(&self.inner.replace("/", delimiter)),
+            _ => encode_utf7(&self.inner),
+        }
+    }
+
+    /// The encoded name as a quoted string, ready to be used in a command.
+    pub fn quoted(&self) -> String {
+        format!("\"{}\"", escape(&self.encoded()))
+    }
+}
+
+/// Parses the `* STATUS name (ITEM value ...)` line of a response. The name can contain anything,
+/// so the item list is found as the parenthesized list which follows it.
+pub fn parse_status_response(response: &str) -> Status {
+    let mut status = Status {
+        messages: 0,
+        recent: 0,
+        unseen: 0,
+        uid_next: None,
+        uid_validity: None,
+        highest_modseq: None,
+    };
+
+    let items = Regex::new(r"(?i)\((\s*(?:MESSAGES|RECENT|UIDNEXT|UIDVALIDITY|UNSEEN|HIGHESTMODSEQ)\s+\d+)*\s*\)\s*$").unwrap();
+
+    for line in response.lines() {
+        let line = line.trim_end();
+        if let Some(list) = items.find(line) {
+            let list = &line[list.start() + 1..list.end() - 1];
+            let words: Vec<&str> = list.split_whitespace().collect();
+
+            for pair in words.chunks(2) {
+                if pair.len() != 2 {
+                    continue;
+                }
+                if pair[0].eq_ignore_ascii_case("HIGHESTMODSEQ") {
+                    status.highest_modseq = u64::from_str(pair[1]).ok();
+                    continue;
+                }
+                let value = match u32::from_str(pair[1]) {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+
+                match pair[0].to_uppercase().as_ref() {
+                    "MESSAGES" => status.messages = value,
+                    "RECENT" => status.recent = value,
+                    "UNSEEN" => status.unseen = value,
+                    "UIDNEXT" => status.uid_next = Some(value),
+                    "UIDVALIDITY" => status.uid_validity = Some(value),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    status
 }
 
 pub trait PathFrom {
     fn select_from(&mut self, path: &Path) -> Result<Mailbox>;
+    /// Leaves the selected mailbox `path`, so STATUS can be used on it again. With UNSELECT (RFC 3691) if
+    /// `has_unselect`, otherwise with EXAMINE and CLOSE, because CLOSE on a mailbox selected read-write
+    /// would expunge every mail marked as `\Deleted`.
+    fn unselect(&mut self, path: &Path, has_unselect: bool) -> Result<()>;
 }
 
 impl<T: Read + Write> PathFrom for Session<T>{
     fn select_from(&mut self, path: &Path) -> Result<Mailbox> {
-        self.select(path.as_str())
+        // `select` puts the name in quotes, but does not escape it
+        self.select(&escape(&path.encoded()))
+    }
+
+    fn unselect(&mut self, path: &Path, has_unselect: bool) -> Result<()> {
+        if has_unselect {
+            return self.run_command_and_read_response("UNSELECT").map(|_| ());
+        }
+
+        self.run_command_and_read_response(&format!("EXAMINE {}", path.quoted()))?;
+        self.run_command_and_read_response("CLOSE").map(|_| ())
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("\"", "\\\"")
+}
+
+/// Encodes a mailbox name as modified UTF-7. Printable ASCII is kept as it is (`&` becomes `&-`),
+/// everything else is written as UTF-16 in modified BASE64 between `&` and `-`.
+pub fn encode_utf7(s: &str) -> String {
+    let mut r = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+
+    for c in s.chars() {
+        if c >= '\u{20}' && c <= '\u{7e}' {
+            if !pending.is_empty() {
+                r.push_str(&encode_modified_base64(&pending));
+                pending.clear();
+            }
+            if c == '&' {
+                r.push_str("&-");
+            } else {
+                r.push(c);
+            }
+        } else {
+            let mut buf = [0u16; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+
+    if !pending.is_empty() {
+        r.push_str(&encode_modified_base64(&pending));
+    }
+
+    r
+}
+
+/// Modified BASE64 is BASE64 with `,` instead of `/` and without padding (RFC 3501, section 5.1.3).
+fn encode_modified_base64(utf16: &Vec<u16>) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    for u in utf16 {
+        bytes.push((u >> 8) as u8);
+        bytes.push((u & 0xff) as u8);
+    }
+
+    format!("&{}-", encode_base64(&bytes).trim_end_matches('=').replace('/', ","))
+}
+
+/// Decodes a modified UTF-7 mailbox name. Anything that is not valid modified UTF-7 is kept as it is.
+pub fn decode_utf7(s: &str) -> String {
+    let mut r = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find('&') {
+        r.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let end = match rest.find('-') {
+            Some(end) => end,
+            None => {
+                r.push('&');
+                continue;
+            }
+        };
+
+        if end == 0 {
+            r.push('&');
+        } else {
+            match decode_modified_base64(&rest[..end]) {
+                Some(decoded) => r.push_str(&decoded),
+                None => {
+                    r.push('&');
+                    r.push_str(&rest[..end + 1]);
+                }
+            }
+        }
+        rest = &rest[end + 1..];
     }
-}
\ No newline at end of file
+
+    r.push_str(rest);
+    r
+}
+
+fn decode_modified_base64(s: &str) -> Option<String> {
+    if !s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b',') {
+        return None;
+    }
+
+    let (bytes, warning) = decode_base64(s.replace(',', "/").as_bytes());
+    if warning.is_some() || bytes.len() % 2 != 0 {
+        return None;
+    }
+
+    let utf16: Vec<u16> = bytes.chunks(2).map(|b| ((b[0] as u16) << 8) | b[1] as u16).collect();
+    String::from_utf16(&utf16).ok()
+}
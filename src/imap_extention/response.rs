@@ -0,0 +1,246 @@
+use std::vec::Vec;
+use std::string::String;
+use std::str::FromStr;
+
+/// A value in a response (RFC 3501, section 4): NIL, a number, a string (quoted or literal),
+/// an atom or a parenthesized list.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value {
+    Nil,
+    Number(u64),
+    String(Vec<u8>),
+    Atom(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    /// The value of a string, an atom or a number, `None` for anything else.
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            &Value::String(ref s) => Some(String::from_utf8_lossy(s).into_owned()),
+            &Value::Atom(ref s) => Some(s.clone()),
+            &Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            &Value::String(ref s) => Some(s),
+            &Value::Atom(ref s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<u64> {
+        match self {
+            &Value::Number(n) => Some(n),
+            &Value::Atom(ref s) => u64::from_str(s).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            &Value::List(ref l) => Some(l),
+            _ => None,
+        }
+    }
+}
+
+/// The data items of a `* n FETCH (...)` response, e.g. `UID`, `FLAGS` or `BODY[1.2]<0>`.
+/// The names are uppercased.
+#[derive(Debug,Clone)]
+pub struct FetchResponse {
+    pub seq: u32,
+    pub items: Vec<(String, Value)>,
+}
+
+impl FetchResponse {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.items.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v)
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.get("UID").and_then(|v| v.as_number()).map(|n| n as u32)
+    }
+
+    /// The first `BODY[section]` item, no matter if it is a partial (`BODY[section]<origin>`) or not.
+    pub fn body_section(&self, section: &str) -> Option<&[u8]> {
+        let name = format!("BODY[{}]", section.to_uppercase());
+        self.items.iter()
+            .find(|&&(ref n, _)| n == &name || (n.starts_with(&name) && n[name.len()..].starts_with('<')))
+            .and_then(|&(_, ref v)| v.as_bytes())
+    }
+}
+
+/// Parses every untagged FETCH response of a response. Everything else is skipped.
+pub fn parse_fetch_responses(response: &[u8]) -> Vec<FetchResponse> {
+    let mut r: Vec<FetchResponse> = Vec::new();
+    let mut pos = 0;
+
+    while pos < response.len() {
+        let mut parser = Parser { data: response, pos: pos };
+        if let Some(fetch) = parser.fetch_response() {
+            r.push(fetch);
+        }
+
+        pos = find_line_end(response, parser.pos.max(pos)) + 2;
+    }
+
+    r
+}
+
+fn find_line_end(data: &[u8], from: usize) -> usize {
+    let mut i = from;
+    while i + 1 < data.len() {
+        if data[i] == b'\r' && data[i + 1] == b'\n' {
+            return i;
+        }
+        i += 1;
+    }
+    data.len()
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// `* n FETCH (name value name value ...)`
+    fn fetch_response(&mut self) -> Option<FetchResponse> {
+        if self.peek()? != b'*' {
+            return None;
+        }
+        self.pos += 1;
+        self.skip_spaces();
+
+        let seq = match self.value()? {
+            Value::Number(n) => n as u32,
+            _ => return None,
+        };
+        self.skip_spaces();
+
+        match self.value()? {
+            Value::Atom(ref a) if a.eq_ignore_ascii_case("FETCH") => (),
+            _ => return None,
+        }
+        self.skip_spaces();
+
+        let list = match self.value()? {
+            Value::List(list) => list,
+            _ => return None,
+        };
+
+        let mut items: Vec<(String, Value)> = Vec::new();
+        let mut list = list.into_iter();
+        while let (Some(name), Some(value)) = (list.next(), list.next()) {
+            if let Some(name) = name.as_string() {
+                items.push((name.to_uppercase(), value));
+            }
+        }
+
+        Some(FetchResponse { seq: seq, items: items })
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        match self.peek()? {
+            b'(' => self.list(),
+            b'"' => self.quoted().map(Value::String),
+            b'{' => self.literal().map(Value::String),
+            _ => {
+                let atom = self.atom()?;
+                if atom.eq_ignore_ascii_case("NIL") {
+                    Some(Value::Nil)
+                } else if !atom.is_empty() && atom.bytes().all(|b| b.is_ascii_digit()) {
+                    u64::from_str(&atom).ok().map(Value::Number).or(Some(Value::Atom(atom)))
+                } else {
+                    Some(Value::Atom(atom))
+                }
+            },
+        }
+    }
+
+    fn list(&mut self) -> Option<Value> {
+        self.pos += 1;
+        let mut list: Vec<Value> = Vec::new();
+        loop {
+            self.skip_spaces();
+            match self.peek()? {
+                b')' => {
+                    self.pos += 1;
+                    return Some(Value::List(list));
+                },
+                _ => list.push(self.value()?),
+            }
+        }
+    }
+
+    /// An atom, which may contain a section in brackets with spaces in it, e.g. `BODY[HEADER.FIELDS (FROM)]<0>`.
+    fn atom(&mut self) -> Option<String> {
+        let start = self.pos;
+        let mut brackets = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                b'[' => brackets += 1,
+                b']' => brackets -= 1,
+                b' ' | b'(' | b')' | b'\r' | b'\n' if brackets <= 0 => break,
+                b'\r' | b'\n' => return None,
+                _ => (),
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.data[start..self.pos]).into_owned())
+    }
+
+    fn quoted(&mut self) -> Option<Vec<u8>> {
+        self.pos += 1;
+        let mut s: Vec<u8> = Vec::new();
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.pos += 1;
+                    return Some(s);
+                },
+                b'\\' => {
+                    self.pos += 1;
+                    s.push(self.peek()?);
+                },
+                c => s.push(c),
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// `{size}\r\n` followed by `size` bytes. `{size+}` is only sent by clients, but does no harm.
+    fn literal(&mut self) -> Option<Vec<u8>> {
+        self.pos += 1;
+        let start = self.pos;
+        while self.peek()? != b'}' {
+            self.pos += 1;
+        }
+        let size: usize = String::from_utf8_lossy(&self.data[start..self.pos]).trim_end_matches('+').parse().ok()?;
+        // Skip `}\r\n`
+        self.pos += 3;
+        if self.pos + size > self.data.len() {
+            return None;
+        }
+        let s = self.data[self.pos..self.pos + size].to_vec();
+        self.pos += size;
+        Some(s)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.pos < self.data.len() && self.data[self.pos] == b' ' {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+}
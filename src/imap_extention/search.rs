@@ -1,4 +1,3 @@
-use imap::client::Client;
 use imap::error::Result;
 use regex::Regex;
 use std::vec::Vec;
@@ -6,44 +5,590 @@ use std::string::String;
 use std::io::{Read,Write};
 use std::str::FromStr;
 use imap::client::Session;
+use connection::fatal;
+use super::flag::is_keyword;
 
+/// The search keys of RFC 3501, section 6.4.4. A `Vec<SEARCH>` means all of the keys have to match.
 #[allow(dead_code)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum SEARCH {
+    ALL,
+    ANSWERED,
+    UNANSWERED,
+    DELETED,
+    UNDELETED,
+    DRAFT,
+    UNDRAFT,
+    FLAGGED,
+    UNFLAGGED,
+    NEW,
+    OLD,
+    RECENT,
     SEEN,
     UNSEEN,
+    FROM(String),
+    TO(String),
+    CC(String),
+    BCC(String),
+    SUBJECT(String),
+    BODY(String),
+    TEXT(String),
+    HEADER(String, String),
+    /// Dates are either IMAP dates (`1-Feb-2018`) or `2018-02-01`
+    SINCE(String),
+    BEFORE(String),
+    ON(String),
+    SENTSINCE(String),
+    SENTBEFORE(String),
+    SENTON(String),
+    LARGER(u32),
+    SMALLER(u32),
+    KEYWORD(String),
+    UNKEYWORD(String),
+    /// A UID sequence set, e.g. `1:100,200:*`
+    UID(String),
+    /// Mails changed at or after the mod-sequence (RFC 7162, CONDSTORE)
+    MODSEQ(u64),
+    OR(Box<SEARCH>, Box<SEARCH>),
+    NOT(Box<SEARCH>),
+    AND(Vec<SEARCH>),
+}
+
+impl SEARCH {
+    /// Parses a search expression written like the IMAP SEARCH command, e.g.
+    /// `UNSEEN OR FROM "list@example.com" SUBJECT "[Announce]" NOT (KEYWORD $Slacked)`.
+    pub fn parse(expression: &str) -> ::std::result::Result<Vec<SEARCH>, String> {
+        let tokens = tokenize(expression)?;
+        let mut pos = 0;
+        let mut keys: Vec<SEARCH> = Vec::new();
+
+        while pos < tokens.len() {
+            keys.push(parse_key(&tokens, &mut pos)?);
+        }
+
+        if keys.is_empty() {
+            keys.push(SEARCH::ALL);
+        }
+
+        Ok(keys)
+    }
+
+    /// Can a mail start to match because of a flag change, which the UNSEEN, UIDNEXT and UIDVALIDITY
+    /// of STATUS do not show? `\Seen` changes UNSEEN, and `\Recent` is only set on new mail.
+    /// `keyword` marks the posted mails (see `keyword` in the config), so UNKEYWORD of it does not count.
+    pub fn depends_on_flags(&self, keyword: Option<&str>) -> bool {
+        match self {
+            &SEARCH::ANSWERED | &SEARCH::UNANSWERED | &SEARCH::DELETED | &SEARCH::UNDELETED | &SEARCH::DRAFT
+            | &SEARCH::UNDRAFT | &SEARCH::FLAGGED | &SEARCH::UNFLAGGED | &SEARCH::OLD | &SEARCH::KEYWORD(_) => true,
+            &SEARCH::UNKEYWORD(ref k) => keyword.map_or(true, |keyword| !k.eq_ignore_ascii_case(keyword)),
+            &SEARCH::OR(ref a, ref b) => a.depends_on_flags(keyword) || b.depends_on_flags(keyword),
+            &SEARCH::NOT(ref a) => a.depends_on_flags(keyword),
+            &SEARCH::AND(ref keys) => keys.iter().any(|k| k.depends_on_flags(keyword)),
+            _ => false,
+        }
+    }
+
+    fn has_non_ascii(&self) -> bool {
+        match self {
+            &SEARCH::FROM(ref s) | &SEARCH::TO(ref s) | &SEARCH::CC(ref s) | &SEARCH::BCC(ref s)
+            | &SEARCH::SUBJECT(ref s) | &SEARCH::BODY(ref s) | &SEARCH::TEXT(ref s) => !s.is_ascii(),
+            &SEARCH::HEADER(ref name, ref value) => !name.is_ascii() || !value.is_ascii(),
+            &SEARCH::OR(ref a, ref b) => a.has_non_ascii() || b.has_non_ascii(),
+            &SEARCH::NOT(ref a) => a.has_non_ascii(),
+            &SEARCH::AND(ref keys) => keys.iter().any(|k| k.has_non_ascii()),
+            _ => false,
+        }
+    }
+}
+
+/// The untagged data of a SEARCH (RFC 3501) or ESEARCH (RFC 4731) response.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct SearchResponse {
+    pub uids: Vec<u32>,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    pub count: Option<u32>,
+    /// The `(MODSEQ n)` trailer of CONDSTORE (RFC 7162)
+    pub modseq: Option<u64>,
 }
 
 pub trait Search {
-    fn search2(&mut self, filter: Vec<SEARCH>) -> Result<Vec<usize>>;
+    /// Runs `UID SEARCH`, so the result are UIDs and not message sequence numbers.
+    /// `literal_plus` is whether the server has the LITERAL+ capability, see `search_response`.
+    fn search2(&mut self, filter: Vec<SEARCH>, literal_plus: bool) -> Result<Vec<u32>>;
+    fn search_response(&mut self, filter: Vec<SEARCH>, literal_plus: bool) -> Result<SearchResponse>;
 }
 
 impl<T: Read + Write> Search for Session<T> {
-    fn search2(&mut self, filter: Vec<SEARCH>) -> Result<Vec<usize>> {
-        let criteria: String = filter.iter().map(|s| format!("{} ", search2str(s))).collect();
-        let search_result = self.run_command_and_read_response(&format!("SEARCH {}", criteria.trim()));
-        println!("Search; {:?}", &search_result);
-        match search_result {
-            Ok(response) => {
-                let mut uids: Vec<usize> = Vec::new();
-
-                let response = String::from_utf8(response).expect("Failed to convert 'Vec<u8>' to 'String', but this should not happen");
-                println!("{}", &response);
-                if response.to_uppercase().contains("SEARCH") {
-                    for uid in Regex::new(r"(?P<uid>\d+)").unwrap().captures_iter(&response) {
-                        uids.push(usize::from_str(&uid["uid"]).unwrap());
-                    }
-                }
+    fn search2(&mut self, filter: Vec<SEARCH>, literal_plus: bool) -> Result<Vec<u32>> {
+        self.search_response(filter, literal_plus).map(|response| response.uids)
+    }
 
-                Ok(uids)
-            },
+    fn search_response(&mut self, filter: Vec<SEARCH>, literal_plus: bool) -> Result<SearchResponse> {
+        // Non-ASCII strings need `CHARSET UTF-8`, and are send as non-synchronizing literals (RFC 7888),
+        // because quoted strings are only allowed to contain 7-bit text. Synchronizing literals would need
+        // to wait for a continuation in the middle of the command, which the session can not do.
+        let utf8 = filter.iter().any(|s| s.has_non_ascii());
+        if utf8 && !literal_plus {
+            return Err(fatal("The search contains non-ASCII text, which needs the LITERAL+ capability (RFC 7888) the server lacks".to_string()));
+        }
+
+        let criteria: String = filter.iter().map(|s| format!("{} ", search2str(s, literal_plus))).collect();
+        let command = if utf8 {
+            format!("UID SEARCH CHARSET UTF-8 {}", criteria.trim())
+        } else {
+            format!("UID SEARCH {}", criteria.trim())
+        };
+
+        match self.run_command_and_read_response(&command) {
+            Ok(response) => Ok(parse_search_response(&String::from_utf8_lossy(&response))),
             Err(e) => Err(e)
         }
     }
 }
 
-fn search2str<'a>(s: &SEARCH) -> &'a str{
+/// Parses the untagged `* SEARCH` and `* ESEARCH` lines of a response. Everything else,
+/// like the tagged `OK SEARCH completed (0.001 + 0.000 secs)`, is ignored.
+pub fn parse_search_response(response: &str) -> SearchResponse {
+    let mut r = SearchResponse::default();
+
+    for line in response.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 2 || words[0] != "*" {
+            continue;
+        }
+
+        match words[1].to_uppercase().as_ref() {
+            "SEARCH" => parse_search_line(&line[line.to_uppercase().find("SEARCH").unwrap() + 6..], &mut r),
+            "ESEARCH" => parse_esearch_line(&line[line.to_uppercase().find("ESEARCH").unwrap() + 7..], &mut r),
+            _ => (),
+        }
+    }
+
+    r
+}
+
+/// `* SEARCH 2 84 882 (MODSEQ 917162500)`
+fn parse_search_line(data: &str, r: &mut SearchResponse) {
+    let (numbers, trailer) = match data.find('(') {
+        Some(i) => (&data[..i], &data[i..]),
+        None => (data, ""),
+    };
+
+    for number in numbers.split_whitespace() {
+        if let Ok(uid) = u32::from_str(number) {
+            r.uids.push(uid);
+        }
+    }
+
+    let trailer: Vec<&str> = trailer.trim_matches(|c| c == '(' || c == ')' || c == ' ').split_whitespace().collect();
+    if trailer.len() == 2 && trailer[0].eq_ignore_ascii_case("MODSEQ") {
+        r.modseq = u64::from_str(trailer[1]).ok();
+    }
+}
+
+/// `* ESEARCH (TAG "a567") UID ALL 2,10:11 MIN 2 MAX 11 COUNT 3 MODSEQ 917162500`
+fn parse_esearch_line(data: &str, r: &mut SearchResponse) {
+    let mut data = data.trim();
+
+    // The search correlator
+    if data.starts_with('(') {
+        data = match data.find(')') {
+            Some(i) => data[i + 1..].trim(),
+            None => return,
+        };
+    }
+
+    let words: Vec<&str> = data.split_whitespace().collect();
+    let mut i = 0;
+    while i < words.len() {
+        let value = words.get(i + 1).cloned().unwrap_or("");
+        match words[i].to_uppercase().as_ref() {
+            "UID" => {
+                i += 1;
+                continue;
+            },
+            "ALL" => r.uids.extend(expand_sequence_set(value)),
+            "MIN" => r.min = u32::from_str(value).ok(),
+            "MAX" => r.max = u32::from_str(value).ok(),
+            "COUNT" => r.count = u32::from_str(value).ok(),
+            "MODSEQ" => r.modseq = u64::from_str(value).ok(),
+            _ => (),
+        }
+        i += 2;
+    }
+}
+
+/// Expands a sequence set without `*`, e.g. `2,10:12` becomes `[2, 10, 11, 12]`.
+fn expand_sequence_set(set: &str) -> Vec<u32> {
+    let mut r: Vec<u32> = Vec::new();
+
+    for range in set.split(',') {
+        let mut ends = range.splitn(2, ':');
+        let start = ends.next().and_then(|s| u32::from_str(s).ok());
+        let end = ends.next().and_then(|s| u32::from_str(s).ok());
+
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                let (low, high) = if start <= end { (start, end) } else { (end, start) };
+                r.extend(low..=high);
+            },
+            (Some(start), None) => r.push(start),
+            _ => (),
+        }
+    }
+
+    r
+}
+
+fn search2str(s: &SEARCH, literal_plus: bool) -> String {
+    let q = |s: &str| string2imap(s, literal_plus);
+
     match s {
-        &SEARCH::SEEN => "SEEN",
-        &SEARCH::UNSEEN => "UNSEEN"
+        &SEARCH::ALL => "ALL".to_string(),
+        &SEARCH::ANSWERED => "ANSWERED".to_string(),
+        &SEARCH::UNANSWERED => "UNANSWERED".to_string(),
+        &SEARCH::DELETED => "DELETED".to_string(),
+        &SEARCH::UNDELETED => "UNDELETED".to_string(),
+        &SEARCH::DRAFT => "DRAFT".to_string(),
+        &SEARCH::UNDRAFT => "UNDRAFT".to_string(),
+        &SEARCH::FLAGGED => "FLAGGED".to_string(),
+        &SEARCH::UNFLAGGED => "UNFLAGGED".to_string(),
+        &SEARCH::NEW => "NEW".to_string(),
+        &SEARCH::OLD => "OLD".to_string(),
+        &SEARCH::RECENT => "RECENT".to_string(),
+        &SEARCH::SEEN => "SEEN".to_string(),
+        &SEARCH::UNSEEN => "UNSEEN".to_string(),
+        &SEARCH::FROM(ref s) => format!("FROM {}", q(s)),
+        &SEARCH::TO(ref s) => format!("TO {}", q(s)),
+        &SEARCH::CC(ref s) => format!("CC {}", q(s)),
+        &SEARCH::BCC(ref s) => format!("BCC {}", q(s)),
+        &SEARCH::SUBJECT(ref s) => format!("SUBJECT {}", q(s)),
+        &SEARCH::BODY(ref s) => format!("BODY {}", q(s)),
+        &SEARCH::TEXT(ref s) => format!("TEXT {}", q(s)),
+        &SEARCH::HEADER(ref name, ref value) => format!("HEADER {} {}", q(name), q(value)),
+        &SEARCH::SINCE(ref d) => format!("SINCE {}", date2imap(d)),
+        &SEARCH::BEFORE(ref d) => format!("BEFORE {}", date2imap(d)),
+        &SEARCH::ON(ref d) => format!("ON {}", date2imap(d)),
+        &SEARCH::SENTSINCE(ref d) => format!("SENTSINCE {}", date2imap(d)),
+        &SEARCH::SENTBEFORE(ref d) => format!("SENTBEFORE {}", date2imap(d)),
+        &SEARCH::SENTON(ref d) => format!("SENTON {}", date2imap(d)),
+        &SEARCH::LARGER(n) => format!("LARGER {}", n),
+        &SEARCH::SMALLER(n) => format!("SMALLER {}", n),
+        &SEARCH::KEYWORD(ref k) => format!("KEYWORD {}", k),
+        &SEARCH::UNKEYWORD(ref k) => format!("UNKEYWORD {}", k),
+        &SEARCH::UID(ref set) => format!("UID {}", set),
+        &SEARCH::MODSEQ(n) => format!("MODSEQ {}", n),
+        &SEARCH::OR(ref a, ref b) => format!("OR {} {}", search2str(a, literal_plus), search2str(b, literal_plus)),
+        &SEARCH::NOT(ref a) => format!("NOT {}", search2str(a, literal_plus)),
+        &SEARCH::AND(ref keys) => {
+            let keys: Vec<String> = keys.iter().map(|k| search2str(k, literal_plus)).collect();
+            format!("({})", keys.join(" "))
+        },
     }
-}
\ No newline at end of file
+}
+
+/// ASCII strings are quoted, everything else is send as a non-synchronizing literal.
+fn string2imap(s: &str, literal_plus: bool) -> String {
+    if literal_plus && !s.is_ascii() {
+        format!("{{{}+}}\r\n{}", s.len(), s)
+    } else {
+        format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+    }
+}
+
+static MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Converts `2018-02-01` to the IMAP date `1-Feb-2018`. Anything else is expected to be an IMAP date already.
+fn date2imap(date: &str) -> String {
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() == 3 && parts[0].len() == 4 {
+        if let (Ok(year), Ok(month), Ok(day)) = (u32::from_str(parts[0]), usize::from_str(parts[1]), u32::from_str(parts[2])) {
+            if month >= 1 && month <= 12 {
+                return format!("{}-{}-{}", day, MONTHS[month - 1], year);
+            }
+        }
+    }
+    date.to_string()
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Atom(String),
+    Quoted(String),
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> ::std::result::Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' => { chars.next(); },
+            '(' => { chars.next(); tokens.push(Token::Open); },
+            ')' => { chars.next(); tokens.push(Token::Close); },
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => s.push(c),
+                            None => return Err("the search expression ends inside a quoted string".to_string()),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err("the search expression ends inside a quoted string".to_string()),
+                    }
+                }
+                tokens.push(Token::Quoted(s));
+            },
+            _ => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' || c == '\t' || c == '\n' || c == '\r' || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(s));
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_key(tokens: &Vec<Token>, pos: &mut usize) -> ::std::result::Result<SEARCH, String> {
+    let token = match tokens.get(*pos) {
+        Some(token) => token.clone(),
+        None => return Err("the search expression ends too early".to_string()),
+    };
+    *pos += 1;
+
+    let key = match token {
+        Token::Open => {
+            let mut keys: Vec<SEARCH> = Vec::new();
+            loop {
+                match tokens.get(*pos) {
+                    Some(&Token::Close) => {
+                        *pos += 1;
+                        break;
+                    },
+                    Some(_) => keys.push(parse_key(tokens, pos)?),
+                    None => return Err("missing ')' in the search expression".to_string()),
+                }
+            }
+            return Ok(SEARCH::AND(keys));
+        },
+        Token::Close => return Err("unexpected ')' in the search expression".to_string()),
+        Token::Quoted(s) => return Err(format!("expected a search key, but found the string \"{}\"", s)),
+        Token::Atom(key) => key,
+    };
+
+    let search = match key.to_uppercase().as_ref() {
+        "ALL" => SEARCH::ALL,
+        "ANSWERED" => SEARCH::ANSWERED,
+        "UNANSWERED" => SEARCH::UNANSWERED,
+        "DELETED" => SEARCH::DELETED,
+        "UNDELETED" => SEARCH::UNDELETED,
+        "DRAFT" => SEARCH::DRAFT,
+        "UNDRAFT" => SEARCH::UNDRAFT,
+        "FLAGGED" => SEARCH::FLAGGED,
+        "UNFLAGGED" => SEARCH::UNFLAGGED,
+        "NEW" => SEARCH::NEW,
+        "OLD" => SEARCH::OLD,
+        "RECENT" => SEARCH::RECENT,
+        "SEEN" => SEARCH::SEEN,
+        "UNSEEN" => SEARCH::UNSEEN,
+        "FROM" => SEARCH::FROM(parse_string(tokens, pos, &key)?),
+        "TO" => SEARCH::TO(parse_string(tokens, pos, &key)?),
+        "CC" => SEARCH::CC(parse_string(tokens, pos, &key)?),
+        "BCC" => SEARCH::BCC(parse_string(tokens, pos, &key)?),
+        "SUBJECT" => SEARCH::SUBJECT(parse_string(tokens, pos, &key)?),
+        "BODY" => SEARCH::BODY(parse_string(tokens, pos, &key)?),
+        "TEXT" => SEARCH::TEXT(parse_string(tokens, pos, &key)?),
+        "HEADER" => {
+            let name = parse_string(tokens, pos, &key)?;
+            SEARCH::HEADER(name, parse_string(tokens, pos, &key)?)
+        },
+        "SINCE" => SEARCH::SINCE(parse_date(tokens, pos, &key)?),
+        "BEFORE" => SEARCH::BEFORE(parse_date(tokens, pos, &key)?),
+        "ON" => SEARCH::ON(parse_date(tokens, pos, &key)?),
+        "SENTSINCE" => SEARCH::SENTSINCE(parse_date(tokens, pos, &key)?),
+        "SENTBEFORE" => SEARCH::SENTBEFORE(parse_date(tokens, pos, &key)?),
+        "SENTON" => SEARCH::SENTON(parse_date(tokens, pos, &key)?),
+        "LARGER" => SEARCH::LARGER(parse_number(tokens, pos, &key)?),
+        "SMALLER" => SEARCH::SMALLER(parse_number(tokens, pos, &key)?),
+        "KEYWORD" => SEARCH::KEYWORD(parse_atom(tokens, pos, &key)?),
+        "UNKEYWORD" => SEARCH::UNKEYWORD(parse_atom(tokens, pos, &key)?),
+        "UID" => SEARCH::UID(parse_sequence_set(tokens, pos, &key)?),
+        "MODSEQ" => {
+            let s = parse_string(tokens, pos, &key)?;
+            SEARCH::MODSEQ(u64::from_str(&s).map_err(|_| format!("{} has to be followed by a number, not '{}'", key, s))?)
+        },
+        "OR" => {
+            let a = parse_key(tokens, pos)?;
+            SEARCH::OR(Box::new(a), Box::new(parse_key(tokens, pos)?))
+        },
+        "NOT" => SEARCH::NOT(Box::new(parse_key(tokens, pos)?)),
+        _ => return Err(format!("unknown search key '{}'", key)),
+    };
+
+    Ok(search)
+}
+
+fn parse_string(tokens: &Vec<Token>, pos: &mut usize, key: &str) -> ::std::result::Result<String, String> {
+    let s = match tokens.get(*pos) {
+        Some(&Token::Quoted(ref s)) | Some(&Token::Atom(ref s)) => s.clone(),
+        _ => return Err(format!("{} has to be followed by a string", key)),
+    };
+    *pos += 1;
+    Ok(s)
+}
+
+fn parse_atom(tokens: &Vec<Token>, pos: &mut usize, key: &str) -> ::std::result::Result<String, String> {
+    let s = match tokens.get(*pos) {
+        Some(&Token::Atom(ref s)) => s.clone(),
+        _ => return Err(format!("{} has to be followed by a keyword, e.g. $Slacked", key)),
+    };
+    if !is_keyword(&s) {
+        return Err(format!("'{}' is not a valid keyword", s));
+    }
+    *pos += 1;
+    Ok(s)
+}
+
+fn parse_date(tokens: &Vec<Token>, pos: &mut usize, key: &str) -> ::std::result::Result<String, String> {
+    let s = parse_string(tokens, pos, key)?;
+    if !Regex::new(r"^(\d{4}-\d{1,2}-\d{1,2}|\d{1,2}-[A-Za-z]{3}-\d{4})$").unwrap().is_match(&s) {
+        return Err(format!("{} has to be followed by a date like 2018-02-01 or 1-Feb-2018, not '{}'", key, s));
+    }
+    Ok(s)
+}
+
+fn parse_number(tokens: &Vec<Token>, pos: &mut usize, key: &str) -> ::std::result::Result<u32, String> {
+    let s = parse_string(tokens, pos, key)?;
+    u32::from_str(&s).map_err(|_| format!("{} has to be followed by a number, not '{}'", key, s))
+}
+
+fn parse_sequence_set(tokens: &Vec<Token>, pos: &mut usize, key: &str) -> ::std::result::Result<String, String> {
+    let s = match tokens.get(*pos) {
+        Some(&Token::Atom(ref s)) => s.clone(),
+        _ => return Err(format!("{} has to be followed by a set of UIDs, e.g. 1:100,200", key)),
+    };
+    *pos += 1;
+    if !Regex::new(r"^(\d+|\*)(:(\d+|\*))?(,(\d+|\*)(:(\d+|\*))?)*$").unwrap().is_match(&s) {
+        return Err(format!("'{}' is not a valid set of UIDs", s));
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_search_response_of_dovecot() {
+        let response = "* SEARCH 2 84 882\r\n\
+                        a2 OK Search completed (0.001 + 0.000 secs).\r\n";
+        assert_eq!(parse_search_response(response).uids, vec![2, 84, 882]);
+    }
+
+    #[test]
+    fn parses_empty_search_response() {
+        let response = "* SEARCH\r\n\
+                        a2 OK Search completed (0.001 + 0.000 secs).\r\n";
+        assert_eq!(parse_search_response(response), SearchResponse::default());
+    }
+
+    #[test]
+    fn parses_search_response_of_gmail() {
+        // Gmail ends the SEARCH line with a space
+        let response = "* SEARCH 1234 1240 \r\n\
+                        A3 OK SEARCH completed (Success)\r\n";
+        assert_eq!(parse_search_response(response).uids, vec![1234, 1240]);
+    }
+
+    #[test]
+    fn parses_search_response_of_exchange() {
+        let response = "* SEARCH 31 32\r\n\
+                        A4 OK SEARCH completed.\r\n";
+        assert_eq!(parse_search_response(response).uids, vec![31, 32]);
+    }
+
+    #[test]
+    fn ignores_other_untagged_responses() {
+        let response = "* 3 EXISTS\r\n\
+                        * 2 FETCH (UID 84 FLAGS (\\Seen))\r\n\
+                        * search 5 7\r\n\
+                        a2 OK Search completed (0.001 + 0.000 secs).\r\n";
+        assert_eq!(parse_search_response(response).uids, vec![5, 7]);
+    }
+
+    #[test]
+    fn parses_modseq_of_search_response() {
+        let response = "* SEARCH 2 5 6 7 11 12 18 19 20 23 (MODSEQ 917162500)\r\n\
+                        a OK Search complete\r\n";
+        let r = parse_search_response(response);
+        assert_eq!(r.uids, vec![2, 5, 6, 7, 11, 12, 18, 19, 20, 23]);
+        assert_eq!(r.modseq, Some(917162500));
+    }
+
+    #[test]
+    fn parses_esearch_response_of_cyrus() {
+        let response = "* ESEARCH (TAG \"A283\") UID ALL 2,10:11 MIN 2 MAX 11 COUNT 3\r\n\
+                        A283 OK Completed (3 msgs in 0.000 secs)\r\n";
+        assert_eq!(parse_search_response(response), SearchResponse {
+            uids: vec![2, 10, 11],
+            min: Some(2),
+            max: Some(11),
+            count: Some(3),
+            modseq: None,
+        });
+    }
+
+    #[test]
+    fn parses_empty_esearch_response() {
+        let response = "* ESEARCH (TAG \"A284\") UID\r\n\
+                        A284 OK Completed (0 msgs in 0.000 secs)\r\n";
+        assert_eq!(parse_search_response(response), SearchResponse::default());
+    }
+
+    #[test]
+    fn parses_modseq_of_esearch_response() {
+        let response = "* ESEARCH (TAG \"a\") UID ALL 1:3,5 MODSEQ 1236\r\n\
+                        a OK Extended SEARCH completed\r\n";
+        let r = parse_search_response(response);
+        assert_eq!(r.uids, vec![1, 2, 3, 5]);
+        assert_eq!(r.modseq, Some(1236));
+    }
+
+    #[test]
+    fn expands_sequence_set() {
+        assert_eq!(expand_sequence_set("2,10:12"), vec![2, 10, 11, 12]);
+        assert_eq!(expand_sequence_set("12:10"), vec![10, 11, 12]);
+        assert_eq!(expand_sequence_set(""), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn finds_searches_depending_on_flags() {
+        let depends = |search: &str| SEARCH::parse(search).unwrap().iter().any(|s| s.depends_on_flags(Some("$Slacked")));
+        assert!(!depends("UNSEEN"));
+        assert!(!depends("ALL UNKEYWORD $slacked"));
+        assert!(!depends("NOT SEEN FROM \"list@example.com\""));
+        assert!(depends("FLAGGED"));
+        assert!(depends("UNSEEN OR FROM \"a@example.com\" (KEYWORD $Important)"));
+        assert!(depends("NOT UNKEYWORD $Done"));
+    }
+
+    #[test]
+    fn parses_modseq_search_key() {
+        assert_eq!(SEARCH::parse("UNSEEN MODSEQ 620162338"), Ok(vec![SEARCH::UNSEEN, SEARCH::MODSEQ(620162338)]));
+        assert!(SEARCH::parse("MODSEQ x").is_err());
+        assert_eq!(search2str(&SEARCH::MODSEQ(620162338), false), "MODSEQ 620162338");
+    }
+}
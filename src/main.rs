@@ -10,154 +10,622 @@ extern crate toml;
 extern crate serde_derive;
 
 
-use std::thread::sleep;
+use std::thread::{self, sleep};
 use std::time::Duration;
+use std::io::{Read, Write};
 
-use native_tls::TlsConnector;
-use imap::client;
-use imap::error::Error;
+use imap::client::Session;
+use imap::error::Result;
 
 mod imap_extention;
+use imap_extention::action::MailAction;
+use imap_extention::capability::Capability;
+use imap_extention::flag::is_keyword;
+use imap_extention::folder::{Folder as ListFolder, has_wildcard};
 use imap_extention::search::*;
 use imap_extention::fetch::*;
 use imap_extention::path::{Path, PathFrom};
 
+mod oauth2;
+
+mod html;
+
+mod proxy;
+
+mod state;
+use state::{check_uid_validity, highest_modseq, is_posted, is_uid_posted, set_highest_modseq, set_posted, set_status, status_changed};
+
+mod connection;
+use connection::{connect, is_transient, supervise, Backoff};
+
 mod config;
 use config::DEFAULT;
+use config::default::{AttachmentPolicy, Attachments, Mail, PostAction, Publish};
 
 mod slack;
-use slack::post_mails;
+use slack::{post_mails, File};
 
-// To connect to the gmail IMAP server with this you will need to allow unsecure apps access.
+// To connect to the gmail IMAP server with `auth = "login"` you will need to allow unsecure apps access.
 // See: https://support.google.com/accounts/answer/6010255?hl=en
-// Look at the gmail_oauth2.rs example on how to connect to a gmail server securely.
+// Otherwise use `auth = "xoauth2"` together with the oauth2 section of the account.
 fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() > 1 {
+        command(&args[1..]);
+        return;
+    }
+
+    for proxy in DEFAULT.proxies() {
+        proxy.address();
+    }
+
+    if let Some(keyword) = DEFAULT.keyword() {
+        if !is_keyword(keyword) {
+            println!("The keyword '{}' is not valid, it can not contain spaces or any of (){{%*\"\\]", keyword);
+            ::std::process::exit(1);
+        }
+    }
+
     for publish in &DEFAULT.publish {
         &publish.filter();
+        &publish.search();
+
+        if DEFAULT.account(publish.account()).is_none() {
+            println!("The account '{}' used by the mailbox '{}' does not exist in the [mail] section", publish.account(), &publish.mailbox);
+            ::std::process::exit(1);
+        }
+
+        if publish.attachment_policy() == AttachmentPolicy::Upload && DEFAULT.slack.token.is_none() {
+            println!("The mailbox '{}' uploads attachments, which needs a `token` in the [slack] section", &publish.mailbox);
+            ::std::process::exit(1);
+        }
+    }
+
+    // Every account runs in its own thread(s), so a failing account does not stop the others
+    let mut threads = Vec::new();
+    for (account, mail) in DEFAULT.accounts() {
+        threads.push(thread::spawn(move || run_account(account, mail)));
+    }
+
+    let mut failed = false;
+    for thread in threads {
+        failed = !thread.join().unwrap_or(false) || failed;
+    }
+
+    if failed {
+        ::std::process::exit(1);
+    }
+}
+
+/// Commands from the command line, instead of running the bridge.
+fn command(args: &[String]) {
+    match (args.get(0).map(|a| a.as_str()), args.get(1).map(|a| a.as_str()), args.get(2)) {
+        (Some("state"), Some("reset"), Some(mailbox)) => {
+            let account = args.get(3).map_or(config::default::DEFAULT_ACCOUNT, |a| a.as_str());
+            match state::reset(account, mailbox) {
+                Ok(count) => println!("Forgot {} posted mail(s) from the mailbox '{}' of the account '{}'", count, mailbox, account),
+                Err(e) => {
+                    println!("{}", e);
+                    ::std::process::exit(1);
+                },
+            }
+        },
+        _ => {
+            println!("Usage:");
+            println!("  imap2slack                                     run the bridge");
+            println!("  imap2slack state reset <mailbox> [<account>]   forget which mails from the mailbox have been posted");
+            ::std::process::exit(1);
+        },
+    }
+}
+
+fn run_account(account: &'static str, mail: &'static Mail) -> bool {
+    let publish: Vec<&'static Publish> = DEFAULT.publish.iter().filter(|p| p.account() == account).collect();
+    if publish.is_empty() {
+        return true;
+    }
+
+    if DEFAULT.service && DEFAULT.idle() {
+        let mut idle = false;
+        let ok = supervise(account, |_| {
+            let mut session = connect(account, mail)?;
+            idle = match session.has_capability("IDLE") {
+                Ok(idle) => idle,
+                Err(e) => {
+                    println!("{}: failed to read the capabilities of the server: {}", account, e);
+                    false
+                }
+            };
+            session.logout()
+        });
+
+        if !ok {
+            return false;
+        }
+
+        if idle {
+            return idle_loop(account, mail, publish);
+        }
+
+        println!("{}: the server does not support IDLE, falling back to polling every {} minutes", account, DEFAULT.sleep_time);
+    }
+
+    poll_loop(account, mail, publish)
+}
+
+/// Logs in, checks every mailbox of the account ones, logs out and sleeps `sleep_time` minutes.
+fn poll_loop(account: &str, mail: &Mail, publish: Vec<&Publish>) -> bool {
+    supervise(account, |backoff| {
+        loop {
+            let mut session = connect(account, mail)?;
+            backoff.reset();
+            let capabilities = read_capabilities(&mut session, account)?;
+            let condstore = enable_condstore(&mut session, account, &capabilities)?;
+            let delimiter = read_delimiter(&mut session)?;
+
+            for publish in &publish {
+                check_mailbox(&mut session, account, publish, condstore, &capabilities, delimiter.as_ref().map(|d| d.as_str()))?;
+            }
+
+            session.logout()?;
+
+            if DEFAULT.service {
+                sleep(Duration::new(DEFAULT.sleep_time * 60, 0));
+            } else {
+                return Ok(());
+            }
+        }
+    })
+}
+
+/// Starts a thread with its own long-lived session for every mailbox of the account.
+/// IDLE only watches a single mailbox, so mailboxes with wildcards are polled in a thread of their own.
+fn idle_loop(account: &'static str, mail: &'static Mail, publish: Vec<&'static Publish>) -> bool {
+    let mut threads = Vec::new();
+
+    let (wildcards, publish): (Vec<&'static Publish>, Vec<&'static Publish>) = publish.into_iter().partition(|p| has_wildcard(&p.mailbox));
+    if !wildcards.is_empty() {
+        threads.push(thread::spawn(move || poll_loop(account, mail, wildcards)));
     }
 
+    for publish in publish {
+        let name = format!("{}/{}", account, &publish.mailbox);
+        threads.push(thread::spawn(move || supervise(&name, |backoff| idle_mailbox(account, mail, publish, backoff))));
+    }
+
+    let mut ok = true;
+    for thread in threads {
+        ok = thread.join().unwrap_or(false) && ok;
+    }
+    ok
+}
 
-    let domain: &str = &DEFAULT.mail.imap;
-    let port: u16 = DEFAULT.mail.port.clone();
-    let socket_addr = (domain, port);
+/// Keeps a session open on the mailbox and checks it again every time the server
+/// sends an untagged response (EXISTS/RECENT) while idling.
+fn idle_mailbox(account: &str, mail: &Mail, publish: &Publish, backoff: &mut Backoff) -> Result<()> {
+    let mut session = connect(account, mail)?;
+    backoff.reset();
+    let capabilities = read_capabilities(&mut session, account)?;
+    let condstore = enable_condstore(&mut session, account, &capabilities)?;
+    let delimiter = read_delimiter(&mut session)?;
+    let path = Path::new(&publish.mailbox).with_delimiter(delimiter.as_ref().map(|d| d.as_str()));
+    let has_unselect = capabilities.iter().any(|c| c == "UNSELECT");
 
     loop {
-        let ssl_connector = TlsConnector::builder().build().unwrap();
-        let mut imap_socket: client::Client<native_tls::TlsStream<std::net::TcpStream>>; // = Client::secure_connect(socket_addr, domain, ssl_connector).unwrap();
-        match client::secure_connect(socket_addr, domain, &ssl_connector) {
-            Ok(mut sock) => {
-                sock.debug = DEFAULT.debug_imap();
-                imap_socket = sock
+        check_mailbox(&mut session, account, publish, condstore, &capabilities, delimiter.as_ref().map(|d| d.as_str()))?;
+
+        if DEFAULT.debug() {
+            println!("---===( Idle - {}/{} )===---", account, &publish.mailbox);
+        }
+
+        // `check_mailbox` leaves the mailbox, so it can start with STATUS, but IDLE needs it selected
+        session.select_from(&path)?;
+        {
+            let mut idle = session.idle()?;
+            idle.wait_keepalive()?;
+        }
+        session.unselect(&path, has_unselect)?;
+    }
+}
+
+/// Enables CONDSTORE (RFC 7162) if the server supports it, so only mails changed since the last check are searched.
+/// QRESYNC implies CONDSTORE, but is not enabled itself: it replaces EXPUNGE with VANISHED responses, which the
+/// imap crate does not parse, and nothing here needs to know about expunged mails.
+fn enable_condstore<T: Read + Write>(session: &mut Session<T>, account: &str, capabilities: &[String]) -> Result<bool> {
+    if !capabilities.iter().any(|c| c == "CONDSTORE" || c == "QRESYNC") {
+        return Ok(false);
+    }
+
+    // Without ENABLE, CONDSTORE is enabled by the first command using it (RFC 7162, section 3.1)
+    if capabilities.iter().any(|c| c == "ENABLE") {
+        match session.enable("CONDSTORE") {
+            Ok(true) => (),
+            Ok(false) => {
+                println!("{}: the server did not enable CONDSTORE", account);
+                return Ok(false);
             },
             Err(e) => {
-                match e {
-                    // An `io::Error` that occurred while trying to read or write to a network stream.
-                    Error::Io(io_error) => {
-                        println!("{:?}", io_error);
-                        ::std::process::exit(1);
-                    },
-                    // An error from the `native_tls` library during the TLS handshake.
-                    Error::TlsHandshake(tls_handshake_error) => {
-                        println!("{:?}", tls_handshake_error);
-                        ::std::process::exit(1);
-                    },
-                    // An error from the `native_tls` library while managing the socket.
-                    Error::Tls(tls_error) => {
-                        println!("{:?}", tls_error);
-                        ::std::process::exit(1);
-                    },
-                    // A BAD response from the IMAP server.
-                    Error::BadResponse(response) => {
-                        println!("{:?}", response);
-                        ::std::process::exit(1);
-                    },
-                    // A NO response from the IMAP server.
-                    Error::NoResponse(response) => {
-                        println!("{:?}", response);
-                        ::std::process::exit(1);
-                    },
-                    // The connection was terminated unexpectedly.
-                    Error::ConnectionLost => {
-                        println!("Connection to the server has been lost");
-                        ::std::process::exit(1);
-                    },
-                    // Error parsing a server response.
-                    Error::Parse(parse_error) => {
-                        println!("{:?}", parse_error);
-                        ::std::process::exit(1);
-                    },
-                    // Error validating input data
-                    Error::Validate(ValidateError) => {
-                        println!("{:?}", ValidateError);
-                        ::std::process::exit(1);
-                    }
-                    // Error appending a mail
-                    Error::Append => {
-                        println!("Error appending a mail");
-                        ::std::process::exit(1);
-                    },
+                if is_transient(&e) {
+                    return Err(e);
                 }
+                println!("{}: failed to enable CONDSTORE: {}", account, e);
+                return Ok(false);
+            },
+        }
+    }
+
+    if DEFAULT.debug() {
+        println!("{}: CONDSTORE enabled", account);
+    }
+
+    Ok(true)
+}
+
+/// Reads the capabilities once per session, after the login, because they may change with it.
+/// If they cannot be read, the session goes on without any of them.
+fn read_capabilities<T: Read + Write>(session: &mut Session<T>, account: &str) -> Result<Vec<String>> {
+    match session.capability_list() {
+        Ok(capabilities) => Ok(capabilities),
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
             }
-        };
+            println!("{}: failed to read the capabilities of the server: {}", account, e);
+            Ok(Vec::new())
+        },
+    }
+}
 
-        let mut session = imap_socket.login(&DEFAULT.mail.username, &DEFAULT.mail.password).unwrap();
+/// Reads the hierarchy delimiter once per session, it is the same for every mailbox of the server.
+fn read_delimiter<T: Read + Write>(session: &mut Session<T>) -> Result<Option<String>> {
+    match session.hierarchy_delimiter() {
+        Ok(delimiter) => Ok(delimiter),
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
+            }
+            println!("Failed to find the hierarchy delimiter of the server, using '/': {}", e);
+            Ok(None)
+        },
+    }
+}
 
-        for publish in &DEFAULT.publish {
-            let path = Path::new(&publish.mailbox);
-            let mut uids: Vec<usize> = Vec::new();
+/// Checks the mailbox of `publish` for new mail. If the mailbox contains wildcards,
+/// every matching mailbox on the server is checked, so new mailboxes are picked up automatically.
+fn check_mailbox<T: Read + Write>(session: &mut Session<T>, account: &str, publish: &Publish, condstore: bool, capabilities: &[String], delimiter: Option<&str>) -> Result<()> {
+    let path = Path::new(&publish.mailbox).with_delimiter(delimiter);
 
-            println!("--- mailbox - {} ---", &path.as_str());
-            match session.select_from(&path) {
-//                Ok(mailbox) => println!("Selected mailbox - '{}'", mailbox),
-                Ok(mailbox) => (),
-                Err(e) => println!("Error selecting INBOX: {}", e),
-            };
+    if !has_wildcard(&publish.mailbox) {
+        return check_folder(session, account, publish, path, condstore, capabilities);
+    }
+
+    let folders = match session.list_folder_info("", &path.encoded(), publish.subscribed_only()) {
+        Ok(folders) => folders,
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
+            }
+            println!("Failed to list the mailboxes matching '{}': {}", &publish.mailbox, e);
+            return Ok(());
+        },
+    };
+
+    if DEFAULT.debug() {
+        println!("---===( List - {} )===---\n{:?}", &publish.mailbox, &folders);
+    }
+
+    for folder in folders.iter().filter(|f| f.is_selectable()) {
+        check_folder(session, account, publish, Path::from_server(&folder.name, folder.delimiter.as_ref().map(|d| d.as_str())), condstore, capabilities)?;
+    }
+
+    Ok(())
+}
+
+/// Checks a single mailbox for new mail. Errors concerning only this mailbox are printed
+/// and skipped, while errors that indicate a broken connection are returned.
+///
+/// No mailbox may be selected when it is called, and the mailbox is left again at the end,
+/// because STATUS must not be used on the selected mailbox (RFC 3501, section 6.3.10).
+///
+/// With `condstore` only the mails changed since the HIGHESTMODSEQ of the last complete check are searched.
+fn check_folder<T: Read + Write>(session: &mut Session<T>, account: &str, publish: &Publish, path: Path, condstore: bool, capabilities: &[String]) -> Result<()> {
+    let mut uids: Vec<u32> = Vec::new();
+    let mut uid_validity: u32 = 0;
+    let mut complete = true;
+    let mut keyword = DEFAULT.keyword();
+
+    // STATUS is much cheaper than SELECT and SEARCH, so skip the mailbox if nothing changed since the last check
+    let status = match path.status(session, condstore) {
+        Ok(status) => Some(status),
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
+            }
+            println!("Failed to get the status of the mailbox '{}': {}", path.as_str(), e);
+            None
+        },
+    };
+
+    if let Some(ref status) = status {
+        let flags = publish.search().iter().any(|s| s.depends_on_flags(keyword));
+        if !status_changed(account, path.as_str(), status, flags) {
+            if DEFAULT.debug() {
+                println!("--- mailbox - {} (unchanged) ---", &path.as_str());
+            }
+            return Ok(());
+        }
+    }
 
-            match session.search2(vec![SEARCH::UNSEEN]) {
-                Ok(u) => {
-                    if DEFAULT.debug() {
-                        println!("---===( Search )===---\n{:?}", &u);
+    println!("--- mailbox - {} ---", &path.as_str());
+    match session.select_from(&path) {
+//        Ok(mailbox) => println!("Selected mailbox - '{}'", mailbox),
+        Ok(mailbox) => {
+            match mailbox.uid_validity {
+                Some(u) => {
+                    match check_uid_validity(account, path.as_str(), u) {
+                        Ok(Some(old)) => println!("{}: the UIDVALIDITY of the mailbox '{}' changed from {} to {}, the mailbox has been rebuilt", account, path.as_str(), old, u),
+                        Ok(None) => (),
+                        Err(e) => {
+                            println!("{}", e);
+                            complete = false;
+                        },
                     }
-                    uids = u
+                    uid_validity = u;
+                },
+                None => println!("The server did not send the UIDVALIDITY of the mailbox '{}'", path.as_str()),
+            }
+
+            // `\*` in PERMANENTFLAGS means new keywords can be created (RFC 3501, section 7.1).
+            // Without PERMANENTFLAGS every flag is permanent, an empty list is taken as missing.
+            if let Some(k) = keyword {
+                if !mailbox.permanent_flags.is_empty() && !mailbox.permanent_flags.iter().any(|f| f == "\\*" || f.eq_ignore_ascii_case(k)) {
+                    println!("The mailbox '{}' does not allow the keyword {}, only the state database is used to avoid posting mails twice", path.as_str(), k);
+                    keyword = None;
                 }
-                Err(e) => println!("Failed in searching for mail: {}", e),
-            };
+            }
+        },
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
+            }
+            println!("Error selecting the mailbox '{}': {}", path.as_str(), e);
+            return Ok(());
+        },
+    };
+
+    let mut search = publish.search();
+    if let Some(k) = keyword {
+        search.push(SEARCH::UNKEYWORD(k.to_string()));
+    }
+    // Every new mail and every mail whose flags changed gets a higher mod-sequence
+    if condstore {
+        match highest_modseq(account, path.as_str(), uid_validity) {
+            Ok(Some(modseq)) => search.push(SEARCH::MODSEQ(modseq + 1)),
+            Ok(None) => (),
+            Err(e) => {
+                println!("{}", e);
+                complete = false;
+            },
+        }
+    }
 
+    match session.search2(search, capabilities.iter().any(|c| c == "LITERAL+")) {
+        Ok(u) => {
             if DEFAULT.debug() {
-                println!("---===( Fetch )===---");
+                println!("---===( Search )===---\n{:?}", &u);
             }
-            let fetch = session.fetch_mail(&uids);
+            // No need to fetch the mails which have been posted to every channel already.
+            // If the state database fails, the mail is fetched and the error reported when it is checked before posting.
+            uids = u.into_iter()
+                .filter(|uid| !publish.channel.iter().all(|channel| is_uid_posted(account, path.as_str(), uid_validity, *uid, channel).unwrap_or(false)))
+                .collect();
+        }
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
+            }
+            println!("Failed in searching for mail: {}", e);
+            complete = false;
+        },
+    };
+
+    // Mails are posted in arrival order. Every batch is sorted on its own, so only more than one batch needs sorting first.
+    if uids.len() > DEFAULT.fetch_batch_size() {
+        match session.arrival_order(&uids) {
+            Ok(u) => uids = u,
+            Err(e) => {
+                if is_transient(&e) {
+                    return Err(e);
+                }
+                println!("Failed to sort the mails in arrival order: {}", e);
+            },
+        }
+    }
+
+    // Fetched in batches, so a mailbox full of mail does not have to fit in memory at ones
+    for batch in uids.chunks(DEFAULT.fetch_batch_size()) {
+        if DEFAULT.debug() {
+            println!("---===( Fetch )===---");
+        }
+        let fetch = session.fetch_mail(batch, publish.prefer_html());
+        if DEFAULT.debug() {
             println!("{:?}", &fetch);
-            match fetch {
-                Ok(mails) => {
-                    for mail in &mails {
-                        match &publish.filter() {
-                            &Some(filter) => {
-                                if filter.check(&mail.subject) {
-                                    post_mails(mail, &publish.channel);
-                                }
-                            },
-                            &None => {
-                                post_mails(mail, &publish.channel);
+        }
+        match fetch {
+            Ok(mails) => {
+                for mail in &mails {
+                    let post = match &publish.filter() {
+                        &Some(filter) => filter.check(&mail.field(filter.field())),
+                        &None => true,
+                    };
+
+                    let mut posted = post;
+                    // Downloaded when the mail is posted to the first channel, and reused for the rest
+                    let mut files: Option<Vec<File>> = None;
+                    if post {
+                        for channel in &publish.channel {
+                            match is_posted(account, path.as_str(), uid_validity, mail.uid, &mail.message_id, channel) {
+                                Ok(true) => {
+                                    println!("The mail {} has already been posted to {}", &mail.uid, channel);
+                                    continue;
+                                },
+                                Ok(false) => (),
+                                // Like a failed post, it might have been posted already
+                                Err(e) => {
+                                    println!("{}", e);
+                                    complete = false;
+                                    posted = false;
+                                    continue;
+                                },
+                            }
+
+                            if files.is_none() {
+                                files = Some(match &publish.attachments {
+                                    &Some(ref attachments) if attachments.policy == AttachmentPolicy::Upload => download_attachments(session, attachments, mail)?,
+                                    _ => Vec::new(),
+                                });
+                            }
+
+                            // A post which is not recorded is treated as failed, so the mail is not marked as done
+                            match post_mails(mail, &vec![channel.clone()], publish.attachments.as_ref(), files.as_ref().unwrap())
+                                .and_then(|()| set_posted(account, path.as_str(), uid_validity, mail.uid, &mail.message_id, channel)) {
+                                Ok(()) => (),
+                                Err(e) => {
+                                    println!("Failed to post the mail {} to {}: {}", &mail.uid, channel, e);
+                                    complete = false;
+                                    posted = false;
+                                },
                             }
                         }
+                    }
 
-                        if DEFAULT.mark_mail_as_seen() {
-                            println!("mark mail as see: {}", &mail.uid);
-                            session.store(&mail.uid.to_string(), r"+FLAGS \Seen");
+                    if DEFAULT.keyword().is_some() {
+                        // The keyword marks the mail as done, so it is not set when posting failed
+                        if let (Some(k), true) = (keyword, !post || posted) {
+                            if let Err(e) = session.add_keyword(mail.uid, k) {
+                                if is_transient(&e) {
+                                    return Err(e);
+                                }
+                                println!("Failed to add the keyword {} to the mail {}: {}", k, &mail.uid, e);
+                            }
+                        }
+                    } else if DEFAULT.mark_mail_as_seen() && !mail.has_flag("\\Seen") && (!post || posted) {
+                        // Like the keyword, so a mail which failed to post stays UNSEEN and is posted again
+                        println!("mark mail as see: {}", &mail.uid);
+                        if let Err(e) = session.uid_store(&mail.uid.to_string(), r"+FLAGS (\Seen)") {
+                            if is_transient(&e) {
+                                return Err(e);
+                            }
+                            println!("Failed to mark the mail {} as seen: {}", &mail.uid, e);
                         }
                     }
-                },
-                Err(e) => println!("Failed to fetch: {}", e),
+
+                    if posted {
+                        run_actions(session, publish, &path, mail.uid, capabilities)?;
+                    }
+                }
+            },
+            Err(e) => {
+                if is_transient(&e) {
+                    return Err(e);
+                }
+                println!("Failed to fetch: {}", e);
+                complete = false;
+            },
+        }
+    }
+
+    let unselected = match session.unselect(&path, capabilities.iter().any(|c| c == "UNSELECT")) {
+        Ok(()) => true,
+        Err(e) => {
+            if is_transient(&e) {
+                return Err(e);
             }
+            println!("Failed to leave the mailbox '{}': {}", path.as_str(), e);
+            false
+        },
+    };
+
+    // Only remember the status if everything went well, otherwise the mailbox is skipped until new mail arrives.
+    // Marking mails as seen changes UNSEEN, so the status has to be read again after that.
+    if complete {
+        // The HIGHESTMODSEQ from before the search, because mails could have arrived since then without being searched.
+        // Unless the mailbox was rebuilt in between.
+        if let Some(ref status) = status {
+            if let (Some(modseq), Some(u)) = (status.highest_modseq, status.uid_validity) {
+                if u == uid_validity {
+                    if let Err(e) = set_highest_modseq(account, path.as_str(), uid_validity, modseq) {
+                        println!("{}", e);
+                    }
+                }
+            }
+        }
+
+        let status = if uids.is_empty() { status } else if unselected { path.status(session, condstore).ok() } else { None };
+        if let Some(status) = status {
+            set_status(account, path.as_str(), status);
         }
+    }
 
-        session.logout().unwrap();
+    Ok(())
+}
 
-        if DEFAULT.service {
-            sleep(Duration::new(DEFAULT.sleep_time * 60, 0));
-        } else {
-            break;
+/// Downloads the attachments of a mail, which may be uploaded to Slack. An attachment which fails
+/// to download is printed and left out, so it is only listed in the post.
+fn download_attachments<T: Read + Write>(session: &mut Session<T>, attachments: &Attachments, mail: &imap_extention::fetch::Mail) -> Result<Vec<File>> {
+    let mut files: Vec<File> = Vec::new();
+
+    for part in mail.attachments.iter().filter(|part| attachments.allows(part)) {
+        match session.fetch_attachment(mail.uid, part) {
+            Ok(data) => files.push(File {
+                section: part.section.clone(),
+                name: part.name(),
+                mimetype: part.mimetype.clone(),
+                data: data,
+            }),
+            Err(e) => {
+                if is_transient(&e) {
+                    return Err(e);
+                }
+                println!("Failed to download the attachment '{}' of the mail {}: {}", part.name(), mail.uid, e);
+            },
         }
+    }
+
+    Ok(files)
+}
+
+/// Runs the `actions` of `publish` on a mail, which has been posted to every channel.
+/// A failed action is printed and the rest of the actions for the mail are skipped.
+fn run_actions<T: Read + Write>(session: &mut Session<T>, publish: &Publish, path: &Path, uid: u32, capabilities: &[String]) -> Result<()> {
+    let actions = match &publish.actions {
+        &Some(ref actions) if !actions.is_empty() => actions,
+        _ => return Ok(()),
     };
+
+    let has_move = capabilities.iter().any(|c| c == "MOVE");
+    let has_uidplus = capabilities.iter().any(|c| c == "UIDPLUS");
+
+    for action in actions {
+        if DEFAULT.debug() {
+            println!("---===( Action - {} - {:?} )===---", uid, action);
+        }
+
+        let result = match action {
+            &PostAction::Move { ref mailbox } => session.move_mail(uid, &Path::new(mailbox).with_delimiter(path.delimiter()), has_move, has_uidplus),
+            &PostAction::Copy { ref mailbox } => session.copy_mail(uid, &Path::new(mailbox).with_delimiter(path.delimiter())),
+            &PostAction::Keyword { ref keyword } => session.add_keyword(uid, keyword),
+            &PostAction::Delete => session.delete_mail(uid, has_uidplus),
+        };
+
+        if let Err(e) = result {
+            if is_transient(&e) {
+                return Err(e);
+            }
+            println!("Failed to {:?} the mail {} in '{}': {}", action, uid, path.as_str(), e);
+            break;
+        }
+    }
+
+    Ok(())
 }
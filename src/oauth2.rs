@@ -0,0 +1,178 @@
+extern crate reqwest;
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use imap::Authenticator;
+use imap::error::{Error, Result};
+use toml;
+
+use config::path_config_file;
+use config::default::{Auth, OAuth2};
+use connection::fatal;
+use proxy::http_client;
+
+/// Refresh the access token this many seconds before it expires,
+/// so that it does not run out between the refresh and the AUTHENTICATE command.
+static EXPIRY_MARGIN: u64 = 120;
+
+lazy_static! {
+    // Every IDLE thread authenticates on its own, but a token file should only be refreshed ones.
+    static ref TOKEN_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// The content of the token file. Only the `refresh_token` has to be filled in by hand,
+/// the rest is written by the program.
+#[derive(Deserialize,Serialize,Clone,Debug)]
+pub struct Token {
+    pub refresh_token: String,
+    pub access_token: Option<String>,
+    pub expires_at: Option<u64>, // Seconds since UNIX epoch
+}
+
+#[derive(Deserialize,Debug)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+}
+
+/// SASL XOAUTH2 / OAUTHBEARER initial response.
+pub struct OAuth2Authenticator {
+    auth: Auth,
+    user: String,
+    access_token: String,
+    responded: Cell<bool>,
+}
+
+impl OAuth2Authenticator {
+    pub fn new(auth: Auth, user: &str, access_token: String) -> OAuth2Authenticator {
+        OAuth2Authenticator {
+            auth: auth,
+            user: user.to_string(),
+            access_token: access_token,
+            responded: Cell::new(false),
+        }
+    }
+
+    pub fn mechanism(&self) -> &'static str {
+        match self.auth {
+            Auth::Oauthbearer => "OAUTHBEARER",
+            _ => "XOAUTH2",
+        }
+    }
+}
+
+impl Authenticator for OAuth2Authenticator {
+    type Response = String;
+
+    fn process(&self, _data: &[u8]) -> Self::Response {
+        // If the token is rejected, the server sends a challenge with the error details
+        // and expects an empty response, before it fails the command.
+        if self.responded.replace(true) {
+            return String::new();
+        }
+
+        match self.auth {
+            Auth::Oauthbearer => format!("n,a={},\x01auth=Bearer {}\x01\x01", self.user, self.access_token),
+            _ => format!("user={}\x01auth=Bearer {}\x01\x01", self.user, self.access_token),
+        }
+    }
+}
+
+/// Returns a valid access token, refreshing it with the refresh token if it is missing
+/// or about to expire.
+pub fn access_token(account: &str, oauth2: &OAuth2) -> Result<String> {
+    let _lock = TOKEN_LOCK.lock().unwrap();
+
+    let mut token = read_token(account, oauth2)?;
+
+    if let (&Some(ref access_token), Some(expires_at)) = (&token.access_token, token.expires_at) {
+        if now() + EXPIRY_MARGIN < expires_at {
+            return Ok(access_token.clone());
+        }
+    }
+
+    let response = refresh(oauth2, &token.refresh_token)?;
+
+    token.access_token = Some(response.access_token.clone());
+    token.expires_at = Some(now() + response.expires_in.unwrap_or(3600));
+    if let Some(refresh_token) = response.refresh_token {
+        token.refresh_token = refresh_token;
+    }
+    write_token(account, oauth2, &token)?;
+
+    Ok(response.access_token)
+}
+
+fn refresh(oauth2: &OAuth2, refresh_token: &str) -> Result<TokenResponse> {
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", oauth2.client_id.as_str()),
+        ("client_secret", oauth2.client_secret.as_str()),
+    ];
+
+    let client = match http_client(oauth2.token_url.as_str()) {
+        Ok(client) => client,
+        Err(e) => return Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("Failed to set up the HTTP client: {}", e)))),
+    };
+    let response = match client.post(oauth2.token_url.as_str()).form(&params).send() {
+        Ok(response) => response,
+        Err(e) => return Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("Failed to refresh the OAuth2 token: {}", e)))),
+    };
+
+    let status = response.status();
+    if status.is_server_error() {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("The OAuth2 token endpoint failed with: {}", status))));
+    }
+    if !status.is_success() {
+        // Most likely `invalid_grant`, which means a new refresh token is needed
+        let body = response.text().unwrap_or(String::new());
+        return Err(Error::NoResponse(vec![format!("The OAuth2 token endpoint refused to refresh the token ({}): {}", status, body)]));
+    }
+
+    match response.json::<TokenResponse>() {
+        Ok(token) => Ok(token),
+        Err(e) => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read the OAuth2 token response: {}", e)))),
+    }
+}
+
+fn read_token(account: &str, oauth2: &OAuth2) -> Result<Token> {
+    let path_token_file = path_config_file(&oauth2.token_file(account));
+
+    if !path_token_file.exists() {
+        write_token(account, oauth2, &Token {
+            refresh_token: "".to_string(),
+            access_token: None,
+            expires_at: None,
+        })?;
+        return Err(fatal(format!("Add the OAuth2 refresh token to '{}'", path_token_file.display())));
+    }
+
+    let mut data = String::new();
+    if let Err(e) = File::open(&path_token_file).and_then(|mut file| file.read_to_string(&mut data)) {
+        return Err(fatal(format!("Failed to read the token file '{}': {}", path_token_file.display(), e)));
+    }
+
+    toml::from_str(&data).map_err(|e| fatal(format!("The token file '{}' is broken: {}", path_token_file.display(), e)))
+}
+
+fn write_token(account: &str, oauth2: &OAuth2, token: &Token) -> Result<()> {
+    let path_token_file = path_config_file(&oauth2.token_file(account));
+
+    // The file contains secrets, so only the owner may read it
+    let toml = toml::to_string(token).map_err(|e| fatal(format!("Failed to serialize the token: {}", e)))?;
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+        .open(&path_token_file)
+        .and_then(|mut token_file| token_file.write_all(toml.as_bytes()))
+        .map_err(|e| fatal(format!("Failed to write the token file '{}': {}", path_token_file.display(), e)))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
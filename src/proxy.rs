@@ -0,0 +1,202 @@
+extern crate reqwest;
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use config::DEFAULT;
+use config::default::{ProxyKind, ProxyServer};
+use imap_extention::encoding::encode_base64;
+
+/// How long connecting to the proxy and each read or write of the handshake may take, so a proxy
+/// which does not answer does not hang the account forever.
+static TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Opens a TCP connection to `host`, through the proxy from `[proxy]` if there is one for the host.
+/// The tunnel is a plain `TcpStream`, so TLS and STARTTLS work on top of it as usual.
+pub fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let server = match DEFAULT.proxy(host) {
+        Some(server) => server,
+        None => return TcpStream::connect((host, port)),
+    };
+
+    let (kind, proxy_host, proxy_port) = server.address();
+    if DEFAULT.debug() {
+        println!("Connecting to {}:{} through the proxy {}", host, port, &server.url);
+    }
+
+    let mut tcp = connect_timeout(proxy_host, proxy_port)?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    match kind {
+        ProxyKind::Socks5 => socks5_connect(&mut tcp, server, host, port)?,
+        ProxyKind::Http => http_connect(&mut tcp, server, host, port)?,
+    }
+
+    // The IMAP connection sets its own timeouts, e.g. none while idling
+    tcp.set_read_timeout(None)?;
+    tcp.set_write_timeout(None)?;
+    Ok(tcp)
+}
+
+/// Tries every address of `host` in turn, like `TcpStream::connect`, but gives up on each after `TIMEOUT`.
+fn connect_timeout(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last = error(format!("The proxy host '{}' has no address", host));
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// A HTTP client, which goes through the proxy from `[proxy]` if there is one for the host of `url`.
+pub fn http_client(url: &str) -> reqwest::Result<reqwest::blocking::Client> {
+    let host = reqwest::Url::parse(url).ok().and_then(|url| url.host_str().map(|h| h.to_string())).unwrap_or(String::new());
+
+    let mut builder = reqwest::blocking::Client::builder();
+    match DEFAULT.proxy(&host) {
+        Some(server) => {
+            // With `socks5://` reqwest resolves the host itself, `socks5h://` lets the proxy resolve it like `connect` does
+            let url = match server.address().0 {
+                ProxyKind::Socks5 => format!("socks5h{}", &server.url[server.url.find("://").unwrap()..]),
+                ProxyKind::Http => server.url.clone(),
+            };
+            let mut proxy = reqwest::Proxy::all(url.as_str())?;
+            if let (&Some(ref username), &Some(ref password)) = (&server.username, &server.password) {
+                proxy = proxy.basic_auth(username, password);
+            }
+            builder = builder.proxy(proxy);
+        },
+        None => builder = builder.no_proxy(),
+    }
+
+    builder.build()
+}
+
+fn error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+/// SOCKS5 (RFC 1928) with username/password authentication (RFC 1929). The host name is resolved by the proxy,
+/// IP addresses are sent as they are.
+fn socks5_connect(tcp: &mut TcpStream, server: &ProxyServer, host: &str, port: u16) -> io::Result<()> {
+    let credentials = match (&server.username, &server.password) {
+        (&Some(ref username), &Some(ref password)) => Some((username, password)),
+        _ => None,
+    };
+
+    if credentials.is_some() {
+        tcp.write_all(&[5, 2, 0, 2])?;
+    } else {
+        tcp.write_all(&[5, 1, 0])?;
+    }
+
+    let mut reply = [0u8; 2];
+    tcp.read_exact(&mut reply)?;
+    if reply[0] != 5 {
+        return Err(error(format!("The proxy {} is not a SOCKS5 proxy", &server.url)));
+    }
+
+    match (reply[1], credentials) {
+        (0, _) => (),
+        (2, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(error("The username and password of a SOCKS5 proxy can at most be 255 bytes".to_string()));
+            }
+
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            tcp.write_all(&request)?;
+
+            tcp.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(error(format!("The proxy {} refused the username and password", &server.url)));
+            }
+        },
+        _ => return Err(error(format!("The proxy {} wants an authentication method, which is not supported", &server.url))),
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(error(format!("The host name '{}' is too long for SOCKS5", host)));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.push((port >> 8) as u8);
+    request.push((port & 0xff) as u8);
+    tcp.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    tcp.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(error(format!("The proxy {} failed to connect to {}:{} (SOCKS5 error {})", &server.url, host, port, reply[1])));
+    }
+
+    // The address the proxy bound to, which is not needed
+    let length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut length = [0u8; 1];
+            tcp.read_exact(&mut length)?;
+            length[0] as usize
+        },
+        _ => return Err(error(format!("The proxy {} sent an invalid reply", &server.url))),
+    };
+    let mut address = vec![0u8; length + 2];
+    tcp.read_exact(&mut address)?;
+
+    Ok(())
+}
+
+/// HTTP CONNECT (RFC 7231, section 4.3.6) with basic authentication.
+fn http_connect(tcp: &mut TcpStream, server: &ProxyServer, host: &str, port: u16) -> io::Result<()> {
+    // An IPv6 address has to be in brackets, e.g. `[::1]:993` (RFC 3986, section 3.2.2)
+    let authority = if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let (&Some(ref username), &Some(ref password)) = (&server.username, &server.password) {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encode_base64(format!("{}:{}", username, password).as_bytes())));
+    }
+    request.push_str("\r\n");
+    tcp.write_all(request.as_bytes())?;
+
+    // Read a byte at a time, so nothing after the header (the greeting of the IMAP server) is read
+    let mut response: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 16384 {
+            return Err(error(format!("The response from the proxy {} is too long", &server.url)));
+        }
+        tcp.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or("");
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(error(format!("The proxy {} failed to connect to {}: {}", &server.url, authority, status))),
+    }
+}
@@ -1,20 +1,76 @@
 extern crate slack_hook;
+extern crate reqwest;
+extern crate serde_json;
 
-use self::slack_hook::{Slack, PayloadBuilder, AttachmentBuilder, Result};
+use self::slack_hook::{PayloadBuilder, AttachmentBuilder, Payload};
 
 use imap_extention::fetch::*;
+use imap_extention::header::format_addresses;
 use config::DEFAULT;
+use config::default::{AttachmentPolicy, Attachments};
+use proxy::http_client;
 
-pub fn post_mails(mail: &Mail, channels: &Vec<String>) -> Result<()> {
-    let slack = Slack::new(DEFAULT.slack.webhook.as_str()).expect("Failed at connecting to the Slack Webhook");
+/// An attachment of a mail, downloaded to be uploaded to Slack.
+pub struct File {
+    /// The section of the part in the mail, to match it with `Mail::attachments`
+    pub section: String,
+    pub name: String,
+    pub mimetype: String,
+    pub data: Vec<u8>,
+}
+
+/// The fields we use from the responses of the Slack Web API.
+#[derive(Deserialize,Debug)]
+struct ApiResponse {
+    ok: bool,
+    error: Option<String>,
+    channel: Option<String>,
+    ts: Option<String>,
+    upload_url: Option<String>,
+    file_id: Option<String>,
+}
+
+/// A file for `files.completeUploadExternal`.
+#[derive(Serialize)]
+struct UploadedFile {
+    id: String,
+    title: String,
+}
+
+/// A plain text reply for `chat.postMessage`, in the thread of the message `thread_ts`.
+#[derive(Serialize)]
+struct ThreadReply<'a> {
+    channel: &'a str,
+    thread_ts: &'a str,
+    text: &'a str,
+}
+
+/// Posts the mail to the channels with the incoming webhook. The payload is built by slack_hook,
+/// but sent with our own HTTP client, so it goes through the proxy from `[proxy]`.
+///
+/// With the `list` and `upload` attachment policies the attachments are listed below the text.
+/// If there are `files` to upload, the mail is posted with the Web API instead, because the webhook
+/// does not tell where the message ended up, and the files are uploaded to the thread of the message.
+/// A failed upload is printed and told in the thread, but does not fail the post, so the mail is not posted twice.
+pub fn post_mails(mail: &Mail, channels: &Vec<String>, attachments: Option<&Attachments>, files: &[File]) -> Result<(), String> {
+    let mut text = mail.text.clone();
+    let policy = attachments.map_or(AttachmentPolicy::None, |a| a.policy);
+    if policy != AttachmentPolicy::None && !mail.attachments.is_empty() {
+        text.push_str("\n\n*Attachments*");
+        for part in &mail.attachments {
+            let skipped = policy == AttachmentPolicy::Upload && !files.iter().any(|f| f.section == part.section);
+            text.push_str(&format!("\n• {} ({}, {}){}", part.name(), part.mimetype, format_size(part.decoded_size()),
+                                   if skipped { " not uploaded" } else { "" }));
+        }
+    }
 
     for channel in channels {
         let p = PayloadBuilder::new()
             .attachments(
                 vec![AttachmentBuilder::new("")
-                    .pretext(format!("From:\t\t{}\nTo:\t\t\t{}", mail.from, mail.to))
+                    .pretext(format!("From:\t\t{}\nTo:\t\t\t{}", format_addresses(&mail.from), format_addresses(&mail.to)))
                     .title(mail.subject.clone())
-                    .text(mail.text.clone())
+                    .text(text.clone())
                     .build().unwrap()])
             .channel(channel.to_string())
             .username(DEFAULT.slack.username.clone())
@@ -22,10 +78,124 @@ pub fn post_mails(mail: &Mail, channels: &Vec<String>) -> Result<()> {
             .build()
             .unwrap();
 
-        match slack.send(&p) {
-            Ok(()) => println!("ok"),
-            Err(x) => return Err(x)
+        if files.is_empty() {
+            post_webhook(&p)?;
+        } else {
+            let (channel_id, ts) = post_message(&p)?;
+            if let Err(e) = upload_files(files, &channel_id, &ts) {
+                println!("Failed to upload the attachments of the mail {} to {}: {}", mail.uid, channel, e);
+                // Otherwise nobody in the channel knows that the attachments listed in the post are missing
+                let text = format!("The attachments of this mail could not be uploaded: {}", e);
+                if let Err(e) = post_reply(&channel_id, &ts, &text) {
+                    println!("Failed to tell {} that the attachments of the mail {} are missing: {}", channel, mail.uid, e);
+                }
+            }
+        }
+        println!("ok");
+    }
+    Ok(())
+}
+
+fn post_webhook(p: &Payload) -> Result<(), String> {
+    let webhook = DEFAULT.slack.webhook.as_str();
+    let client = http_client(webhook).map_err(|e| format!("Failed at connecting to the Slack Webhook: {}", e))?;
+
+    let response = client.post(webhook).json(p).send().map_err(|e| format!("Failed to post to Slack: {}", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().unwrap_or(String::new());
+        return Err(format!("Slack refused the post ({}): {}", status, body));
+    }
+    Ok(())
+}
+
+/// Posts with `chat.postMessage`, which returns the ID of the channel and the timestamp of the message.
+fn post_message(p: &Payload) -> Result<(String, String), String> {
+    let url = format!("{}/chat.postMessage", DEFAULT.slack.api_url());
+    let client = http_client(&url).map_err(|e| format!("Failed at connecting to the Slack API: {}", e))?;
+
+    let request = client.post(&url).bearer_auth(token()).json(p);
+    match api_call(request, "chat.postMessage")? {
+        ApiResponse { channel: Some(channel), ts: Some(ts), .. } => Ok((channel, ts)),
+        response => Err(format!("Slack did not return the channel and timestamp of the post: {:?}", response)),
+    }
+}
+
+/// Replies with `chat.postMessage` in the thread of a message.
+fn post_reply(channel_id: &str, thread_ts: &str, text: &str) -> Result<(), String> {
+    let url = format!("{}/chat.postMessage", DEFAULT.slack.api_url());
+    let client = http_client(&url).map_err(|e| format!("Failed at connecting to the Slack API: {}", e))?;
+
+    let request = client.post(&url).bearer_auth(token()).json(&ThreadReply { channel: channel_id, thread_ts: thread_ts, text: text });
+    api_call(request, "chat.postMessage").map(|_| ())
+}
+
+/// Uploads the files to the thread of a message: every file gets an upload URL,
+/// and all of them are shared with a single `files.completeUploadExternal`.
+fn upload_files(files: &[File], channel_id: &str, thread_ts: &str) -> Result<(), String> {
+    let api_url = DEFAULT.slack.api_url();
+    let client = http_client(api_url).map_err(|e| format!("Failed at connecting to the Slack API: {}", e))?;
+
+    let mut uploaded: Vec<UploadedFile> = Vec::new();
+    for file in files {
+        let length = file.data.len().to_string();
+        let request = client.post(&format!("{}/files.getUploadURLExternal", api_url))
+            .bearer_auth(token())
+            .form(&[("filename", file.name.as_str()), ("length", length.as_str())]);
+        let (upload_url, file_id) = match api_call(request, "files.getUploadURLExternal")? {
+            ApiResponse { upload_url: Some(upload_url), file_id: Some(file_id), .. } => (upload_url, file_id),
+            response => return Err(format!("Slack did not return an upload URL for '{}': {:?}", file.name, response)),
+        };
+
+        let upload_client = http_client(&upload_url).map_err(|e| format!("Failed at connecting to the Slack upload URL: {}", e))?;
+        let response = upload_client.post(&upload_url)
+            .header("Content-Type", file.mimetype.as_str())
+            .body(file.data.clone())
+            .send()
+            .map_err(|e| format!("Failed to upload '{}': {}", file.name, e))?;
+        if !response.status().is_success() {
+            return Err(format!("Slack refused the upload of '{}' ({})", file.name, response.status()));
         }
+
+        uploaded.push(UploadedFile { id: file_id, title: file.name.clone() });
     }
+
+    let files = serde_json::to_string(&uploaded).map_err(|e| e.to_string())?;
+    let request = client.post(&format!("{}/files.completeUploadExternal", api_url))
+        .bearer_auth(token())
+        .form(&[("files", files.as_str()), ("channel_id", channel_id), ("thread_ts", thread_ts)]);
+    api_call(request, "files.completeUploadExternal")?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The Web API answers `200 OK` with `"ok": false` when a call fails.
+fn api_call(request: reqwest::blocking::RequestBuilder, method: &str) -> Result<ApiResponse, String> {
+    let response = request.send().map_err(|e| format!("Failed to call {}: {}", method, e))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().unwrap_or(String::new());
+        return Err(format!("Slack refused {} ({}): {}", method, status, body));
+    }
+
+    let response: ApiResponse = response.json().map_err(|e| format!("Failed to read the response of {}: {}", method, e))?;
+    if !response.ok {
+        return Err(format!("Slack refused {}: {}", method, response.error.unwrap_or(String::new())));
+    }
+    Ok(response)
+}
+
+/// Checked at startup, when a publish uploads attachments.
+fn token() -> &'static str {
+    DEFAULT.slack.token.as_ref().map_or("", |t| t.as_str())
+}
+
+/// e.g. `512 B`, `12.3 KB` or `4.0 MB`
+fn format_size(size: u64) -> String {
+    if size < 1024 {
+        format!("{} B", size)
+    } else if size < 1024 * 1024 {
+        format!("{:.1} KB", size as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,193 @@
+extern crate rusqlite;
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::rusqlite::{params, Connection, OptionalExtension};
+
+use config::DEFAULT;
+use config::path_config_file;
+use imap_extention::path::Status;
+
+lazy_static! {
+    // Opened on first use, and again on the next use if that failed
+    static ref STATE: Mutex<Option<Connection>> = Mutex::new(None);
+    // The STATUS of every mailbox after the last check, keyed by (account, mailbox). Only kept in memory,
+    // so every mailbox is checked ones after a restart.
+    static ref STATUS: Mutex<HashMap<(String, String), Status>> = Mutex::new(HashMap::new());
+}
+
+/// Opens the state database in the config folder, and creates the tables if they are missing.
+///
+/// `posted` has a row for every mail posted to a channel, keyed by both the location of the mail
+/// (account, mailbox, UIDVALIDITY, UID) and the Message-ID, so the same mail is not posted twice
+/// even when it is left unread, copied to another mailbox or the mailbox is rebuilt.
+fn open() -> Result<Connection, String> {
+    let path_state_file = path_config_file(DEFAULT.state_file());
+
+    let connection = Connection::open(&path_state_file)
+        .map_err(|e| format!("Failed to open the state database '{}': {}", path_state_file.display(), e))?;
+
+    connection.execute_batch("
+        CREATE TABLE IF NOT EXISTS mailbox (
+            account      TEXT    NOT NULL,
+            mailbox      TEXT    NOT NULL,
+            uid_validity   INTEGER NOT NULL,
+            highest_modseq INTEGER,
+            PRIMARY KEY (account, mailbox)
+        );
+        CREATE TABLE IF NOT EXISTS posted (
+            account      TEXT    NOT NULL,
+            mailbox      TEXT    NOT NULL,
+            uid_validity INTEGER NOT NULL,
+            uid          INTEGER NOT NULL,
+            message_id   TEXT    NOT NULL,
+            channel      TEXT    NOT NULL,
+            posted_at    INTEGER NOT NULL,
+            PRIMARY KEY (account, mailbox, uid_validity, uid, channel)
+        );
+        CREATE INDEX IF NOT EXISTS posted_message_id ON posted (message_id, channel);
+    ").map_err(|e| format!("Failed to create the tables in the state database '{}': {}", path_state_file.display(), e))?;
+
+    // Databases from before CONDSTORE support lack the column
+    if connection.prepare("SELECT highest_modseq FROM mailbox LIMIT 0").is_err() {
+        connection.execute_batch("ALTER TABLE mailbox ADD COLUMN highest_modseq INTEGER")
+            .map_err(|e| format!("Failed to upgrade the state database '{}': {}", path_state_file.display(), e))?;
+    }
+
+    Ok(connection)
+}
+
+/// Locks the state database, and opens it if it is not open yet. The lock is released when the guard is dropped,
+/// so errors are returned to be reported by the caller without holding it.
+fn lock() -> Result<MutexGuard<'static, Option<Connection>>, String> {
+    // A panic while holding the lock cannot leave the database half written, SQLite rolls back unfinished statements
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    if state.is_none() {
+        *state = Some(open()?);
+    }
+    Ok(state)
+}
+
+/// Runs `f` on the state database, with `what` failed in front of its error.
+fn with_state<T, F: FnOnce(&Connection) -> rusqlite::Result<T>>(what: &str, f: F) -> Result<T, String> {
+    let state = lock()?;
+    f(state.as_ref().unwrap()).map_err(|e| format!("Failed to {} the state database: {}", what, e))
+}
+
+/// Records the UIDVALIDITY of a mailbox. Returns the old UIDVALIDITY if it changed since the mailbox was
+/// last selected, which means the mailbox was rebuilt and the UIDs from before mean nothing now.
+/// The HIGHESTMODSEQ is forgotten as well then, since mod-sequences also start over.
+pub fn check_uid_validity(account: &str, mailbox: &str, uid_validity: u32) -> Result<Option<u32>, String> {
+    let old: Option<u32> = with_state("read the UIDVALIDITY from", |state| state.query_row(
+        "SELECT uid_validity FROM mailbox WHERE account = ?1 AND mailbox = ?2",
+        params![account, mailbox],
+        |row| row.get(0),
+    ).optional())?;
+
+    match old {
+        Some(old) if old == uid_validity => Ok(None),
+        Some(old) => {
+            with_state("write the UIDVALIDITY to", |state| state.execute(
+                "UPDATE mailbox SET uid_validity = ?3, highest_modseq = NULL WHERE account = ?1 AND mailbox = ?2",
+                params![account, mailbox, uid_validity],
+            ))?;
+            Ok(Some(old))
+        },
+        None => {
+            with_state("write the UIDVALIDITY to", |state| state.execute(
+                "INSERT INTO mailbox (account, mailbox, uid_validity) VALUES (?1, ?2, ?3)",
+                params![account, mailbox, uid_validity],
+            ))?;
+            Ok(None)
+        },
+    }
+}
+
+/// The HIGHESTMODSEQ (RFC 7162) of the mailbox after the last complete check, if it was done with the same UIDVALIDITY.
+pub fn highest_modseq(account: &str, mailbox: &str, uid_validity: u32) -> Result<Option<u64>, String> {
+    let modseq: Option<Option<i64>> = with_state("read the HIGHESTMODSEQ from", |state| state.query_row(
+        "SELECT highest_modseq FROM mailbox WHERE account = ?1 AND mailbox = ?2 AND uid_validity = ?3",
+        params![account, mailbox, uid_validity],
+        |row| row.get(0),
+    ).optional())?;
+
+    Ok(modseq.and_then(|m| m).map(|m| m as u64))
+}
+
+pub fn set_highest_modseq(account: &str, mailbox: &str, uid_validity: u32, highest_modseq: u64) -> Result<(), String> {
+    with_state("write the HIGHESTMODSEQ to", |state| state.execute(
+        "UPDATE mailbox SET highest_modseq = ?4 WHERE account = ?1 AND mailbox = ?2 AND uid_validity = ?3",
+        params![account, mailbox, uid_validity, highest_modseq as i64],
+    )).map(|_| ())
+}
+
+/// Has UNSEEN, UIDNEXT, UIDVALIDITY or HIGHESTMODSEQ changed since the status was last set?
+///
+/// Only HIGHESTMODSEQ (CONDSTORE) changes with the other flags, so without it the status always changed
+/// for a search which depends on them (`flags`, see `SEARCH::depends_on_flags`).
+pub fn status_changed(account: &str, mailbox: &str, status: &Status, flags: bool) -> bool {
+    let known = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+
+    match known.get(&(account.to_string(), mailbox.to_string())) {
+        Some(old) => old.unseen != status.unseen || old.uid_next != status.uid_next || old.uid_validity != status.uid_validity
+            || old.highest_modseq != status.highest_modseq
+            || old.uid_next.is_none() || status.uid_next.is_none()
+            || (flags && status.highest_modseq.is_none()),
+        None => true,
+    }
+}
+
+pub fn set_status(account: &str, mailbox: &str, status: Status) {
+    STATUS.lock().unwrap_or_else(|e| e.into_inner()).insert((account.to_string(), mailbox.to_string()), status);
+}
+
+/// Has the mail already been posted to the channel? Either found by its location or, if it has one, by its Message-ID.
+pub fn is_posted(account: &str, mailbox: &str, uid_validity: u32, uid: u32, message_id: &str, channel: &str) -> Result<bool, String> {
+    Ok(is_uid_posted(account, mailbox, uid_validity, uid, channel)? || is_message_id_posted(message_id, channel)?)
+}
+
+pub fn is_uid_posted(account: &str, mailbox: &str, uid_validity: u32, uid: u32, channel: &str) -> Result<bool, String> {
+    with_state("read from", |state| state.query_row(
+        "SELECT 1 FROM posted WHERE account = ?1 AND mailbox = ?2 AND uid_validity = ?3 AND uid = ?4 AND channel = ?5",
+        params![account, mailbox, uid_validity, uid, channel],
+        |_| Ok(()),
+    ).optional()).map(|row| row.is_some())
+}
+
+pub fn is_message_id_posted(message_id: &str, channel: &str) -> Result<bool, String> {
+    if message_id.is_empty() {
+        return Ok(false);
+    }
+
+    with_state("read from", |state| state.query_row(
+        "SELECT 1 FROM posted WHERE message_id = ?1 AND channel = ?2",
+        params![message_id, channel],
+        |_| Ok(()),
+    ).optional()).map(|row| row.is_some())
+}
+
+pub fn set_posted(account: &str, mailbox: &str, uid_validity: u32, uid: u32, message_id: &str, channel: &str) -> Result<(), String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+
+    with_state("write to", |state| state.execute(
+        "INSERT OR REPLACE INTO posted (account, mailbox, uid_validity, uid, message_id, channel, posted_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![account, mailbox, uid_validity, uid, message_id, channel, now],
+    )).map(|_| ())
+}
+
+/// Forgets everything about the mailbox, so its mails can be posted again. Returns the number of forgotten posts.
+pub fn reset(account: &str, mailbox: &str) -> Result<usize, String> {
+    with_state("delete from", |state| {
+        state.execute(
+            "DELETE FROM mailbox WHERE account = ?1 AND mailbox = ?2",
+            params![account, mailbox],
+        )?;
+
+        state.execute(
+            "DELETE FROM posted WHERE account = ?1 AND mailbox = ?2",
+            params![account, mailbox],
+        )
+    })
+}
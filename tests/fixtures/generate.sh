@@ -0,0 +1,24 @@
+#!/bin/sh
+# Generates the certificates for the TLS tests: a CA, the certificate of the fake IMAP server
+# for 127.0.0.1 and localhost, and a client certificate as PEM and as PKCS#12 (password `secret`).
+# The SHA-256 fingerprint of server.pem is pinned in tests/pipeline.rs, update it after running this.
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=imap2slack test CA" \
+    -keyout ca.key -out ca.pem
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=127.0.0.1" -keyout server.key -out server.csr
+printf "subjectAltName = IP:127.0.0.1, DNS:localhost\n" > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 \
+    -extfile server.ext -out server.pem
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=imap2slack test client" -keyout client.key -out client.csr
+printf "extendedKeyUsage = clientAuth\n" > client.ext
+openssl x509 -req -in client.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 36500 \
+    -extfile client.ext -out client.pem
+openssl pkcs12 -export -in client.pem -inkey client.key -passout pass:secret -out client.p12
+
+rm -f ca.key ca.srl server.csr server.ext client.csr client.ext
+
+openssl x509 -in server.pem -outform der | openssl dgst -sha256
@@ -0,0 +1,1214 @@
+//! Runs imap2slack against the fake IMAP server and the fake Slack from `support`.
+
+extern crate openssl;
+
+mod support;
+
+use std::time::Duration;
+
+use support::bridge::{wait_for, Bridge};
+use support::imap_server::{fixture, FakeImapServer, Failure, Quirk};
+use support::mime::{alternative_mail, base64, encoded_mail, html_mail, mail_with_attachments, text_mail};
+use support::proxy::{FakeProxy, ProxyKind};
+use support::slack_receiver::FakeSlack;
+
+static INBOX: &'static str = r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+"##;
+
+static ARCHIVE: &'static str = r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+
+[[publish.actions]]
+action = "move"
+mailbox = "Archive"
+"##;
+
+fn setup() -> (FakeImapServer, FakeSlack) {
+    let imap = FakeImapServer::start();
+    imap.add_mailbox("INBOX");
+    (imap, FakeSlack::start())
+}
+
+fn assert_success(run: &support::bridge::Run) {
+    assert!(run.status.success(), "imap2slack failed:\n{}", run.stdout);
+}
+
+/// STATUS must not be used on the selected mailbox (RFC 3501, section 6.3.10). Expects one connection at a time.
+fn assert_no_status_on_the_selected_mailbox(imap: &FakeImapServer) {
+    let commands = imap.commands();
+    let mut selected: Option<&str> = None;
+    for command in &commands {
+        let mut words = command.splitn(2, ' ');
+        match (words.next().unwrap_or(""), words.next()) {
+            ("SELECT", Some(name)) | ("EXAMINE", Some(name)) => selected = Some(name),
+            ("CLOSE", _) | ("UNSELECT", _) | ("LOGOUT", _) => selected = None,
+            ("STATUS", Some(args)) => assert!(selected.map_or(true, |name| !args.starts_with(name)), "STATUS on the selected mailbox:\n{:?}", commands),
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn posts_unseen_mail_and_marks_it_as_seen() {
+    let (imap, slack) = setup();
+    let unseen = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Hello Bob", "How are you?"), &[]);
+    imap.append("INBOX", &text_mail("carol@example.com", "bob@example.com", "Old news", "Already read"), &["\\Seen"]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("Hello Bob"));
+    assert!(posts[0].contains("How are you?"));
+    assert!(posts[0].contains("#mail"));
+    assert!(imap.flags("INBOX", unseen).contains(&"\\Seen".to_string()));
+}
+
+#[test]
+fn does_not_use_status_on_the_selected_mailbox() {
+    let (imap, slack) = setup();
+    imap.add_capability("CONDSTORE");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "First", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Second", "Hi"), &[]);
+    assert_success(&bridge.run());
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 2);
+    assert_no_status_on_the_selected_mailbox(&imap);
+    assert!(imap.commands().iter().any(|c| c == "UNSELECT"));
+}
+
+#[test]
+fn leaves_the_mailbox_without_expunging_it() {
+    let (imap, slack) = setup();
+    imap.set_capabilities(&["IMAP4rev1", "LITERAL+", "IDLE", "UIDPLUS", "ENABLE"]);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Hello", "Hi"), &[]);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Deleted elsewhere", "Hi"), &["\\Seen", "\\Deleted"]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert_eq!(imap.uids("INBOX").len(), 2);
+    assert_no_status_on_the_selected_mailbox(&imap);
+    let commands = imap.commands();
+    assert!(commands.iter().any(|c| c.starts_with("EXAMINE")) && commands.iter().any(|c| c == "CLOSE"), "{:?}", commands);
+}
+
+#[test]
+fn posts_mail_of_a_named_account() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Named", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", &format!("{}account = \"work\"\n", INBOX));
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("[mail]", "[mail.work]"));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+}
+
+#[test]
+fn reports_a_missing_setting_of_the_mail_section() {
+    let (imap, slack) = setup();
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("username = \"user\"\n", ""));
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains("You have to add the") && run.stdout.contains("username"), "{}", run.stdout);
+}
+
+#[test]
+fn reports_a_missing_setting_of_a_named_account() {
+    let (imap, slack) = setup();
+
+    let bridge = Bridge::new(&imap, &slack, "", &format!("{}account = \"work\"\n", INBOX));
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("[mail]", "[mail.work]").replace("username = \"user\"\n", ""));
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains("You have to add the") && run.stdout.contains("username") && run.stdout.contains("'work'"), "{}", run.stdout);
+}
+
+#[test]
+fn posts_to_every_channel() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Two channels", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#one", "#two"]
+"##);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 2);
+    assert!(posts.iter().any(|p| p.contains("#one")));
+    assert!(posts.iter().any(|p| p.contains("#two")));
+}
+
+#[test]
+fn does_not_post_the_same_mail_twice() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Only once", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "mark_mail_as_seen = false", INBOX);
+    assert_success(&bridge.run());
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(!imap.flags("INBOX", uid).contains(&"\\Seen".to_string()));
+}
+
+#[test]
+fn does_not_post_again_after_the_mailbox_is_rebuilt() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Rebuilt", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "mark_mail_as_seen = false", INBOX);
+    assert_success(&bridge.run());
+    imap.rebuild("INBOX");
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+}
+
+#[test]
+fn leaves_mail_unread_when_the_state_database_fails() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "No state", "Hi"), &[]);
+
+    // The folder of the state database does not exist, so it cannot be opened
+    let bridge = Bridge::new(&imap, &slack, r#"state_file = "missing/state.sqlite""#, INBOX);
+    let run = bridge.run();
+    assert_success(&run);
+
+    assert!(run.stdout.contains("Failed to open the state database"), "{}", run.stdout);
+    assert_eq!(slack.posts().len(), 0);
+    assert!(!imap.flags("INBOX", uid).contains(&"\\Seen".to_string()));
+}
+
+#[test]
+fn posts_again_after_state_reset() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Again", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "mark_mail_as_seen = false", INBOX);
+    assert_success(&bridge.run());
+    assert_success(&bridge.run_with_args(&["state", "reset", "INBOX"]));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 2);
+}
+
+#[test]
+fn marks_posted_mail_with_a_keyword() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Keyword", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, r#"keyword = "$Imap2Slack""#, INBOX);
+    assert_success(&bridge.run());
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    let flags = imap.flags("INBOX", uid);
+    assert!(flags.contains(&"$Imap2Slack".to_string()));
+    assert!(!flags.contains(&"\\Seen".to_string()));
+    assert!(imap.commands().iter().any(|c| c.contains("UNKEYWORD $Imap2Slack")));
+}
+
+#[test]
+fn marks_posted_mail_with_a_keyword_without_permanentflags() {
+    let (imap, slack) = setup();
+    imap.quirk(Quirk::NoPermanentFlags);
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Keyword", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, r#"keyword = "$Imap2Slack""#, INBOX);
+    let run = bridge.run();
+    assert_success(&run);
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(!run.stdout.contains("does not allow the keyword"), "{}", run.stdout);
+    assert!(imap.flags("INBOX", uid).contains(&"$Imap2Slack".to_string()));
+}
+
+#[test]
+fn uses_only_the_state_database_when_the_keyword_is_not_permanent() {
+    let (imap, slack) = setup();
+    imap.set_permanent_flags("INBOX", &["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"]);
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "No keywords", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, r#"keyword = "$Imap2Slack""#, INBOX);
+    let run = bridge.run();
+    assert_success(&run);
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(run.stdout.contains("does not allow the keyword $Imap2Slack"), "{}", run.stdout);
+    assert!(!imap.flags("INBOX", uid).contains(&"$Imap2Slack".to_string()));
+}
+
+#[test]
+fn refuses_a_keyword_which_is_not_an_atom() {
+    let (imap, slack) = setup();
+
+    let bridge = Bridge::new(&imap, &slack, r#"keyword = "$Imap 2 Slack""#, INBOX);
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains("The keyword '$Imap 2 Slack' is not valid"), "{}", run.stdout);
+    assert!(imap.commands().is_empty());
+}
+
+#[test]
+fn posts_mail_read_in_another_client_with_a_keyword() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Read already", "Hi"), &["\\Seen"]);
+
+    let bridge = Bridge::new(&imap, &slack, r#"keyword = "$Imap2Slack""#, INBOX);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("Read already"));
+    assert!(imap.flags("INBOX", uid).contains(&"$Imap2Slack".to_string()));
+    assert!(!imap.commands().iter().any(|c| c.contains("SEARCH") && c.contains("UNSEEN")));
+}
+
+#[test]
+fn retries_mail_when_slack_fails() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Retry", "Hi"), &[]);
+    slack.fail_next(1);
+
+    let bridge = Bridge::new(&imap, &slack, r#"keyword = "$Imap2Slack""#, INBOX);
+    assert_success(&bridge.run());
+    assert_eq!(slack.posts().len(), 0);
+    assert!(!imap.flags("INBOX", uid).contains(&"$Imap2Slack".to_string()));
+
+    assert_success(&bridge.run());
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.flags("INBOX", uid).contains(&"$Imap2Slack".to_string()));
+}
+
+#[test]
+fn retries_unseen_mail_when_slack_fails() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Retry", "Hi"), &[]);
+    slack.fail_next(1);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+    assert_eq!(slack.posts().len(), 0);
+    assert!(!imap.flags("INBOX", uid).contains(&"\\Seen".to_string()));
+
+    assert_success(&bridge.run());
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.flags("INBOX", uid).contains(&"\\Seen".to_string()));
+}
+
+#[test]
+fn checks_every_mailbox_matching_a_wildcard() {
+    let imap = FakeImapServer::start();
+    let slack = FakeSlack::start();
+    for mailbox in &["INBOX", "Lists/rust", "Lists/go", "Other"] {
+        imap.add_mailbox(mailbox);
+        imap.append(mailbox, &text_mail("list@example.com", "bob@example.com", &format!("Mail in {}", mailbox), "Hi"), &[]);
+    }
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "Lists/*"
+channel = ["#lists"]
+"##);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 2);
+    assert!(posts.iter().any(|p| p.contains("Mail in Lists/rust")));
+    assert!(posts.iter().any(|p| p.contains("Mail in Lists/go")));
+}
+
+#[test]
+fn reads_the_hierarchy_delimiter_once_per_session() {
+    let (imap, slack) = setup();
+    imap.add_mailbox("Lists/rust");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Mail in INBOX", "Hi"), &[]);
+    imap.append("Lists/rust", &text_mail("list@example.com", "bob@example.com", "Mail in Lists/rust", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", &format!("{}{}", INBOX, r##"
+[[publish]]
+mailbox = "Lists/rust"
+channel = ["#lists"]
+"##));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 2);
+    assert_eq!(imap.commands().iter().filter(|c| c.as_str() == "LIST \"\" \"\"").count(), 1);
+}
+
+#[test]
+fn skips_a_mailbox_which_cannot_be_selected() {
+    let imap = FakeImapServer::start();
+    let slack = FakeSlack::start();
+    imap.add_mailbox("First");
+    imap.add_mailbox("Second");
+    imap.append("First", &text_mail("alice@example.com", "bob@example.com", "From the first", "Hi"), &[]);
+    imap.append("Second", &text_mail("alice@example.com", "bob@example.com", "From the second", "Hi"), &[]);
+    imap.fail("SELECT", Failure::No, 1);
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "First"
+channel = ["#mail"]
+
+[[publish]]
+mailbox = "Second"
+channel = ["#mail"]
+"##);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("From the second"));
+}
+
+#[test]
+fn reconnects_when_the_connection_is_lost() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Reconnect", "Hi"), &[]);
+    imap.fail("UID FETCH", Failure::Disconnect, 1);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.connections() >= 2);
+}
+
+#[test]
+fn stops_on_wrong_password() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Never", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    bridge.write_file("default.toml", &::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap().replace("password = \"secret\"", "password = \"wrong\""));
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert_eq!(slack.posts().len(), 0);
+}
+
+/// A bridge which logs in with `auth` ("xoauth2" or "oauthbearer"), and gets its tokens from the fake Slack.
+fn oauth2_bridge(imap: &FakeImapServer, slack: &FakeSlack, auth: &str) -> Bridge {
+    let bridge = Bridge::new(imap, slack, "", INBOX);
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("password = \"secret\"\n", &format!(r#"password = ""
+auth = "{}"
+
+[mail.oauth2]
+token_url = "{}"
+client_id = "imap2slack-test"
+client_secret = "client-secret"
+"#, auth, slack.url("/token"))));
+    bridge
+}
+
+fn now() -> u64 {
+    ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[test]
+fn logs_in_with_oauthbearer_and_a_valid_access_token() {
+    let (imap, slack) = setup();
+    imap.add_capability("AUTH=OAUTHBEARER");
+    imap.set_access_token("access-1");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "OAuth2", "Hi"), &[]);
+
+    let bridge = oauth2_bridge(&imap, &slack, "oauthbearer");
+    bridge.write_file("oauth2_token.toml", &format!("refresh_token = \"refresh-1\"\naccess_token = \"access-1\"\nexpires_at = {}\n", now() + 3600));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.commands().iter().any(|c| c == "AUTHENTICATE OAUTHBEARER"), "{:?}", imap.commands());
+    assert!(!imap.commands().iter().any(|c| c.starts_with("LOGIN")));
+    assert!(slack.requests().iter().all(|r| r.path != "/token"));
+}
+
+#[test]
+fn refreshes_an_access_token_about_to_expire() {
+    let (imap, slack) = setup();
+    imap.add_capability("AUTH=XOAUTH2");
+    imap.set_access_token("access-2");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "OAuth2", "Hi"), &[]);
+    slack.respond("/token", r#"{"access_token": "access-2", "token_type": "Bearer", "expires_in": 3600, "refresh_token": "refresh-2"}"#);
+
+    let bridge = oauth2_bridge(&imap, &slack, "xoauth2");
+    // Still valid, but within the margin before it expires
+    bridge.write_file("oauth2_token.toml", &format!("refresh_token = \"refresh-1\"\naccess_token = \"access-1\"\nexpires_at = {}\n", now() + 60));
+    let start = now();
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.commands().iter().any(|c| c == "AUTHENTICATE XOAUTH2"), "{:?}", imap.commands());
+
+    let refreshes: Vec<String> = slack.requests().iter().filter(|r| r.path == "/token").map(|r| r.body_str()).collect();
+    assert_eq!(refreshes.len(), 1);
+    for param in &["grant_type=refresh_token", "refresh_token=refresh-1", "client_id=imap2slack-test", "client_secret=client-secret"] {
+        assert!(refreshes[0].split('&').any(|p| p == *param), "{}", refreshes[0]);
+    }
+
+    // The new tokens are written back, readable only by the owner
+    let path = bridge.config_dir().join("oauth2_token.toml");
+    let token = ::std::fs::read_to_string(&path).unwrap();
+    assert!(token.contains("refresh_token = \"refresh-2\"") && token.contains("access_token = \"access-2\""), "{}", token);
+    let expires_at: u64 = token.lines().find(|l| l.starts_with("expires_at = ")).and_then(|l| l[13..].trim().parse().ok()).expect(&token);
+    assert!(expires_at >= start + 3600 && expires_at <= now() + 3600, "{}", token);
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(::std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn stops_when_the_refresh_token_is_refused() {
+    let (imap, slack) = setup();
+    imap.add_capability("AUTH=XOAUTH2");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Never", "Hi"), &[]);
+    slack.respond_with_status("/token", "400 Bad Request", r#"{"error": "invalid_grant", "error_description": "Token has been expired or revoked."}"#);
+
+    let bridge = oauth2_bridge(&imap, &slack, "xoauth2");
+    let token = "refresh_token = \"revoked\"\n";
+    bridge.write_file("oauth2_token.toml", token);
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains("invalid_grant"), "{}", run.stdout);
+    assert_eq!(slack.posts().len(), 0);
+    assert!(!imap.commands().iter().any(|c| c.starts_with("AUTHENTICATE")));
+    assert_eq!(::std::fs::read_to_string(bridge.config_dir().join("oauth2_token.toml")).unwrap(), token);
+}
+
+#[test]
+fn stops_when_the_server_refuses_the_access_token() {
+    let (imap, slack) = setup();
+    imap.add_capability("AUTH=XOAUTH2");
+    imap.set_access_token("access-2");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Never", "Hi"), &[]);
+
+    let bridge = oauth2_bridge(&imap, &slack, "xoauth2");
+    bridge.write_file("oauth2_token.toml", &format!("refresh_token = \"refresh-1\"\naccess_token = \"access-1\"\nexpires_at = {}\n", now() + 3600));
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(imap.commands().iter().any(|c| c == "AUTHENTICATE XOAUTH2"), "{:?}", imap.commands());
+    assert_eq!(slack.posts().len(), 0);
+}
+
+#[test]
+fn asks_for_the_refresh_token_when_the_token_file_is_missing() {
+    let (imap, slack) = setup();
+    imap.add_capability("AUTH=XOAUTH2");
+
+    let bridge = oauth2_bridge(&imap, &slack, "xoauth2");
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains("Add the OAuth2 refresh token to"), "{}", run.stdout);
+    let token = ::std::fs::read_to_string(bridge.config_dir().join("oauth2_token.toml")).unwrap();
+    assert!(token.contains("refresh_token = \"\""), "{}", token);
+}
+
+/// The SHA-256 fingerprint of `tests/fixtures/server.pem`, printed by `tests/fixtures/generate.sh`.
+static SERVER_FINGERPRINT: &'static str = "8eaa3b6f7fab4a90d88be9a27fd4e163248c7b09dac6533f66c78fb19bc94b9b";
+
+/// A bridge which connects with TLS, with the `[mail.tls]` settings `tls`.
+fn tls_bridge(imap: &FakeImapServer, slack: &FakeSlack, tls: &str) -> Bridge {
+    let bridge = Bridge::new(imap, slack, "", INBOX);
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config
+        .replace("security = \"plain\"\nallow_plaintext_login = true\n", "security = \"tls\"\n")
+        .replace("password = \"secret\"\n", &format!("password = \"secret\"\n\n[mail.tls]\n{}\n", tls)));
+    bridge
+}
+
+fn tls_server(require_client_certificate: bool) -> FakeImapServer {
+    let imap = FakeImapServer::start_tls(require_client_certificate);
+    imap.add_mailbox("INBOX");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "TLS", "Hi"), &[]);
+    imap
+}
+
+#[test]
+fn trusts_the_server_with_the_ca_file() {
+    let (imap, slack) = (tls_server(false), FakeSlack::start());
+
+    let bridge = tls_bridge(&imap, &slack, &format!("ca_file = \"{}\"", fixture("ca.pem")));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.client_certificates().is_empty());
+}
+
+#[test]
+fn accepts_a_pinned_certificate() {
+    let (imap, slack) = (tls_server(false), FakeSlack::start());
+    // Written like `openssl x509 -fingerprint -sha256` does, with colons and in upper case
+    let pin: Vec<String> = SERVER_FINGERPRINT.as_bytes().chunks(2).map(|b| String::from_utf8_lossy(b).to_uppercase()).collect();
+
+    let bridge = tls_bridge(&imap, &slack, &format!("ca_file = \"{}\"\npinned_sha256 = [\"{}\", \"{}\"]", fixture("ca.pem"), "00".repeat(32), pin.join(":")));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+}
+
+#[test]
+fn stops_on_a_certificate_which_is_not_pinned() {
+    let (imap, slack) = (tls_server(false), FakeSlack::start());
+
+    let bridge = tls_bridge(&imap, &slack, &format!("ca_file = \"{}\"\npinned_sha256 = [\"{}\"]", fixture("ca.pem"), "00".repeat(32)));
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains(SERVER_FINGERPRINT) && run.stdout.contains("not in `pinned_sha256`"), "{}", run.stdout);
+    assert_eq!(slack.posts().len(), 0);
+    // The password is not sent to a server which is not trusted
+    assert!(imap.commands().is_empty(), "{:?}", imap.commands());
+}
+
+#[test]
+fn logs_in_with_a_pem_client_certificate() {
+    let (imap, slack) = (tls_server(true), FakeSlack::start());
+
+    let bridge = tls_bridge(&imap, &slack, &format!("ca_file = \"{}\"\nclient_cert = \"{}\"\nclient_key = \"{}\"",
+                                                    fixture("ca.pem"), fixture("client.pem"), fixture("client.key")));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert_eq!(imap.client_certificates(), vec!["imap2slack test client".to_string()]);
+}
+
+#[test]
+fn logs_in_with_a_pkcs12_client_certificate() {
+    let (imap, slack) = (tls_server(true), FakeSlack::start());
+
+    let bridge = tls_bridge(&imap, &slack, &format!("ca_file = \"{}\"\nclient_cert = \"{}\"\nclient_cert_password = \"secret\"",
+                                                    fixture("ca.pem"), fixture("client.p12")));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert_eq!(imap.client_certificates(), vec!["imap2slack test client".to_string()]);
+}
+
+#[test]
+fn stops_on_a_client_certificate_with_the_wrong_password() {
+    let (imap, slack) = (tls_server(true), FakeSlack::start());
+
+    let bridge = tls_bridge(&imap, &slack, &format!("ca_file = \"{}\"\nclient_cert = \"{}\"\nclient_cert_password = \"wrong\"",
+                                                    fixture("ca.pem"), fixture("client.p12")));
+    let run = bridge.run();
+
+    assert!(!run.status.success());
+    assert!(run.stdout.contains("is not valid"), "{}", run.stdout);
+    assert!(imap.commands().is_empty(), "{:?}", imap.commands());
+    assert_eq!(slack.posts().len(), 0);
+}
+
+#[test]
+fn accepts_any_certificate_with_the_insecure_override() {
+    let (imap, slack) = (tls_server(false), FakeSlack::start());
+
+    let bridge = tls_bridge(&imap, &slack, "danger_accept_invalid_certs = true");
+    let run = bridge.run();
+    assert_success(&run);
+
+    assert!(run.stdout.contains("WARNING: `danger_accept_invalid_certs` is enabled"), "{}", run.stdout);
+    assert_eq!(slack.posts().len(), 1);
+}
+
+#[test]
+fn runs_actions_after_posting() {
+    let (imap, slack) = setup();
+    imap.add_mailbox("Archive");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Archive me", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+
+[[publish.actions]]
+action = "move"
+mailbox = "Archive"
+"##);
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    assert!(imap.uids("INBOX").is_empty());
+    assert_eq!(imap.uids("Archive").len(), 1);
+}
+
+#[test]
+fn reads_the_capabilities_once_per_session() {
+    let (imap, slack) = setup();
+    imap.add_mailbox("Archive");
+    for i in 0..3 {
+        imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", &format!("Archive me {}", i), "Hi"), &[]);
+    }
+
+    let bridge = Bridge::new(&imap, &slack, "", ARCHIVE);
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 3);
+    assert_eq!(imap.uids("Archive").len(), 3);
+    assert_eq!(imap.commands().iter().filter(|c| c.as_str() == "CAPABILITY").count(), 1);
+}
+
+#[test]
+fn does_not_expunge_without_uidplus() {
+    let (imap, slack) = setup();
+    imap.set_capabilities(&["IMAP4rev1", "LITERAL+", "IDLE", "ENABLE"]);
+    imap.add_mailbox("Archive");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Archive me", "Hi"), &[]);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Deleted elsewhere", "Hi"), &["\\Seen", "\\Deleted"]);
+
+    let bridge = Bridge::new(&imap, &slack, "", ARCHIVE);
+    let run = bridge.run();
+    assert_success(&run);
+
+    assert_eq!(slack.posts().len(), 1);
+    assert_eq!(imap.uids("INBOX").len(), 2);
+    assert!(imap.uids("Archive").is_empty());
+    assert!(!imap.commands().iter().any(|c| c.contains("EXPUNGE")));
+    assert!(run.stdout.contains("UIDPLUS"), "{}", run.stdout);
+}
+
+#[test]
+fn only_fetches_the_text_of_a_large_mail() {
+    let (imap, slack) = setup();
+    let text = "All work and no play makes Jack a dull boy.\n".repeat(100);
+    let attachment = vec![b'x'; 200000];
+    imap.append("INBOX", &mail_with_attachments("alice@example.com", "bob@example.com", "Large", &text, &[("large.bin", "application/octet-stream", &attachment)]), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "max_fetch_bytes = 1000", INBOX);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("All work and no play"));
+    assert!(posts[0].contains("…"));
+
+    let commands = imap.commands();
+    assert!(commands.iter().any(|c| c.contains("BODYSTRUCTURE")));
+    assert!(commands.iter().any(|c| c.contains("BODY.PEEK[1]<0.1000>")));
+    assert!(!commands.iter().any(|c| c.contains("BODY.PEEK[2]") || c.contains("BODY.PEEK[]")));
+}
+
+#[test]
+fn fetches_the_text_of_every_mail_with_one_command() {
+    let (imap, slack) = setup();
+    for i in 0..3 {
+        imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", &format!("Mail {}", i), &format!("Text {}", i)), &[]);
+    }
+    imap.append("INBOX", &mail_with_attachments("alice@example.com", "bob@example.com", "Report", "Text 3", &[("report.pdf", "application/pdf", b"%PDF")]), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 4);
+    for i in 0..4 {
+        assert!(posts.iter().any(|p| p.contains(&format!("Text {}", i))));
+    }
+
+    let text_fetches: Vec<String> = imap.commands().into_iter().filter(|c| c.contains("BODY.PEEK[1]")).collect();
+    assert_eq!(text_fetches.len(), 1, "{:?}", text_fetches);
+    assert!(text_fetches[0].contains("UID FETCH 1:4 "));
+}
+
+#[test]
+fn filters_on_the_sender_address() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("=?utf-8?q?J=C3=B8rgen?= <jorgen@example.dk>", "\"Team\": bob@example.com;", "From Denmark", "Hej"), &[]);
+    imap.append("INBOX", &text_mail("\"example.dk, but not really\" <spam@example.com>", "bob@example.com", "Spam", "Buy"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+filter = "Danes"
+"##);
+    bridge.write_file("filters.toml", r#"
+[filter.Danes]
+case_sensitive = false
+field = "from"
+contains = ["@example.dk"]
+"#);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("From Denmark"));
+    assert!(posts[0].contains("Jørgen <jorgen@example.dk>"));
+    assert!(posts[0].contains("Team: bob@example.com;"));
+}
+
+#[test]
+fn posts_html_only_mail_as_mrkdwn() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &html_mail("news@example.com", "bob@example.com", "Newsletter",
+                                     "<html><head><style>p { color: red }</style></head><body><p>Hello <b>Bob</b>, read <a href=\"https://example.com/news\">the news</a>.</p><ul><li>One</li><li>Two</li></ul></body></html>"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("Hello *Bob*, read <https://example.com/news|the news>."), "{}", posts[0]);
+    assert!(posts[0].contains("• One\\n• Two"), "{}", posts[0]);
+    assert!(!posts[0].contains("color: red"));
+}
+
+#[test]
+fn posts_the_html_part_when_the_rule_prefers_it() {
+    let (imap, slack) = setup();
+    imap.add_mailbox("Html");
+    let mail = alternative_mail("alice@example.com", "bob@example.com", "Both", "Plain version", "<p><i>HTML</i> version</p>");
+    imap.append("INBOX", &mail, &[]);
+    imap.append("Html", &mail, &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#plain"]
+
+[[publish]]
+mailbox = "Html"
+channel = ["#html"]
+prefer_html = true
+"##);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 2);
+    assert!(posts.iter().any(|p| p.contains("#plain") && p.contains("Plain version")));
+    assert!(posts.iter().any(|p| p.contains("#html") && p.contains("_HTML_ version")));
+}
+
+#[test]
+fn posts_mail_in_arrival_order() {
+    let (imap, slack) = setup();
+    // Copied from another mailbox, so the UIDs are not in arrival order
+    imap.append_with_date("INBOX", &text_mail("alice@example.com", "bob@example.com", "Third", "Hi"), &[], "03-Jan-2020 12:00:00 +0000");
+    imap.append_with_date("INBOX", &text_mail("alice@example.com", "bob@example.com", "First", "Hi"), &[], "01-Jan-2020 12:00:00 +0100");
+    imap.append_with_date("INBOX", &text_mail("alice@example.com", "bob@example.com", "Second", "Hi"), &[], " 2-Jan-2020 12:00:00 -0500");
+
+    // A new state database every time, so the mails are posted again
+    for batch_size in &[50, 1] {
+        let bridge = Bridge::new(&imap, &slack, &format!("fetch_batch_size = {}\nmark_mail_as_seen = false", batch_size), INBOX);
+        assert_success(&bridge.run());
+    }
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 6);
+    for posts in posts.chunks(3) {
+        assert!(posts[0].contains("First"));
+        assert!(posts[1].contains("Second"));
+        assert!(posts[2].contains("Third"));
+    }
+    assert!(imap.commands().iter().any(|c| c.contains("(UID INTERNALDATE)")));
+}
+
+#[test]
+fn lists_the_attachments_of_a_mail() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &mail_with_attachments("alice@example.com", "bob@example.com", "Report", "See the attachment",
+                                                 &[("report.pdf", "application/pdf", &[b'%'; 3000])]), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+
+[publish.attachments]
+policy = "list"
+"##);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("*Attachments*\\n• report.pdf (application/pdf, "), "{}", posts[0]);
+    assert!(!imap.commands().iter().any(|c| c.contains("BODY.PEEK[2]")));
+}
+
+#[test]
+fn uploads_the_attachments_of_a_mail_to_the_thread() {
+    let (imap, slack) = setup();
+    let pdf: Vec<u8> = (0..2000).map(|i| (i % 256) as u8).collect();
+    imap.append("INBOX", &mail_with_attachments("alice@example.com", "bob@example.com", "Report", "See the attachments",
+                                                 &[("report.pdf", "application/pdf", &pdf), ("tool.exe", "application/octet-stream", b"MZ"),
+                                                   ("huge.pdf", "application/pdf", &[b'%'; 5000])]), &[]);
+
+    slack.respond("/api/chat.postMessage", r#"{"ok": true, "channel": "C123", "ts": "1580000000.000100"}"#);
+    slack.respond("/api/files.getUploadURLExternal", &format!(r#"{{"ok": true, "upload_url": "{}", "file_id": "F42"}}"#, slack.url("/upload")));
+    slack.respond("/api/files.completeUploadExternal", r#"{"ok": true}"#);
+
+    let bridge = Bridge::with_slack(&imap, &slack, "", &format!("token = \"xoxb-test\"\napi_url = \"{}\"", slack.url("/api/")), r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+
+[publish.attachments]
+policy = "upload"
+max_size = 4000
+mime_types = ["application/pdf", "image/*"]
+"##);
+    assert_success(&bridge.run());
+
+    assert!(slack.posts().is_empty());
+    let requests = slack.requests();
+    let request = |path: &str| requests.iter().filter(|r| r.path == path).cloned().collect::<Vec<_>>();
+
+    let messages = request("/api/chat.postMessage");
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].header("Authorization"), Some("Bearer xoxb-test"));
+    let message = messages[0].body_str();
+    assert!(message.contains("#mail") && message.contains("See the attachments"), "{}", message);
+    // The attachments are listed in the order of the mail
+    let listed: Vec<&str> = message.split("\\n• ").skip(1).map(|l| l.split("\\n").next().unwrap()).collect();
+    assert_eq!(listed.len(), 3, "{}", message);
+    assert!(listed[0].starts_with("report.pdf (application/pdf, ") && !listed[0].contains("not uploaded"), "{}", message);
+    assert!(listed[1].starts_with("tool.exe (application/octet-stream, ") && listed[1].contains(" B) not uploaded"), "{}", message);
+    assert!(listed[2].starts_with("huge.pdf (application/pdf, ") && listed[2].contains(" KB) not uploaded"), "{}", message);
+
+    let upload_urls = request("/api/files.getUploadURLExternal");
+    assert_eq!(upload_urls.len(), 1);
+    assert_eq!(upload_urls[0].body_str(), "filename=report.pdf&length=2000");
+
+    let uploads = request("/upload");
+    assert_eq!(uploads.len(), 1);
+    assert_eq!(uploads[0].body, pdf);
+
+    let completes = request("/api/files.completeUploadExternal");
+    assert_eq!(completes.len(), 1);
+    let complete = completes[0].body_str();
+    assert!(complete.contains("channel_id=C123") && complete.contains("thread_ts=1580000000.000100"), "{}", complete);
+    assert!(complete.contains("F42"), "{}", complete);
+}
+
+#[test]
+fn tells_the_thread_when_the_upload_fails() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &mail_with_attachments("alice@example.com", "bob@example.com", "Report", "See the attachment",
+                                                           &[("report.pdf", "application/pdf", b"%PDF")]), &[]);
+
+    slack.respond("/api/chat.postMessage", r#"{"ok": true, "channel": "C123", "ts": "1580000000.000100"}"#);
+    slack.respond("/api/files.getUploadURLExternal", r#"{"ok": false, "error": "missing_scope"}"#);
+
+    let bridge = Bridge::with_slack(&imap, &slack, "", &format!("token = \"xoxb-test\"\napi_url = \"{}\"", slack.url("/api/")), r##"
+[[publish]]
+mailbox = "INBOX"
+channel = ["#mail"]
+
+[publish.attachments]
+policy = "upload"
+"##);
+    assert_success(&bridge.run());
+
+    let messages: Vec<String> = slack.requests().iter().filter(|r| r.path == "/api/chat.postMessage").map(|r| r.body_str()).collect();
+    assert_eq!(messages.len(), 2, "{:?}", messages);
+    assert!(messages[0].contains("See the attachment"), "{}", messages[0]);
+    assert!(messages[1].contains("\"thread_ts\":\"1580000000.000100\"") && messages[1].contains("\"channel\":\"C123\""), "{}", messages[1]);
+    assert!(messages[1].contains("could not be uploaded") && messages[1].contains("missing_scope"), "{}", messages[1]);
+    // Posted all the same, so it is not posted again
+    assert!(imap.flags("INBOX", uid).contains(&"\\Seen".to_string()));
+}
+
+#[test]
+fn decodes_mail_in_other_charsets() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &encoded_mail(b"=?ISO-8859-1?Q?Bl=E5b=E6rgr=F8d?=", "text/plain; charset=iso-8859-1", "8bit", b"R\xF8dgr\xF8d med fl\xF8de"), &[]);
+    imap.append("INBOX", &encoded_mail(b"=?ISO-2022-JP?B?GyRCRnxLXDhsGyhC?=", "text/plain; charset=ISO-2022-JP", "7bit", b"\x1b$B$3$s$K$A$O\x1b(B"), &[]);
+    imap.append("INBOX", &encoded_mail(b"=?windows-1252?q?=93Quoted=94?=", "text/plain; charset=windows-1252", "quoted-printable", b"=80 5 for a caf=E9 au lait"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    let run = bridge.run();
+    assert_success(&run);
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 3);
+    assert!(posts.iter().any(|p| p.contains("Blåbærgrød") && p.contains("Rødgrød med fløde")), "{:?}", posts);
+    assert!(posts.iter().any(|p| p.contains("日本語") && p.contains("こんにちは")), "{:?}", posts);
+    assert!(posts.iter().any(|p| p.contains("“Quoted”") && p.contains("€ 5 for a café au lait")), "{:?}", posts);
+    assert!(!run.stdout.contains("WARNING"), "{}", run.stdout);
+}
+
+#[test]
+fn posts_mail_which_cannot_be_decoded_properly() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &encoded_mail(b"Caf\xE9 =?x-unknown?q?ol=C3=A9?= =?utf-8?b?w6b?", "text/plain; charset=utf-8", "base64", b"SGVq*IGRl\nciE"), &[]);
+    imap.append("INBOX", &encoded_mail(b"Second", "text/plain; charset=x-mystery", "8bit", "Still readable: æøå".as_bytes()), &[]);
+    // Not an encoded word, because the encoding is not `B` or `Q`
+    imap.append("INBOX", &encoded_mail("=?utf-8?€?x?=".as_bytes(), "text/plain; charset=utf-8", "8bit", b"Euro"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    // The warnings themselves are tested in `imap_extention::fetch`
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 3);
+    assert!(posts.iter().any(|p| p.contains("Café olé =?utf-8?b?w6b?") && p.contains("Hej der!")), "{:?}", posts);
+    assert!(posts.iter().any(|p| p.contains("Still readable: æøå")), "{:?}", posts);
+    assert!(posts.iter().any(|p| p.contains("=?utf-8?€?x?=") && p.contains("Euro")), "{:?}", posts);
+}
+
+#[test]
+fn only_searches_changed_mail_with_condstore() {
+    let (imap, slack) = setup();
+    imap.add_capability("CONDSTORE");
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Before", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "After", "Hi"), &[]);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 2);
+    assert!(posts[1].contains("After"));
+
+    let commands = imap.commands();
+    assert!(commands.iter().any(|c| c.starts_with("ENABLE CONDSTORE")));
+    assert!(commands.iter().any(|c| c.starts_with("UID SEARCH") && c.contains("MODSEQ")));
+}
+
+fn posts_with_quirk(quirk: Quirk) {
+    let (imap, slack) = setup();
+    imap.add_capability("CONDSTORE");
+    imap.quirk(quirk);
+    for i in 0..5 {
+        imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", &format!("Quirk {}", i), "Hi"), &[]);
+    }
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 5, "{:?}", quirk);
+    for i in 0..5 {
+        assert!(posts.iter().any(|p| p.contains(&format!("Quirk {}", i))), "{:?}", quirk);
+    }
+}
+
+#[test]
+fn searches_non_ascii_text_as_a_literal() {
+    let (imap, slack) = setup();
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Café", "Hi"), &[]);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Tea", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", &format!("{}search = 'SUBJECT \"café\"'\n", INBOX));
+    assert_success(&bridge.run());
+
+    let posts = slack.posts();
+    assert_eq!(posts.len(), 1);
+    assert!(posts[0].contains("Café"));
+    assert!(imap.commands().iter().any(|c| c.starts_with("UID SEARCH CHARSET UTF-8") && c.contains("{5+}")), "{:?}", imap.commands());
+}
+
+#[test]
+fn refuses_non_ascii_search_without_literal_plus() {
+    let (imap, slack) = setup();
+    imap.set_capabilities(&["IMAP4rev1", "IDLE", "UIDPLUS", "ENABLE", "UNSELECT"]);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Café", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", &format!("{}search = 'SUBJECT \"café\"'\n", INBOX));
+    let run = bridge.run();
+    assert_success(&run);
+
+    assert!(run.stdout.contains("needs the LITERAL+ capability"), "{}", run.stdout);
+    assert!(slack.posts().is_empty());
+    assert!(!imap.commands().iter().any(|c| c.contains("SEARCH")), "{:?}", imap.commands());
+}
+
+#[test]
+fn search_response_with_trailing_space() {
+    posts_with_quirk(Quirk::SearchTrailingSpace);
+}
+
+#[test]
+fn search_response_split_over_several_lines() {
+    posts_with_quirk(Quirk::SearchSplit);
+}
+
+#[test]
+fn search_response_as_esearch() {
+    posts_with_quirk(Quirk::Esearch);
+}
+
+#[test]
+fn search_response_with_modseq() {
+    posts_with_quirk(Quirk::SearchModseq);
+}
+
+#[test]
+fn search_response_with_unsolicited_exists() {
+    posts_with_quirk(Quirk::SearchUnsolicitedExists);
+}
+
+#[test]
+fn posts_new_mail_while_idling() {
+    let (imap, slack) = setup();
+
+    let bridge = Bridge::new(&imap, &slack, "", INBOX);
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("service = false", "service = true").replace("idle = false", "idle = true"));
+
+    let service = bridge.spawn();
+    assert!(wait_for(Duration::from_secs(20), || imap.commands().iter().any(|c| c == "IDLE")), "imap2slack never started to idle:\n{}", service.stdout());
+
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "While idling", "Hi"), &[]);
+    assert!(slack.wait_for_posts(1, Duration::from_secs(20)), "The mail was not posted:\n{}", service.stdout());
+    assert!(slack.posts()[0].contains("While idling"));
+
+    // Idling again on the mailbox, after it was checked with STATUS
+    assert!(wait_for(Duration::from_secs(20), || imap.commands().iter().filter(|c| c.as_str() == "IDLE").count() >= 2), "imap2slack did not idle again:\n{}", service.stdout());
+    assert_no_status_on_the_selected_mailbox(&imap);
+}
+
+#[test]
+fn posts_mail_flagged_after_the_last_check() {
+    let (imap, slack) = setup();
+    let uid = imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Flag me", "Hi"), &[]);
+
+    let bridge = Bridge::new(&imap, &slack, "", &format!("{}search = \"FLAGGED\"\n", INBOX));
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("service = false", "service = true"));
+
+    // Flagging changes neither UNSEEN nor UIDNEXT, so the mailbox must not be skipped as unchanged
+    let service = bridge.spawn();
+    assert!(wait_for(Duration::from_secs(20), || imap.commands().iter().filter(|c| c.starts_with("STATUS")).count() >= 2),
+            "imap2slack never checked the mailbox twice:\n{}", service.stdout());
+    assert!(slack.posts().is_empty());
+
+    imap.add_flags("INBOX", uid, &["\\Flagged"]);
+    assert!(slack.wait_for_posts(1, Duration::from_secs(20)), "The flagged mail was not posted:\n{}", service.stdout());
+    assert!(slack.posts()[0].contains("Flag me"));
+}
+
+/// Connects to the fake IMAP server as `host`, e.g. `localhost`, so it can have a proxy of its own in `proxy`.
+fn bridge_behind_proxy(imap: &FakeImapServer, slack: &FakeSlack, host: &str, proxy: &str) -> Bridge {
+    let bridge = Bridge::new(imap, slack, "", &format!("{}\n{}", proxy, INBOX));
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    bridge.write_file("default.toml", &config.replace("imap = \"127.0.0.1\"", &format!("imap = \"{}\"", host)));
+    bridge
+}
+
+#[test]
+fn connects_through_a_http_proxy() {
+    let (imap, slack) = setup();
+    let proxy = FakeProxy::start(ProxyKind::Http);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Through the proxy", "Hi"), &[]);
+
+    // Only the IMAP server is behind the proxy, the fake Slack is posted to directly
+    let bridge = bridge_behind_proxy(&imap, &slack, "localhost", &format!(r#"
+[proxy]
+url = "direct"
+
+[proxy.destination."localhost"]
+url = "{}"
+username = "proxyuser"
+password = "proxysecret"
+"#, proxy.url()));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    let tunnels = proxy.tunnels();
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].destination, format!("localhost:{}", imap.port()));
+    assert_eq!(tunnels[0].auth, Some(format!("Basic {}", base64(b"proxyuser:proxysecret"))));
+}
+
+#[test]
+fn connects_to_an_ipv6_address_through_a_http_proxy() {
+    let (imap, slack) = setup();
+    let proxy = FakeProxy::start(ProxyKind::Http);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Through the proxy", "Hi"), &[]);
+
+    let bridge = bridge_behind_proxy(&imap, &slack, "::1", &format!(r#"
+[proxy]
+url = "direct"
+
+[proxy.destination."::1"]
+url = "{}"
+"#, proxy.url()));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    let tunnels = proxy.tunnels();
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].destination, format!("[::1]:{}", imap.port()));
+    assert_eq!(tunnels[0].auth, None);
+}
+
+#[test]
+fn connects_through_a_socks5_proxy() {
+    let (imap, slack) = setup();
+    let proxy = FakeProxy::start(ProxyKind::Socks5);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Through the proxy", "Hi"), &[]);
+
+    // Everything goes through the proxy, except the fake Slack
+    let bridge = bridge_behind_proxy(&imap, &slack, "localhost", &format!(r#"
+[proxy]
+url = "{}"
+username = "proxyuser"
+password = "proxysecret"
+
+[proxy.destination."127.0.0.1"]
+url = "direct"
+"#, proxy.url()));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    let tunnels = proxy.tunnels();
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0].destination, format!("localhost:{}", imap.port()));
+    assert_eq!(tunnels[0].auth, Some("proxyuser:proxysecret".to_string()));
+}
+
+#[test]
+fn posts_to_slack_through_a_socks5_proxy_which_resolves_the_host() {
+    let (imap, slack) = setup();
+    let proxy = FakeProxy::start(ProxyKind::Socks5);
+    imap.append("INBOX", &text_mail("alice@example.com", "bob@example.com", "Through the proxy", "Hi"), &[]);
+
+    // `.invalid` never resolves (RFC 2606), so only the proxy can connect to it
+    let bridge = Bridge::new(&imap, &slack, "", &format!(r#"
+[proxy]
+url = "{}"
+
+[proxy.destination."127.0.0.1"]
+url = "direct"
+
+{}"#, proxy.url(), INBOX));
+    let config = ::std::fs::read_to_string(bridge.config_dir().join("default.toml")).unwrap();
+    let webhook = slack.webhook_url();
+    bridge.write_file("default.toml", &config.replace(&webhook, &webhook.replace("127.0.0.1", "slack.invalid")));
+    assert_success(&bridge.run());
+
+    assert_eq!(slack.posts().len(), 1);
+    let tunnels = proxy.tunnels();
+    assert_eq!(tunnels.len(), 1);
+    assert!(tunnels[0].destination.starts_with("slack.invalid:"), "{:?}", tunnels);
+}
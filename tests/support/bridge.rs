@@ -0,0 +1,189 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use super::imap_server::FakeImapServer;
+use super::slack_receiver::FakeSlack;
+
+static HOME: AtomicUsize = AtomicUsize::new(0);
+
+/// The output of a finished run of imap2slack.
+pub struct Run {
+    pub status: ExitStatus,
+    pub stdout: String,
+}
+
+/// imap2slack with a HOME of its own, so its config and state database are kept apart from
+/// other tests. The HOME is removed again when the bridge is dropped.
+pub struct Bridge {
+    home: PathBuf,
+}
+
+impl Bridge {
+    /// A bridge, which reads mail from `imap` and posts to `slack`. `settings` are added to the top of
+    /// `default.toml` (e.g. `keyword = "$Posted"`), `tables` to the end (e.g. `[[publish]]` sections).
+    pub fn new(imap: &FakeImapServer, slack: &FakeSlack, settings: &str, tables: &str) -> Bridge {
+        Bridge::with_slack(imap, slack, settings, "", tables)
+    }
+
+    /// Same as `new`, with `slack_settings` added to the `[slack]` section (e.g. `token = "xoxb-test"`).
+    pub fn with_slack(imap: &FakeImapServer, slack: &FakeSlack, settings: &str, slack_settings: &str, tables: &str) -> Bridge {
+        let config = format!(r#"service = false
+sleep_time = 1
+idle = false
+{}
+
+[mail]
+imap = "127.0.0.1"
+port = {}
+security = "plain"
+allow_plaintext_login = true
+username = "user"
+password = "secret"
+
+[slack]
+webhook = "{}"
+username = "imap2slack"
+emoji = "email"
+{}
+
+[reconnect]
+initial_delay = 0
+max_delay = 0
+jitter = 0.0
+
+{}
+"#, settings, imap.port(), slack.webhook_url(), slack_settings, tables);
+
+        Bridge::with_config(&config)
+    }
+
+    pub fn with_config(config: &str) -> Bridge {
+        let mut home = ::std::env::temp_dir();
+        home.push(format!("imap2slack-test-{}-{}", ::std::process::id(), HOME.fetch_add(1, Ordering::SeqCst)));
+
+        let bridge = Bridge { home: home };
+        bridge.write_file("default.toml", config);
+        bridge.write_file("filters.toml", "[filter]\n");
+        bridge
+    }
+
+    /// The folder with the config files and the state database.
+    pub fn config_dir(&self) -> PathBuf {
+        let mut dir = self.home.clone();
+        dir.push(".config/imap2slack");
+        dir
+    }
+
+    pub fn write_file(&self, name: &str, content: &str) {
+        let dir = self.config_dir();
+        fs::create_dir_all(&dir).expect("Failed to create the config folder");
+        let mut path = dir;
+        path.push(name);
+        File::create(&path).and_then(|mut f| f.write_all(content.as_bytes())).expect("Failed to write a config file");
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_imap2slack"));
+        command.args(args)
+            .env("HOME", &self.home)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        command
+    }
+
+    /// Runs imap2slack until it exits, which it does after one check with `service = false`.
+    pub fn run(&self) -> Run {
+        self.run_with_args(&[])
+    }
+
+    pub fn run_with_args(&self, args: &[&str]) -> Run {
+        let mut child = self.command(args).spawn().expect("Failed to start imap2slack");
+        let stdout = read_stdout(&mut child);
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().expect("Failed to wait for imap2slack") {
+                break status;
+            }
+            if start.elapsed() > Duration::from_secs(60) {
+                let _ = child.kill();
+                panic!("imap2slack did not stop within a minute:\n{}", stdout.lock().unwrap());
+            }
+            sleep(Duration::from_millis(20));
+        };
+
+        // Let the reader thread catch up with the last output
+        sleep(Duration::from_millis(50));
+        let stdout = stdout.lock().unwrap().clone();
+        Run { status: status, stdout: stdout }
+    }
+
+    /// Starts imap2slack in the background, e.g. with `service = true`. It is killed when the `Service` is dropped.
+    pub fn spawn(&self) -> Service {
+        let mut child = self.command(&[]).spawn().expect("Failed to start imap2slack");
+        let stdout = read_stdout(&mut child);
+        Service { child: child, stdout: stdout }
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+pub struct Service {
+    child: Child,
+    stdout: Arc<Mutex<String>>,
+}
+
+impl Service {
+    pub fn stdout(&self) -> String {
+        self.stdout.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Reads stdout in a thread of its own, so imap2slack never blocks on a full pipe.
+fn read_stdout(child: &mut Child) -> Arc<Mutex<String>> {
+    let output = Arc::new(Mutex::new(String::new()));
+    let mut stdout = child.stdout.take().unwrap();
+
+    let shared = output.clone();
+    thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match stdout.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => shared.lock().unwrap().push_str(&String::from_utf8_lossy(&buffer[..n])),
+            }
+        }
+    });
+
+    output
+}
+
+/// Waits until `condition` is true. Returns `false` on timeout.
+pub fn wait_for<F>(timeout: Duration, mut condition: F) -> bool where F: FnMut() -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if condition() {
+            return true;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    false
+}
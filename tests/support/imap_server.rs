@@ -0,0 +1,1274 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+
+use super::mime::{base64, parse_part, Part};
+
+/// A mail in a mailbox of the fake server.
+#[derive(Debug,Clone)]
+pub struct Message {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub modseq: u64,
+    pub internal_date: String,
+    pub raw: Vec<u8>,
+}
+
+impl Message {
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Mailbox {
+    pub name: String,
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    pub highest_modseq: u64,
+    pub messages: Vec<Message>,
+    pub permanent_flags: Vec<String>,
+    pub subscribed: bool,
+}
+
+/// What to do instead of running a command, see `FakeImapServer::fail`.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Failure {
+    No,
+    Bad,
+    /// Closes the connection without answering
+    Disconnect,
+}
+
+/// The SEARCH responses of real servers differ in ways the RFC allows.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Quirk {
+    /// `* SEARCH 1 2 3 ` with a space at the end (Exchange)
+    SearchTrailingSpace,
+    /// A long result split over several `* SEARCH` lines
+    SearchSplit,
+    /// `* ESEARCH (TAG "a1") UID ALL 1:3`, even though the client did not ask for ESEARCH
+    Esearch,
+    /// `* SEARCH 1 2 3 (MODSEQ 42)` also without a MODSEQ search key (Dovecot with CONDSTORE enabled)
+    SearchModseq,
+    /// An untagged `* n EXISTS` in the middle of the SEARCH response, as if a mail just arrived (Gmail)
+    SearchUnsolicitedExists,
+    /// No PERMANENTFLAGS in the response to SELECT, which means every flag is permanent (RFC 3501, section 7.1)
+    NoPermanentFlags,
+}
+
+struct State {
+    capabilities: Vec<String>,
+    username: String,
+    password: String,
+    /// The access token accepted by AUTHENTICATE
+    access_token: Option<String>,
+    mailboxes: Vec<Mailbox>,
+    failures: Vec<(String, Failure, usize)>,
+    quirks: Vec<Quirk>,
+    log: Vec<String>,
+    connections: usize,
+    /// The common names of the client certificates of the TLS connections
+    client_certificates: Vec<String>,
+}
+
+impl State {
+    fn mailbox(&mut self, name: &str) -> Option<&mut Mailbox> {
+        self.mailboxes.iter_mut().find(|m| m.name == name || (m.name.eq_ignore_ascii_case("INBOX") && name.eq_ignore_ascii_case("INBOX")))
+    }
+}
+
+/// A scriptable IMAP server on localhost. Every connection is handled in a thread of its own,
+/// and all of them share the mailboxes, so the tests can add mail and look at the flags at any time.
+///
+/// Only what imap2slack uses is implemented: LOGIN, AUTHENTICATE (XOAUTH2 and OAUTHBEARER), CAPABILITY, ENABLE, LIST/LSUB, STATUS, SELECT/EXAMINE,
+/// CLOSE/UNSELECT, UID SEARCH/FETCH/STORE/COPY/MOVE/EXPUNGE, EXPUNGE, IDLE, NOOP and LOGOUT.
+pub struct FakeImapServer {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+pub static DEFAULT_CAPABILITIES: &'static [&'static str] = &["IMAP4rev1", "LITERAL+", "IDLE", "UIDPLUS", "MOVE", "ENABLE", "UNSELECT"];
+
+impl FakeImapServer {
+    pub fn start() -> FakeImapServer {
+        FakeImapServer::start_with(None)
+    }
+
+    /// Same as `start`, but every connection starts with TLS, with the certificate `tests/fixtures/server.pem`
+    /// signed by `tests/fixtures/ca.pem`. With `require_client_certificate` the client has to show a certificate
+    /// signed by the same CA.
+    pub fn start_tls(require_client_certificate: bool) -> FakeImapServer {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        builder.set_private_key_file(fixture("server.key"), SslFiletype::PEM).unwrap();
+        builder.set_certificate_chain_file(fixture("server.pem")).unwrap();
+        if require_client_certificate {
+            builder.set_ca_file(fixture("ca.pem")).unwrap();
+            builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        FakeImapServer::start_with(Some(builder.build()))
+    }
+
+    fn start_with(acceptor: Option<SslAcceptor>) -> FakeImapServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the fake IMAP server");
+        let port = listener.local_addr().unwrap().port();
+
+        let state = Arc::new(Mutex::new(State {
+            capabilities: DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            username: "user".to_string(),
+            password: "secret".to_string(),
+            access_token: None,
+            mailboxes: Vec::new(),
+            failures: Vec::new(),
+            quirks: Vec::new(),
+            log: Vec::new(),
+            connections: 0,
+            client_certificates: Vec::new(),
+        }));
+
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                shared.lock().unwrap().connections += 1;
+
+                let state = shared.clone();
+                let acceptor = acceptor.clone();
+                thread::spawn(move || {
+                    let stream = match acceptor {
+                        Some(acceptor) => match acceptor.accept(stream) {
+                            Ok(stream) => Stream::Tls(stream),
+                            Err(_) => return,
+                        },
+                        None => Stream::Plain(stream),
+                    };
+                    if let Some(name) = stream.client_certificate() {
+                        state.lock().unwrap().client_certificates.push(name);
+                    }
+                    let _ = Connection::new(stream, state).run();
+                });
+            }
+        });
+
+        FakeImapServer { port: port, state: state }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_capabilities(&self, capabilities: &[&str]) {
+        self.state.lock().unwrap().capabilities = capabilities.iter().map(|c| c.to_string()).collect();
+    }
+
+    pub fn add_capability(&self, capability: &str) {
+        self.state.lock().unwrap().capabilities.push(capability.to_string());
+    }
+
+    /// Accepts `token` in AUTHENTICATE, which needs the `AUTH=XOAUTH2` or `AUTH=OAUTHBEARER` capability too.
+    pub fn set_access_token(&self, token: &str) {
+        self.state.lock().unwrap().access_token = Some(token.to_string());
+    }
+
+    pub fn add_mailbox(&self, name: &str) {
+        self.state.lock().unwrap().mailboxes.push(Mailbox {
+            name: name.to_string(),
+            uid_validity: 1,
+            uid_next: 1,
+            highest_modseq: 1,
+            messages: Vec::new(),
+            permanent_flags: vec!["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft", "\\*"].into_iter().map(|f| f.to_string()).collect(),
+            subscribed: true,
+        });
+    }
+
+    pub fn set_subscribed(&self, mailbox: &str, subscribed: bool) {
+        self.state.lock().unwrap().mailbox(mailbox).expect("No such mailbox").subscribed = subscribed;
+    }
+
+    pub fn set_permanent_flags(&self, mailbox: &str, flags: &[&str]) {
+        self.state.lock().unwrap().mailbox(mailbox).expect("No such mailbox").permanent_flags = flags.iter().map(|f| f.to_string()).collect();
+    }
+
+    /// Rebuilds the mailbox: a new UIDVALIDITY and new UIDs for all the mails.
+    pub fn rebuild(&self, mailbox: &str) {
+        let mut state = self.state.lock().unwrap();
+        let mailbox = state.mailbox(mailbox).expect("No such mailbox");
+        mailbox.uid_validity += 1;
+        mailbox.uid_next = 1;
+        for message in mailbox.messages.iter_mut() {
+            message.uid = mailbox.uid_next;
+            mailbox.uid_next += 1;
+        }
+    }
+
+    /// Adds a mail and returns its UID.
+    pub fn append(&self, mailbox: &str, raw: &[u8], flags: &[&str]) -> u32 {
+        self.append_with_date(mailbox, raw, flags, "01-Jan-2020 00:00:00 +0000")
+    }
+
+    /// Adds a mail with an INTERNALDATE like `17-Jul-1996 02:44:25 -0700` and returns its UID.
+    pub fn append_with_date(&self, mailbox: &str, raw: &[u8], flags: &[&str], internal_date: &str) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let mailbox = state.mailbox(mailbox).expect("No such mailbox");
+
+        let uid = mailbox.uid_next;
+        mailbox.uid_next += 1;
+        mailbox.highest_modseq += 1;
+        mailbox.messages.push(Message {
+            uid: uid,
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+            modseq: mailbox.highest_modseq,
+            internal_date: internal_date.to_string(),
+            raw: raw.to_vec(),
+        });
+        uid
+    }
+
+    pub fn uids(&self, mailbox: &str) -> Vec<u32> {
+        self.state.lock().unwrap().mailbox(mailbox).expect("No such mailbox").messages.iter().map(|m| m.uid).collect()
+    }
+
+    pub fn flags(&self, mailbox: &str, uid: u32) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mailbox = state.mailbox(mailbox).expect("No such mailbox");
+        mailbox.messages.iter().find(|m| m.uid == uid).expect("No such mail").flags.clone()
+    }
+
+    /// Adds flags to a mail like another client would, which also raises the HIGHESTMODSEQ.
+    pub fn add_flags(&self, mailbox: &str, uid: u32, flags: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        let mailbox = state.mailbox(mailbox).expect("No such mailbox");
+        set_flags(mailbox, uid, &flags.iter().map(|f| f.to_string()).collect::<Vec<String>>(), '+');
+    }
+
+    /// The next `times` commands starting with `command` (e.g. `SELECT` or `UID FETCH`) fail.
+    pub fn fail(&self, command: &str, failure: Failure, times: usize) {
+        self.state.lock().unwrap().failures.push((command.to_uppercase(), failure, times));
+    }
+
+    pub fn quirk(&self, quirk: Quirk) {
+        self.state.lock().unwrap().quirks.push(quirk);
+    }
+
+    /// Every command received so far, without the tag.
+    pub fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().log.clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// The common names of the client certificates, one for every TLS connection which had one.
+    pub fn client_certificates(&self) -> Vec<String> {
+        self.state.lock().unwrap().client_certificates.clone()
+    }
+}
+
+/// The path of a file in `tests/fixtures`.
+pub fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// The socket of a connection, with or without TLS.
+enum Stream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            &Stream::Plain(ref stream) => stream,
+            &Stream::Tls(ref stream) => stream.get_ref(),
+        }
+    }
+
+    fn client_certificate(&self) -> Option<String> {
+        let certificate = match self {
+            &Stream::Tls(ref stream) => stream.ssl().peer_certificate()?,
+            &Stream::Plain(_) => return None,
+        };
+        let name = certificate.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+        name.data().as_utf8().ok().map(|name| name.to_string())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut Stream::Plain(ref mut stream) => stream.read(buf),
+            &mut Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut Stream::Plain(ref mut stream) => stream.write(buf),
+            &mut Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut Stream::Plain(ref mut stream) => stream.flush(),
+            &mut Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// A token of a command: an atom, a string (quoted or literal) or a parenthesized list.
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Atom(String),
+    Str(String),
+    List(Vec<Token>),
+}
+
+impl Token {
+    fn text(&self) -> String {
+        match self {
+            &Token::Atom(ref s) | &Token::Str(ref s) => s.clone(),
+            &Token::List(_) => String::new(),
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<Stream>,
+    state: Arc<Mutex<State>>,
+    authenticated: bool,
+    selected: Option<String>,
+    /// Selected with EXAMINE
+    read_only: bool,
+    condstore: bool,
+}
+
+impl Connection {
+    fn new(stream: Stream, state: Arc<Mutex<State>>) -> Connection {
+        Connection {
+            reader: BufReader::new(stream),
+            state: state,
+            authenticated: false,
+            selected: None,
+            read_only: false,
+            condstore: false,
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        self.send_bytes(data.as_bytes())
+    }
+
+    fn send_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        stream.write_all(data)?;
+        stream.flush()
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.send("* OK [CAPABILITY IMAP4rev1] Fake IMAP server ready\r\n")?;
+
+        loop {
+            let line = match self.read_command()? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+
+            let tokens = tokenize(&line);
+            let tag = match tokens.first() {
+                Some(&Token::Atom(ref tag)) => tag.clone(),
+                _ => {
+                    self.send("* BAD Missing tag\r\n")?;
+                    continue;
+                }
+            };
+            let mut args: Vec<Token> = tokens[1..].to_vec();
+            let mut command = args.first().map(|t| t.text().to_uppercase()).unwrap_or(String::new());
+            args = args.into_iter().skip(1).collect();
+            if command == "UID" && !args.is_empty() {
+                command = format!("UID {}", args[0].text().to_uppercase());
+                args = args.into_iter().skip(1).collect();
+            }
+
+            let logged = String::from_utf8_lossy(&line).splitn(2, ' ').nth(1).unwrap_or("").to_string();
+            self.state.lock().unwrap().log.push(logged);
+
+            if let Some(failure) = self.injected_failure(&command) {
+                match failure {
+                    Failure::No => self.send(&format!("{} NO [UNAVAILABLE] Injected failure\r\n", tag))?,
+                    Failure::Bad => self.send(&format!("{} BAD Injected failure\r\n", tag))?,
+                    Failure::Disconnect => {
+                        let _ = self.reader.get_ref().tcp().shutdown(Shutdown::Both);
+                        return Ok(());
+                    },
+                }
+                continue;
+            }
+
+            let done = self.handle(&tag, &command, &args)?;
+            if done {
+                let _ = self.reader.get_ref().tcp().shutdown(Shutdown::Both);
+                return Ok(());
+            }
+        }
+    }
+
+    fn injected_failure(&mut self, command: &str) -> Option<Failure> {
+        let mut state = self.state.lock().unwrap();
+        for entry in state.failures.iter_mut() {
+            if entry.2 > 0 && command.starts_with(&entry.0) {
+                entry.2 -= 1;
+                return Some(entry.1);
+            }
+        }
+        None
+    }
+
+    /// Reads a command, including its literals. `{n+}` is read right away, `{n}` after a continuation.
+    fn read_command(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut command: Vec<u8> = Vec::new();
+        loop {
+            let mut line: Vec<u8> = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(None);
+            }
+            command.extend_from_slice(&line);
+
+            let trimmed = String::from_utf8_lossy(&line).trim_end().to_string();
+            if !trimmed.ends_with('}') {
+                break;
+            }
+            let open = match trimmed.rfind('{') {
+                Some(open) => open,
+                None => break,
+            };
+            let size = &trimmed[open + 1..trimmed.len() - 1];
+            let (size, sync) = if size.ends_with('+') { (&size[..size.len() - 1], false) } else { (size, true) };
+            let size: usize = match size.parse() {
+                Ok(size) => size,
+                Err(_) => break,
+            };
+
+            if sync {
+                self.send("+ Ready for literal data\r\n")?;
+            }
+            let mut literal = vec![0u8; size];
+            self.reader.read_exact(&mut literal)?;
+            command.extend_from_slice(&literal);
+        }
+
+        while command.ends_with(b"\n") || command.ends_with(b"\r") {
+            command.pop();
+        }
+        Ok(Some(command))
+    }
+
+    /// Runs a command and returns `true` if the connection should be closed.
+    fn handle(&mut self, tag: &str, command: &str, args: &[Token]) -> io::Result<bool> {
+        match command {
+            "CAPABILITY" => {
+                let capabilities = self.state.lock().unwrap().capabilities.join(" ");
+                self.send(&format!("* CAPABILITY {}\r\n{} OK CAPABILITY completed\r\n", capabilities, tag))?;
+            },
+            "NOOP" | "CHECK" => self.send(&format!("{} OK {} completed\r\n", tag, command))?,
+            "LOGOUT" => {
+                self.send(&format!("* BYE Logging out\r\n{} OK LOGOUT completed\r\n", tag))?;
+                return Ok(true);
+            },
+            "LOGIN" => {
+                let (username, password) = {
+                    let state = self.state.lock().unwrap();
+                    (state.username.clone(), state.password.clone())
+                };
+                if args.len() == 2 && args[0].text() == username && args[1].text() == password {
+                    self.authenticated = true;
+                    self.send(&format!("{} OK LOGIN completed\r\n", tag))?;
+                } else {
+                    self.send(&format!("{} NO [AUTHENTICATIONFAILED] Invalid credentials\r\n", tag))?;
+                }
+            },
+            "AUTHENTICATE" => self.authenticate(tag, args)?,
+            _ if !self.authenticated => self.send(&format!("{} BAD Log in first\r\n", tag))?,
+            "ENABLE" => {
+                let has_condstore = self.has_capability("CONDSTORE") || self.has_capability("QRESYNC");
+                let mut enabled: Vec<String> = Vec::new();
+                for arg in args {
+                    let name = arg.text().to_uppercase();
+                    if name == "CONDSTORE" && has_condstore {
+                        self.condstore = true;
+                        enabled.push(name);
+                    }
+                }
+                self.send(&format!("* ENABLED {}\r\n{} OK ENABLE completed\r\n", enabled.join(" "), tag))?;
+            },
+            "LIST" | "LSUB" => self.list(tag, command, args)?,
+            "STATUS" => self.status(tag, args)?,
+            "SELECT" | "EXAMINE" => self.select(tag, command, args)?,
+            "CLOSE" | "UNSELECT" => {
+                // CLOSE silently expunges a mailbox selected read-write (RFC 3501, section 6.4.2)
+                if command == "CLOSE" && self.selected.is_some() && !self.read_only {
+                    self.remove_deleted(None);
+                }
+                self.selected = None;
+                self.send(&format!("{} OK {} completed\r\n", tag, command))?;
+            },
+            "IDLE" => self.idle(tag)?,
+            _ if self.selected.is_none() => self.send(&format!("{} BAD No mailbox selected\r\n", tag))?,
+            "UID SEARCH" => self.search(tag, args)?,
+            "UID FETCH" => self.fetch(tag, args)?,
+            "UID STORE" => self.store(tag, args)?,
+            "UID COPY" => self.copy(tag, args, false)?,
+            "UID MOVE" => self.copy(tag, args, true)?,
+            "UID EXPUNGE" => {
+                let set = args.first().map(|t| t.text()).unwrap_or(String::new());
+                self.expunge(tag, Some(&set))?;
+            },
+            "EXPUNGE" => self.expunge(tag, None)?,
+            _ => self.send(&format!("{} BAD Unknown command {}\r\n", tag, command))?,
+        }
+        Ok(false)
+    }
+
+    /// SASL XOAUTH2 or OAUTHBEARER (RFC 7628): the client sends its initial response after an empty challenge.
+    /// A wrong token gets a challenge with the error details, which the client answers with an empty line.
+    fn authenticate(&mut self, tag: &str, args: &[Token]) -> io::Result<()> {
+        let mechanism = args.first().map(|t| t.text().to_uppercase()).unwrap_or(String::new());
+        if !self.has_capability(&format!("AUTH={}", mechanism)) {
+            return self.send(&format!("{} NO Unsupported authentication mechanism\r\n", tag));
+        }
+
+        self.send("+ \r\n")?;
+        let response = self.read_line()?;
+
+        let expected = {
+            let state = self.state.lock().unwrap();
+            state.access_token.as_ref().map(|token| match mechanism.as_str() {
+                "OAUTHBEARER" => format!("n,a={},\x01auth=Bearer {}\x01\x01", state.username, token),
+                _ => format!("user={}\x01auth=Bearer {}\x01\x01", state.username, token),
+            })
+        };
+        if expected.map_or(false, |expected| response == base64(expected.as_bytes())) {
+            self.authenticated = true;
+            return self.send(&format!("{} OK AUTHENTICATE completed\r\n", tag));
+        }
+
+        self.send(&format!("+ {}\r\n", base64(br#"{"status":"401","schemes":"bearer"}"#)))?;
+        self.read_line()?;
+        self.send(&format!("{} NO [AUTHENTICATIONFAILED] Invalid token\r\n", tag))
+    }
+
+    /// A line of a SASL exchange, without the CRLF.
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line: Vec<u8> = Vec::new();
+        self.reader.read_until(b'\n', &mut line)?;
+        Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+    }
+
+    fn has_capability(&self, capability: &str) -> bool {
+        self.state.lock().unwrap().capabilities.iter().any(|c| c.eq_ignore_ascii_case(capability))
+    }
+
+    fn has_quirk(&self, quirk: Quirk) -> bool {
+        self.state.lock().unwrap().quirks.contains(&quirk)
+    }
+
+    fn list(&mut self, tag: &str, command: &str, args: &[Token]) -> io::Result<()> {
+        let reference = args.get(0).map(|t| t.text()).unwrap_or(String::new());
+        let pattern = args.get(1).map(|t| t.text()).unwrap_or(String::new());
+
+        let mut response = String::new();
+        if pattern.is_empty() {
+            response.push_str(&format!("* {} (\\Noselect) \"/\" \"\"\r\n", command));
+        } else {
+            let pattern = format!("{}{}", reference, pattern);
+            let mailboxes = self.state.lock().unwrap().mailboxes.clone();
+            for mailbox in &mailboxes {
+                if command == "LSUB" && !mailbox.subscribed {
+                    continue;
+                }
+                if wildcard_match(pattern.as_bytes(), mailbox.name.as_bytes()) {
+                    response.push_str(&format!("* {} (\\HasNoChildren) \"/\" {}\r\n", command, quote(&mailbox.name)));
+                }
+            }
+        }
+        response.push_str(&format!("{} OK {} completed\r\n", tag, command));
+        self.send(&response)
+    }
+
+    fn status(&mut self, tag: &str, args: &[Token]) -> io::Result<()> {
+        let name = args.get(0).map(|t| t.text()).unwrap_or(String::new());
+        let items: Vec<String> = match args.get(1) {
+            Some(&Token::List(ref items)) => items.iter().map(|t| t.text().to_uppercase()).collect(),
+            _ => return self.send(&format!("{} BAD Missing status items\r\n", tag)),
+        };
+
+        if items.iter().any(|i| i == "HIGHESTMODSEQ") {
+            if !self.has_capability("CONDSTORE") && !self.has_capability("QRESYNC") {
+                return self.send(&format!("{} BAD Unknown status item HIGHESTMODSEQ\r\n", tag));
+            }
+            self.condstore = true;
+        }
+
+        let mailbox = self.state.lock().unwrap().mailbox(&name).cloned();
+        let mailbox = match mailbox {
+            Some(mailbox) => mailbox,
+            None => return self.send(&format!("{} NO [NONEXISTENT] Mailbox does not exist\r\n", tag)),
+        };
+        // Real servers mostly answer anyway, but RFC 3501, section 6.3.10 does not allow it
+        if self.selected.as_ref() == Some(&mailbox.name) {
+            return self.send(&format!("{} BAD STATUS on the selected mailbox\r\n", tag));
+        }
+
+        let mut values: Vec<String> = Vec::new();
+        for item in &items {
+            let value = match item.as_ref() {
+                "MESSAGES" => mailbox.messages.len() as u64,
+                "RECENT" => 0,
+                "UNSEEN" => mailbox.messages.iter().filter(|m| !m.has_flag("\\Seen")).count() as u64,
+                "UIDNEXT" => mailbox.uid_next as u64,
+                "UIDVALIDITY" => mailbox.uid_validity as u64,
+                "HIGHESTMODSEQ" => mailbox.highest_modseq,
+                _ => return self.send(&format!("{} BAD Unknown status item {}\r\n", tag, item)),
+            };
+            values.push(format!("{} {}", item, value));
+        }
+
+        self.send(&format!("* STATUS {} ({})\r\n{} OK STATUS completed\r\n", quote(&mailbox.name), values.join(" "), tag))
+    }
+
+    fn select(&mut self, tag: &str, command: &str, args: &[Token]) -> io::Result<()> {
+        let name = args.get(0).map(|t| t.text()).unwrap_or(String::new());
+        let mailbox = self.state.lock().unwrap().mailbox(&name).cloned();
+        let mailbox = match mailbox {
+            Some(mailbox) => mailbox,
+            None => {
+                self.selected = None;
+                return self.send(&format!("{} NO [NONEXISTENT] Mailbox does not exist\r\n", tag));
+            },
+        };
+
+        let mut response = String::new();
+        response.push_str("* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n");
+        if !self.has_quirk(Quirk::NoPermanentFlags) {
+            response.push_str(&format!("* OK [PERMANENTFLAGS ({})] Flags permitted\r\n", mailbox.permanent_flags.join(" ")));
+        }
+        response.push_str(&format!("* {} EXISTS\r\n", mailbox.messages.len()));
+        response.push_str("* 0 RECENT\r\n");
+        response.push_str(&format!("* OK [UIDVALIDITY {}] UIDs valid\r\n", mailbox.uid_validity));
+        response.push_str(&format!("* OK [UIDNEXT {}] Predicted next UID\r\n", mailbox.uid_next));
+        if self.condstore {
+            response.push_str(&format!("* OK [HIGHESTMODSEQ {}] Highest\r\n", mailbox.highest_modseq));
+        }
+        self.read_only = command == "EXAMINE";
+        response.push_str(&format!("{} OK [{}] {} completed\r\n", tag, if self.read_only { "READ-ONLY" } else { "READ-WRITE" }, command));
+
+        self.selected = Some(mailbox.name.clone());
+        self.send(&response)
+    }
+
+    /// Waits for DONE, and sends `* n EXISTS` when mail is added in the mean time.
+    fn idle(&mut self, tag: &str) -> io::Result<()> {
+        self.send("+ idling\r\n")?;
+
+        let mut exists = self.exists();
+        self.reader.get_ref().tcp().set_read_timeout(Some(Duration::from_millis(50)))?;
+
+        let mut line: Vec<u8> = Vec::new();
+        let result = loop {
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => break Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed while idling")),
+                Ok(_) => {
+                    if String::from_utf8_lossy(&line).trim().eq_ignore_ascii_case("DONE") {
+                        break Ok(());
+                    }
+                    line.clear();
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    let now = self.exists();
+                    if now != exists {
+                        exists = now;
+                        self.send(&format!("* {} EXISTS\r\n", now))?;
+                    }
+                },
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.reader.get_ref().tcp().set_read_timeout(None)?;
+        result?;
+        self.send(&format!("{} OK IDLE terminated\r\n", tag))
+    }
+
+    fn exists(&self) -> usize {
+        let name = self.selected.clone().unwrap_or(String::new());
+        self.state.lock().unwrap().mailbox(&name).map(|m| m.messages.len()).unwrap_or(0)
+    }
+
+    fn selected_mailbox(&self) -> Mailbox {
+        let name = self.selected.clone().unwrap();
+        self.state.lock().unwrap().mailbox(&name).expect("The selected mailbox was deleted").clone()
+    }
+
+    fn search(&mut self, tag: &str, args: &[Token]) -> io::Result<()> {
+        let mut args = args;
+        if args.len() >= 2 && args[0].text().eq_ignore_ascii_case("CHARSET") {
+            if !args[1].text().eq_ignore_ascii_case("UTF-8") && !args[1].text().eq_ignore_ascii_case("US-ASCII") {
+                return self.send(&format!("{} NO [BADCHARSET (UTF-8 US-ASCII)] Unsupported charset\r\n", tag));
+            }
+            args = &args[2..];
+        }
+
+        let mut pos = 0;
+        let mut keys: Vec<Key> = Vec::new();
+        while pos < args.len() {
+            match parse_key(args, &mut pos) {
+                Ok(key) => keys.push(key),
+                Err(e) => return self.send(&format!("{} BAD {}\r\n", tag, e)),
+            }
+        }
+        let key = Key::And(keys);
+
+        if key.uses_modseq() {
+            if !self.has_capability("CONDSTORE") && !self.has_capability("QRESYNC") {
+                return self.send(&format!("{} BAD Unknown search key MODSEQ\r\n", tag));
+            }
+            self.condstore = true;
+        }
+
+        let mailbox = self.selected_mailbox();
+        let max_uid = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+        let found: Vec<&Message> = mailbox.messages.iter().enumerate()
+            .filter(|&(i, m)| key.matches(m, i as u32 + 1, max_uid))
+            .map(|(_, m)| m)
+            .collect();
+        let uids: Vec<String> = found.iter().map(|m| m.uid.to_string()).collect();
+        let modseq = found.iter().map(|m| m.modseq).max();
+
+        let mut response = String::new();
+        if self.has_quirk(Quirk::SearchUnsolicitedExists) {
+            response.push_str(&format!("* {} EXISTS\r\n", mailbox.messages.len()));
+        }
+
+        let trailer = match modseq {
+            Some(modseq) if key.uses_modseq() || (self.condstore && self.has_quirk(Quirk::SearchModseq)) => format!(" (MODSEQ {})", modseq),
+            _ => String::new(),
+        };
+
+        if self.has_quirk(Quirk::Esearch) {
+            response.push_str(&format!("* ESEARCH (TAG \"{}\") UID", tag));
+            if !uids.is_empty() {
+                response.push_str(&format!(" ALL {}", uids.join(",")));
+            }
+            if let Some(modseq) = modseq.filter(|_| !trailer.is_empty()) {
+                response.push_str(&format!(" MODSEQ {}", modseq));
+            }
+            response.push_str("\r\n");
+        } else if self.has_quirk(Quirk::SearchSplit) && uids.len() > 1 {
+            for chunk in uids.chunks(2) {
+                response.push_str(&format!("* SEARCH {}\r\n", chunk.join(" ")));
+            }
+        } else {
+            let space = if self.has_quirk(Quirk::SearchTrailingSpace) { " " } else { "" };
+            if uids.is_empty() {
+                response.push_str(&format!("* SEARCH{}\r\n", space));
+            } else {
+                response.push_str(&format!("* SEARCH {}{}{}\r\n", uids.join(" "), trailer, space));
+            }
+        }
+
+        response.push_str(&format!("{} OK SEARCH completed (0.001 + 0.000 secs)\r\n", tag));
+        self.send(&response)
+    }
+
+    fn fetch(&mut self, tag: &str, args: &[Token]) -> io::Result<()> {
+        let set = args.get(0).map(|t| t.text()).unwrap_or(String::new());
+        let items: Vec<String> = match args.get(1) {
+            Some(&Token::List(ref items)) => items.iter().map(|t| t.text().to_uppercase()).collect(),
+            Some(item) => vec![item.text().to_uppercase()],
+            None => return self.send(&format!("{} BAD Missing fetch items\r\n", tag)),
+        };
+
+        let mailbox = self.selected_mailbox();
+        let max_uid = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+
+        let mut response: Vec<u8> = Vec::new();
+        let mut seen: Vec<u32> = Vec::new();
+        for (i, message) in mailbox.messages.iter().enumerate() {
+            if !in_set(&set, message.uid, max_uid) {
+                continue;
+            }
+
+            let part = parse_part(&message.raw);
+            let mut data: Vec<u8> = format!("UID {}", message.uid).into_bytes();
+            for item in &items {
+                match item.as_ref() {
+                    "UID" => (),
+                    "FLAGS" => data.extend_from_slice(format!(" FLAGS ({})", message.flags.join(" ")).as_bytes()),
+                    "INTERNALDATE" => data.extend_from_slice(format!(" INTERNALDATE \"{}\"", message.internal_date).as_bytes()),
+                    "RFC822.SIZE" => data.extend_from_slice(format!(" RFC822.SIZE {}", message.raw.len()).as_bytes()),
+                    "MODSEQ" => data.extend_from_slice(format!(" MODSEQ ({})", message.modseq).as_bytes()),
+                    "BODYSTRUCTURE" => data.extend_from_slice(format!(" BODYSTRUCTURE {}", part.bodystructure()).as_bytes()),
+                    "RFC822" => {
+                        seen.push(message.uid);
+                        data.extend_from_slice(format!(" RFC822 {{{}}}\r\n", message.raw.len()).as_bytes());
+                        data.extend_from_slice(&message.raw);
+                    },
+                    item if item.starts_with("BODY[") || item.starts_with("BODY.PEEK[") => {
+                        if item.starts_with("BODY[") {
+                            seen.push(message.uid);
+                        }
+                        let open = item.find('[').unwrap();
+                        let close = match item.find(']') {
+                            Some(close) => close,
+                            None => return self.send(&format!("{} BAD Invalid section\r\n", tag)),
+                        };
+                        let section = &item[open + 1..close];
+                        let mut content = match section_of(&part, &message.raw, section) {
+                            Some(content) => content,
+                            None => Vec::new(),
+                        };
+
+                        let mut name = format!("BODY[{}]", section);
+                        // <origin.length>
+                        let partial = &item[close + 1..];
+                        if partial.starts_with('<') && partial.ends_with('>') {
+                            let numbers: Vec<usize> = partial[1..partial.len() - 1].split('.').filter_map(|n| n.parse().ok()).collect();
+                            let origin = numbers.get(0).cloned().unwrap_or(0).min(content.len());
+                            let end = numbers.get(1).map(|length| (origin + length).min(content.len())).unwrap_or(content.len());
+                            content = content[origin..end].to_vec();
+                            name = format!("{}<{}>", name, origin);
+                        }
+
+                        data.extend_from_slice(format!(" {} {{{}}}\r\n", name, content.len()).as_bytes());
+                        data.extend_from_slice(&content);
+                    },
+                    _ => return self.send(&format!("{} BAD Unsupported fetch item {}\r\n", tag, item)),
+                }
+            }
+
+            response.extend_from_slice(format!("* {} FETCH (", i + 1).as_bytes());
+            response.extend_from_slice(&data);
+            response.extend_from_slice(b")\r\n");
+        }
+
+        if !seen.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            let mailbox = state.mailbox(&mailbox.name).unwrap();
+            for uid in seen {
+                set_flags(mailbox, uid, &["\\Seen".to_string()], '+');
+            }
+        }
+
+        response.extend_from_slice(format!("{} OK FETCH completed\r\n", tag).as_bytes());
+        self.send_bytes(&response)
+    }
+
+    fn store(&mut self, tag: &str, args: &[Token]) -> io::Result<()> {
+        let set = args.get(0).map(|t| t.text()).unwrap_or(String::new());
+        let operation = args.get(1).map(|t| t.text().to_uppercase()).unwrap_or(String::new());
+        let flags: Vec<String> = match args.get(2) {
+            Some(&Token::List(ref flags)) => flags.iter().map(|t| t.text()).collect(),
+            Some(flag) => vec![flag.text()],
+            None => return self.send(&format!("{} BAD Missing flags\r\n", tag)),
+        };
+
+        let silent = operation.ends_with(".SILENT");
+        let mode = match operation.trim_end_matches(".SILENT") {
+            "+FLAGS" => '+',
+            "-FLAGS" => '-',
+            "FLAGS" => '=',
+            _ => return self.send(&format!("{} BAD Invalid store operation\r\n", tag)),
+        };
+
+        let name = self.selected.clone().unwrap();
+        let permanent: Vec<String> = self.selected_mailbox().permanent_flags;
+        // Without PERMANENTFLAGS every keyword is permanent as well
+        let new_keywords = permanent.iter().any(|f| f == "\\*") || self.has_quirk(Quirk::NoPermanentFlags);
+        if let Some(flag) = flags.iter().find(|f| !permanent.iter().any(|p| p.eq_ignore_ascii_case(f)) && (f.starts_with('\\') || !new_keywords)) {
+            return self.send(&format!("{} NO Flag {} is not permitted\r\n", tag, flag));
+        }
+
+        let mut response = String::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let mailbox = state.mailbox(&name).unwrap();
+            let max_uid = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+            let uids: Vec<u32> = mailbox.messages.iter().filter(|m| in_set(&set, m.uid, max_uid)).map(|m| m.uid).collect();
+            for uid in uids {
+                set_flags(mailbox, uid, &flags, mode);
+                if !silent {
+                    let (i, message) = mailbox.messages.iter().enumerate().find(|&(_, m)| m.uid == uid).unwrap();
+                    response.push_str(&format!("* {} FETCH (UID {} FLAGS ({}))\r\n", i + 1, uid, message.flags.join(" ")));
+                }
+            }
+        }
+
+        response.push_str(&format!("{} OK STORE completed\r\n", tag));
+        self.send(&response)
+    }
+
+    fn copy(&mut self, tag: &str, args: &[Token], remove: bool) -> io::Result<()> {
+        let set = args.get(0).map(|t| t.text()).unwrap_or(String::new());
+        let target = args.get(1).map(|t| t.text()).unwrap_or(String::new());
+        let name = self.selected.clone().unwrap();
+
+        if self.state.lock().unwrap().mailbox(&target).is_none() {
+            return self.send(&format!("{} NO [TRYCREATE] Mailbox does not exist\r\n", tag));
+        }
+
+        let mut response = String::new();
+        {
+            let mut state = self.state.lock().unwrap();
+
+            let source = state.mailbox(&name).unwrap().clone();
+            let max_uid = source.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+            let messages: Vec<Message> = source.messages.iter().filter(|m| in_set(&set, m.uid, max_uid)).cloned().collect();
+
+            let mut new_uids: Vec<String> = Vec::new();
+            {
+                let target = state.mailbox(&target).unwrap();
+                for message in &messages {
+                    target.highest_modseq += 1;
+                    new_uids.push(target.uid_next.to_string());
+                    target.messages.push(Message { uid: target.uid_next, modseq: target.highest_modseq, ..message.clone() });
+                    target.uid_next += 1;
+                }
+            }
+
+            if remove {
+                let source = state.mailbox(&name).unwrap();
+                for message in &messages {
+                    if let Some(i) = source.messages.iter().position(|m| m.uid == message.uid) {
+                        source.messages.remove(i);
+                        response.push_str(&format!("* {} EXPUNGE\r\n", i + 1));
+                    }
+                }
+                source.highest_modseq += 1;
+            }
+
+            let old_uids: Vec<String> = messages.iter().map(|m| m.uid.to_string()).collect();
+            let uid_validity = state.mailbox(&target).unwrap().uid_validity;
+            response.push_str(&format!("{} OK [COPYUID {} {} {}] Done\r\n", tag, uid_validity, old_uids.join(","), new_uids.join(",")));
+        }
+
+        self.send(&response)
+    }
+
+    fn expunge(&mut self, tag: &str, set: Option<&str>) -> io::Result<()> {
+        let mut response = self.remove_deleted(set);
+        response.push_str(&format!("{} OK EXPUNGE completed\r\n", tag));
+        self.send(&response)
+    }
+
+    /// Removes the mails marked as `\Deleted` (only those in `set`, if given) and returns the `* n EXPUNGE` responses.
+    fn remove_deleted(&mut self, set: Option<&str>) -> String {
+        let name = self.selected.clone().unwrap();
+        let mut response = String::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let mailbox = state.mailbox(&name).unwrap();
+            let max_uid = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+
+            let mut i = 0;
+            while i < mailbox.messages.len() {
+                let message = &mailbox.messages[i];
+                if message.has_flag("\\Deleted") && set.map(|set| in_set(set, message.uid, max_uid)).unwrap_or(true) {
+                    mailbox.messages.remove(i);
+                    response.push_str(&format!("* {} EXPUNGE\r\n", i + 1));
+                } else {
+                    i += 1;
+                }
+            }
+            mailbox.highest_modseq += 1;
+        }
+        response
+    }
+}
+
+fn set_flags(mailbox: &mut Mailbox, uid: u32, flags: &[String], mode: char) {
+    let modseq = mailbox.highest_modseq + 1;
+    let message = match mailbox.messages.iter_mut().find(|m| m.uid == uid) {
+        Some(message) => message,
+        None => return,
+    };
+
+    let before = message.flags.clone();
+    match mode {
+        '+' => {
+            for flag in flags {
+                if !message.has_flag(flag) {
+                    message.flags.push(flag.clone());
+                }
+            }
+        },
+        '-' => message.flags.retain(|f| !flags.iter().any(|flag| flag.eq_ignore_ascii_case(f))),
+        _ => message.flags = flags.to_vec(),
+    }
+
+    if message.flags != before {
+        message.modseq = modseq;
+        mailbox.highest_modseq = modseq;
+    }
+}
+
+/// The content of `BODY[section]`: `HEADER`, `TEXT`, the whole mail for an empty section, or a part number like `1.2`.
+fn section_of(part: &Part, raw: &[u8], section: &str) -> Option<Vec<u8>> {
+    match section {
+        "" => Some(raw.to_vec()),
+        "HEADER" => Some(part.header.clone()),
+        "TEXT" => Some(part.body.clone()),
+        _ => {
+            let mut part = part;
+            for (i, number) in section.split('.').enumerate() {
+                let number: usize = number.parse().ok()?;
+                if part.children.is_empty() {
+                    // The body of a part which is not multipart is part 1 of itself
+                    if number != 1 || i + 1 != section.split('.').count() {
+                        return None;
+                    }
+                } else {
+                    part = part.children.get(number.checked_sub(1)?)?;
+                }
+            }
+            Some(part.body.clone())
+        },
+    }
+}
+
+/// `1:4,7,9:*` (RFC 3501, section 9, sequence-set)
+fn in_set(set: &str, uid: u32, max: u32) -> bool {
+    let number = |s: &str| if s == "*" { Some(max) } else { s.parse::<u32>().ok() };
+
+    set.split(',').any(|range| {
+        let mut ends = range.splitn(2, ':');
+        let start = ends.next().and_then(number);
+        let end = ends.next().map(number).unwrap_or(start);
+        match (start, end) {
+            (Some(start), Some(end)) => uid >= start.min(end) && uid <= start.max(end),
+            _ => false,
+        }
+    })
+}
+
+/// LIST patterns: `*` matches anything, `%` anything but the hierarchy delimiter.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') => (0..name.len() + 1).any(|i| wildcard_match(&pattern[1..], &name[i..])),
+        Some(&b'%') => (0..name.len() + 1).take_while(|&i| i == 0 || name[i - 1] != b'/').any(|i| wildcard_match(&pattern[1..], &name[i..])),
+        Some(&c) => !name.is_empty() && (c == name[0] || (c as char).eq_ignore_ascii_case(&(name[0] as char))) && wildcard_match(&pattern[1..], &name[1..]),
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+fn tokenize(data: &[u8]) -> Vec<Token> {
+    let mut pos = 0;
+    let mut tokens: Vec<Token> = Vec::new();
+    while let Some(token) = next_token(data, &mut pos) {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn next_token(data: &[u8], pos: &mut usize) -> Option<Token> {
+    while *pos < data.len() && data[*pos] == b' ' {
+        *pos += 1;
+    }
+    if *pos >= data.len() {
+        return None;
+    }
+
+    match data[*pos] {
+        b'(' => {
+            *pos += 1;
+            let mut list: Vec<Token> = Vec::new();
+            loop {
+                while *pos < data.len() && data[*pos] == b' ' {
+                    *pos += 1;
+                }
+                if *pos >= data.len() {
+                    return Some(Token::List(list));
+                }
+                if data[*pos] == b')' {
+                    *pos += 1;
+                    return Some(Token::List(list));
+                }
+                list.push(next_token(data, pos)?);
+            }
+        },
+        b')' => {
+            *pos += 1;
+            None
+        },
+        b'"' => {
+            *pos += 1;
+            let mut s: Vec<u8> = Vec::new();
+            while *pos < data.len() && data[*pos] != b'"' {
+                if data[*pos] == b'\\' {
+                    *pos += 1;
+                }
+                if *pos < data.len() {
+                    s.push(data[*pos]);
+                }
+                *pos += 1;
+            }
+            *pos += 1;
+            Some(Token::Str(String::from_utf8_lossy(&s).into_owned()))
+        },
+        b'{' => {
+            let close = data[*pos..].iter().position(|&b| b == b'}')? + *pos;
+            let size: usize = String::from_utf8_lossy(&data[*pos + 1..close]).trim_end_matches('+').parse().ok()?;
+            // Skip `}\r\n`
+            let start = close + 3;
+            let end = (start + size).min(data.len());
+            *pos = end;
+            Some(Token::Str(String::from_utf8_lossy(&data[start.min(end)..end]).into_owned()))
+        },
+        _ => {
+            let start = *pos;
+            let mut brackets = 0;
+            while *pos < data.len() {
+                match data[*pos] {
+                    b'[' => brackets += 1,
+                    b']' => brackets -= 1,
+                    b' ' | b'(' | b')' if brackets <= 0 => break,
+                    _ => (),
+                }
+                *pos += 1;
+            }
+            Some(Token::Atom(String::from_utf8_lossy(&data[start..*pos]).into_owned()))
+        },
+    }
+}
+
+/// The search keys the fake server understands.
+#[derive(Debug)]
+enum Key {
+    All,
+    Flag(String, bool),
+    Header(String, String),
+    Body(String),
+    Text(String),
+    Larger(u64),
+    Smaller(u64),
+    Modseq(u64),
+    Uid(String),
+    Sequence(String),
+    Not(Box<Key>),
+    Or(Box<Key>, Box<Key>),
+    And(Vec<Key>),
+}
+
+impl Key {
+    fn uses_modseq(&self) -> bool {
+        match self {
+            &Key::Modseq(_) => true,
+            &Key::Not(ref key) => key.uses_modseq(),
+            &Key::Or(ref a, ref b) => a.uses_modseq() || b.uses_modseq(),
+            &Key::And(ref keys) => keys.iter().any(|k| k.uses_modseq()),
+            _ => false,
+        }
+    }
+
+    fn matches(&self, message: &Message, seq: u32, max_uid: u32) -> bool {
+        match self {
+            &Key::All => true,
+            &Key::Flag(ref flag, set) => message.has_flag(flag) == set,
+            &Key::Header(ref name, ref value) => {
+                let part = parse_part(&message.raw);
+                part.headers.iter().any(|&(ref n, ref v)| n.eq_ignore_ascii_case(name) && v.to_lowercase().contains(&value.to_lowercase()))
+            },
+            &Key::Body(ref value) => {
+                let part = parse_part(&message.raw);
+                String::from_utf8_lossy(&part.body).to_lowercase().contains(&value.to_lowercase())
+            },
+            &Key::Text(ref value) => String::from_utf8_lossy(&message.raw).to_lowercase().contains(&value.to_lowercase()),
+            &Key::Larger(n) => message.raw.len() as u64 > n,
+            &Key::Smaller(n) => (message.raw.len() as u64) < n,
+            &Key::Modseq(n) => message.modseq >= n,
+            &Key::Uid(ref set) => in_set(set, message.uid, max_uid),
+            &Key::Sequence(ref set) => in_set(set, seq, u32::max_value()),
+            &Key::Not(ref key) => !key.matches(message, seq, max_uid),
+            &Key::Or(ref a, ref b) => a.matches(message, seq, max_uid) || b.matches(message, seq, max_uid),
+            &Key::And(ref keys) => keys.iter().all(|k| k.matches(message, seq, max_uid)),
+        }
+    }
+}
+
+fn parse_key(args: &[Token], pos: &mut usize) -> Result<Key, String> {
+    let token = args.get(*pos).ok_or("Missing search key".to_string())?.clone();
+    *pos += 1;
+
+    let argument = |pos: &mut usize| -> Result<String, String> {
+        let value = args.get(*pos).map(|t| t.text()).ok_or("Missing search argument".to_string())?;
+        *pos += 1;
+        Ok(value)
+    };
+
+    let name = match token {
+        Token::List(ref keys) => {
+            let mut inner = 0;
+            let mut r: Vec<Key> = Vec::new();
+            while inner < keys.len() {
+                r.push(parse_key(keys, &mut inner)?);
+            }
+            return Ok(Key::And(r));
+        },
+        Token::Atom(ref s) | Token::Str(ref s) => s.to_uppercase(),
+    };
+
+    let flag = |flag: &str, set: bool| -> Result<Key, String> { Ok(Key::Flag(flag.to_string(), set)) };
+    match name.as_ref() {
+        "ALL" => Ok(Key::All),
+        "SEEN" => flag("\\Seen", true),
+        "UNSEEN" => flag("\\Seen", false),
+        "ANSWERED" => flag("\\Answered", true),
+        "UNANSWERED" => flag("\\Answered", false),
+        "DELETED" => flag("\\Deleted", true),
+        "UNDELETED" => flag("\\Deleted", false),
+        "DRAFT" => flag("\\Draft", true),
+        "UNDRAFT" => flag("\\Draft", false),
+        "FLAGGED" => flag("\\Flagged", true),
+        "UNFLAGGED" => flag("\\Flagged", false),
+        "KEYWORD" => Ok(Key::Flag(argument(pos)?, true)),
+        "UNKEYWORD" => Ok(Key::Flag(argument(pos)?, false)),
+        "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => Ok(Key::Header(name.clone(), argument(pos)?)),
+        "HEADER" => {
+            let header = argument(pos)?;
+            Ok(Key::Header(header, argument(pos)?))
+        },
+        "BODY" => Ok(Key::Body(argument(pos)?)),
+        "TEXT" => Ok(Key::Text(argument(pos)?)),
+        "LARGER" => argument(pos)?.parse().map(Key::Larger).map_err(|_| "Invalid number".to_string()),
+        "SMALLER" => argument(pos)?.parse().map(Key::Smaller).map_err(|_| "Invalid number".to_string()),
+        "MODSEQ" => argument(pos)?.parse().map(Key::Modseq).map_err(|_| "Invalid mod-sequence".to_string()),
+        "UID" => Ok(Key::Uid(argument(pos)?)),
+        "NOT" => Ok(Key::Not(Box::new(parse_key(args, pos)?))),
+        "OR" => {
+            let a = parse_key(args, pos)?;
+            Ok(Key::Or(Box::new(a), Box::new(parse_key(args, pos)?)))
+        },
+        // The date keys are accepted, but match every mail
+        "SINCE" | "BEFORE" | "ON" | "SENTSINCE" | "SENTBEFORE" | "SENTON" => {
+            argument(pos)?;
+            Ok(Key::All)
+        },
+        _ if name.chars().next().map(|c| c.is_ascii_digit() || c == '*').unwrap_or(false) => Ok(Key::Sequence(name.clone())),
+        _ => Err(format!("Unknown search key {}", name)),
+    }
+}
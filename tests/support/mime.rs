@@ -0,0 +1,242 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A MIME part as far as the fake server needs it: enough to answer BODYSTRUCTURE and `BODY[section]`.
+#[derive(Debug,Clone)]
+pub struct Part {
+    /// Unfolded, in the order of the mail
+    pub headers: Vec<(String, String)>,
+    /// The raw header, including the empty line after it
+    pub header: Vec<u8>,
+    /// The raw body, still transfer encoded
+    pub body: Vec<u8>,
+    pub mimetype: (String, String),
+    pub params: Vec<(String, String)>,
+    pub encoding: String,
+    pub disposition: Option<(String, Vec<(String, String)>)>,
+    pub children: Vec<Part>,
+}
+
+impl Part {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| v.as_str())
+    }
+
+    /// The BODYSTRUCTURE of the part (RFC 3501, section 7.4.2). `message/rfc822` is not supported
+    /// and described as if it was an ordinary part.
+    pub fn bodystructure(&self) -> String {
+        if !self.children.is_empty() {
+            let children: String = self.children.iter().map(|c| c.bodystructure()).collect();
+            return format!("({} {} {} NIL NIL NIL)", children, quote(&self.mimetype.1), params(&self.params));
+        }
+
+        let disposition = match &self.disposition {
+            &Some((ref kind, ref p)) => format!("({} {})", quote(kind), params(p)),
+            &None => "NIL".to_string(),
+        };
+
+        let lines = if self.mimetype.0.eq_ignore_ascii_case("text") {
+            format!(" {}", self.body.iter().filter(|&&b| b == b'\n').count())
+        } else {
+            String::new()
+        };
+
+        format!("({} {} {} NIL NIL {} {}{} NIL {} NIL NIL)",
+                quote(&self.mimetype.0), quote(&self.mimetype.1), params(&self.params), quote(&self.encoding), self.body.len(), lines, disposition)
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+fn params(params: &[(String, String)]) -> String {
+    if params.is_empty() {
+        return "NIL".to_string();
+    }
+    let list: Vec<String> = params.iter().map(|&(ref n, ref v)| format!("{} {}", quote(n), quote(v))).collect();
+    format!("({})", list.join(" "))
+}
+
+/// Splits a mail (or a part of it) into header and body, and multipart bodies into their parts.
+pub fn parse_part(raw: &[u8]) -> Part {
+    let split = find(raw, b"\r\n\r\n").map(|i| i + 4)
+        .or_else(|| find(raw, b"\n\n").map(|i| i + 2))
+        .unwrap_or(raw.len());
+    let header = raw[..split].to_vec();
+    let body = raw[split..].to_vec();
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(&header).lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+        }
+    }
+
+    let mut part = Part {
+        headers: headers,
+        header: header,
+        body: body,
+        mimetype: ("text".to_string(), "plain".to_string()),
+        params: Vec::new(),
+        encoding: "7bit".to_string(),
+        disposition: None,
+        children: Vec::new(),
+    };
+
+    if let Some(content_type) = part.header("Content-Type").map(|v| v.to_string()) {
+        let (value, p) = parse_value(&content_type);
+        let mut types = value.splitn(2, '/');
+        part.mimetype = (types.next().unwrap_or("text").to_lowercase(), types.next().unwrap_or("plain").to_lowercase());
+        part.params = p;
+    } else {
+        part.params = vec![("charset".to_string(), "us-ascii".to_string())];
+    }
+    if let Some(encoding) = part.header("Content-Transfer-Encoding").map(|v| v.trim().to_lowercase()) {
+        part.encoding = encoding;
+    }
+    if let Some(disposition) = part.header("Content-Disposition").map(|v| v.to_string()) {
+        let (value, p) = parse_value(&disposition);
+        part.disposition = Some((value.to_lowercase(), p));
+    }
+
+    if part.mimetype.0 == "multipart" {
+        let boundary = part.params.iter().find(|&&(ref n, _)| n == "boundary").map(|&(_, ref v)| v.clone());
+        if let Some(boundary) = boundary {
+            part.children = split_multipart(&part.body, &boundary).iter().map(|raw| parse_part(raw)).collect();
+        }
+    }
+
+    part
+}
+
+/// `value; name=param; name="param"`
+fn parse_value(header: &str) -> (String, Vec<(String, String)>) {
+    let mut fields = header.split(';');
+    let value = fields.next().unwrap_or("").trim().to_string();
+    let params = fields.filter_map(|field| {
+        let mut pair = field.splitn(2, '=');
+        let name = pair.next()?.trim().to_lowercase();
+        let value = pair.next()?.trim().trim_matches('"').to_string();
+        Some((name, value))
+    }).collect();
+    (value, params)
+}
+
+fn split_multipart(body: &[u8], boundary: &str) -> Vec<Vec<u8>> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts: Vec<Vec<u8>> = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+
+    for line in body.split(|&b| b == b'\n') {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end();
+        if text == delimiter || text == format!("{}--", delimiter) {
+            if let Some(mut part) = current.take() {
+                // The line break before the delimiter belongs to the delimiter
+                while part.ends_with(b"\n") || part.ends_with(b"\r") {
+                    part.pop();
+                }
+                parts.push(part);
+            }
+            if text == delimiter {
+                current = Some(Vec::new());
+            }
+            continue;
+        }
+        if let Some(ref mut part) = current {
+            part.extend_from_slice(line);
+            part.push(b'\n');
+        }
+    }
+
+    parts
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+static MESSAGE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A unique Message-ID, so the mails of one test are never taken for each other.
+pub fn message_id() -> String {
+    format!("<{}.{}@fake.example.com>", ::std::process::id(), MESSAGE_ID.fetch_add(1, Ordering::SeqCst))
+}
+
+/// A plain text mail.
+pub fn text_mail(from: &str, to: &str, subject: &str, text: &str) -> Vec<u8> {
+    format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: Wed, 1 Jan 2020 00:00:00 +0000\r\nMessage-ID: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+            from, to, subject, message_id(), text.replace("\n", "\r\n")).into_bytes()
+}
+
+/// A single part mail with a raw subject, which may be 8 bit, and a body which is already encoded,
+/// e.g. in ISO-8859-1 with `Content-Type: text/plain; charset=iso-8859-1`.
+pub fn encoded_mail(subject: &[u8], content_type: &str, transfer_encoding: &str, body: &[u8]) -> Vec<u8> {
+    let mut mail = b"From: alice@example.com\r\nTo: bob@example.com\r\nSubject: ".to_vec();
+    mail.extend_from_slice(subject);
+    mail.extend_from_slice(format!("\r\nDate: Wed, 1 Jan 2020 00:00:00 +0000\r\nMessage-ID: {}\r\nMIME-Version: 1.0\r\nContent-Type: {}\r\nContent-Transfer-Encoding: {}\r\n\r\n",
+                                   message_id(), content_type, transfer_encoding).as_bytes());
+    mail.extend_from_slice(body);
+    mail.extend_from_slice(b"\r\n");
+    mail
+}
+
+/// A mail with only a HTML part, like many newsletters.
+pub fn html_mail(from: &str, to: &str, subject: &str, html: &str) -> Vec<u8> {
+    format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: Wed, 1 Jan 2020 00:00:00 +0000\r\nMessage-ID: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/html; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+            from, to, subject, message_id(), html.replace("\n", "\r\n")).into_bytes()
+}
+
+/// A `multipart/alternative` mail with both a plain text and a HTML part.
+pub fn alternative_mail(from: &str, to: &str, subject: &str, text: &str, html: &str) -> Vec<u8> {
+    let boundary = "=_fake_alternative_42";
+    format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: Wed, 1 Jan 2020 00:00:00 +0000\r\nMessage-ID: {}\r\nMIME-Version: 1.0\r\nContent-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n\
+--{}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n\
+--{}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n\
+--{}--\r\n",
+            from, to, subject, message_id(), boundary, boundary, text.replace("\n", "\r\n"), boundary, html.replace("\n", "\r\n"), boundary).into_bytes()
+}
+
+/// A `multipart/mixed` mail with a text part and attachments of (filename, mimetype, content), encoded as BASE64.
+pub fn mail_with_attachments(from: &str, to: &str, subject: &str, text: &str, attachments: &[(&str, &str, &[u8])]) -> Vec<u8> {
+    let boundary = "=_fake_boundary_42";
+    let mut mail = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: Wed, 1 Jan 2020 00:00:00 +0000\r\nMessage-ID: {}\r\nMIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=\"{}\"\r\n\r\nThis is a multi-part message in MIME format.\r\n",
+                           from, to, subject, message_id(), boundary);
+
+    mail.push_str(&format!("--{}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n", boundary, text.replace("\n", "\r\n")));
+
+    for &(filename, mimetype, content) in attachments {
+        mail.push_str(&format!("--{}\r\nContent-Type: {}; name=\"{}\"\r\nContent-Transfer-Encoding: base64\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\r\n", boundary, mimetype, filename, filename));
+        let encoded = base64(content);
+        for line in encoded.as_bytes().chunks(76) {
+            mail.push_str(&String::from_utf8_lossy(line));
+            mail.push_str("\r\n");
+        }
+    }
+
+    mail.push_str(&format!("--{}--\r\n", boundary));
+    mail.into_bytes()
+}
+
+pub fn base64(data: &[u8]) -> String {
+    static BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut r = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                r.push(BASE64[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                r.push('=');
+            }
+        }
+    }
+    r
+}
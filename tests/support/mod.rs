@@ -0,0 +1,9 @@
+//! A fake IMAP server, a fake Slack and a way to run imap2slack against them,
+//! so the whole pipeline can be tested without a real mail server.
+#![allow(dead_code)]
+
+pub mod bridge;
+pub mod imap_server;
+pub mod mime;
+pub mod proxy;
+pub mod slack_receiver;
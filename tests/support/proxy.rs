@@ -0,0 +1,170 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ProxyKind {
+    Http,
+    Socks5,
+}
+
+/// A tunnel a client asked the fake proxy for.
+#[derive(Debug,Clone)]
+pub struct Tunnel {
+    /// As the client sent it, e.g. `localhost:1143` or `[::1]:1143`
+    pub destination: String,
+    /// The Proxy-Authorization header for HTTP, `username:password` for SOCKS5
+    pub auth: Option<String>,
+}
+
+/// Stands in for a HTTP CONNECT or SOCKS5 proxy. Every tunnel goes to the port of the destination
+/// on 127.0.0.1, so names like `localhost` or `::1` reach the fake IMAP server too.
+pub struct FakeProxy {
+    kind: ProxyKind,
+    port: u16,
+    tunnels: Arc<Mutex<Vec<Tunnel>>>,
+}
+
+impl FakeProxy {
+    pub fn start(kind: ProxyKind) -> FakeProxy {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the fake proxy");
+        let port = listener.local_addr().unwrap().port();
+        let tunnels = Arc::new(Mutex::new(Vec::new()));
+
+        let shared = tunnels.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let tunnels = shared.clone();
+                    thread::spawn(move || {
+                        let _ = handle(stream, kind, tunnels);
+                    });
+                }
+            }
+        });
+
+        FakeProxy { kind: kind, port: port, tunnels: tunnels }
+    }
+
+    /// The `url` for the `[proxy]` section.
+    pub fn url(&self) -> String {
+        match self.kind {
+            ProxyKind::Http => format!("http://127.0.0.1:{}", self.port),
+            ProxyKind::Socks5 => format!("socks5://127.0.0.1:{}", self.port),
+        }
+    }
+
+    pub fn tunnels(&self) -> Vec<Tunnel> {
+        self.tunnels.lock().unwrap().clone()
+    }
+}
+
+fn handle(mut client: TcpStream, kind: ProxyKind, tunnels: Arc<Mutex<Vec<Tunnel>>>) -> io::Result<()> {
+    let (tunnel, port) = match kind {
+        ProxyKind::Http => http_request(&mut client)?,
+        ProxyKind::Socks5 => socks5_request(&mut client)?,
+    };
+    tunnels.lock().unwrap().push(tunnel);
+
+    let server = TcpStream::connect(("127.0.0.1", port))?;
+    match kind {
+        ProxyKind::Http => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+        ProxyKind::Socks5 => client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?,
+    }
+
+    let (mut client_reader, mut server_writer) = (client.try_clone()?, server.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut server_writer);
+        let _ = server_writer.shutdown(Shutdown::Write);
+    });
+
+    let (mut server_reader, mut client_writer) = (server, client);
+    let _ = io::copy(&mut server_reader, &mut client_writer);
+    let _ = client_writer.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// `CONNECT host:port HTTP/1.1` and its header, read a byte at a time so nothing of the tunnel is read.
+fn http_request(client: &mut TcpStream) -> io::Result<(Tunnel, u16)> {
+    let mut request: Vec<u8> = Vec::new();
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte)?;
+        request.push(byte[0]);
+    }
+
+    let request = String::from_utf8_lossy(&request).into_owned();
+    let mut lines = request.lines();
+    let words: Vec<&str> = lines.next().unwrap_or("").split_whitespace().collect();
+    if words.len() != 3 || words[0] != "CONNECT" {
+        client.write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, request.clone()));
+    }
+
+    let auth = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("Proxy-Authorization") => Some(value.trim().to_string()),
+                _ => None,
+            }
+        })
+        .next();
+
+    let destination = words[1].to_string();
+    let port = destination.rsplit(':').next().and_then(|p| p.parse().ok()).unwrap_or(0);
+    Ok((Tunnel { destination: destination, auth: auth }, port))
+}
+
+/// The greeting, the username/password authentication if offered, and the CONNECT request of SOCKS5.
+fn socks5_request(client: &mut TcpStream) -> io::Result<(Tunnel, u16)> {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header)?;
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods)?;
+
+    let mut auth = None;
+    if methods.contains(&2) {
+        client.write_all(&[5, 2])?;
+        let mut version = [0u8; 1];
+        client.read_exact(&mut version)?;
+        let username = read_string(client)?;
+        let password = read_string(client)?;
+        client.write_all(&[1, 0])?;
+        auth = Some(format!("{}:{}", username, password));
+    } else {
+        client.write_all(&[5, 0])?;
+    }
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        },
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip)?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        },
+        _ => read_string(client)?,
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    let port = ((port[0] as u16) << 8) | port[1] as u16;
+
+    Ok((Tunnel { destination: format!("{}:{}", host, port), auth: auth }, port))
+}
+
+/// A string with its length in the first byte.
+fn read_string(client: &mut TcpStream) -> io::Result<String> {
+    let mut length = [0u8; 1];
+    client.read_exact(&mut length)?;
+    let mut data = vec![0u8; length[0] as usize];
+    client.read_exact(&mut data)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
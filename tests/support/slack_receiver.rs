@@ -0,0 +1,163 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+/// A HTTP request received by the fake Slack.
+#[derive(Debug,Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| v.as_str())
+    }
+
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+struct State {
+    requests: Vec<Request>,
+    failures: usize,
+    responses: Vec<(String, &'static str, String)>,
+}
+
+/// Stands in for Slack: records every request, answers `ok` (or the response set for the path)
+/// and can be told to fail with `500 Internal Server Error`. It also stands in for the OAuth2 token endpoint.
+pub struct FakeSlack {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeSlack {
+    pub fn start() -> FakeSlack {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the fake Slack");
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(State { requests: Vec::new(), failures: 0, responses: Vec::new() }));
+
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let state = shared.clone();
+                    thread::spawn(move || {
+                        let _ = handle(stream, state);
+                    });
+                }
+            }
+        });
+
+        FakeSlack { port: port, state: state }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    /// The incoming webhook to put in `[slack]`.
+    pub fn webhook_url(&self) -> String {
+        self.url("/hook")
+    }
+
+    /// The next `count` requests are answered with `500 Internal Server Error`.
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    /// Answers requests to `path` with a JSON body instead of `ok`, e.g. for the Web API.
+    pub fn respond(&self, path: &str, json: &str) {
+        self.respond_with_status(path, "200 OK", json);
+    }
+
+    /// Same as `respond` with another status, e.g. `400 Bad Request`.
+    pub fn respond_with_status(&self, path: &str, status: &'static str, json: &str) {
+        self.state.lock().unwrap().responses.push((path.to_string(), status, json.to_string()));
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The bodies of the successful posts to the webhook.
+    pub fn posts(&self) -> Vec<String> {
+        self.requests().into_iter().filter(|r| r.method == "POST" && r.path == "/hook").map(|r| r.body_str()).collect()
+    }
+
+    /// Waits until there are at least `count` posts. Returns `false` on timeout.
+    pub fn wait_for_posts(&self, count: usize, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.posts().len() >= count {
+                return true;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        false
+    }
+}
+
+fn handle(stream: TcpStream, state: Arc<Mutex<State>>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let method = words.next().unwrap_or("").to_string();
+    let path = words.next().unwrap_or("").to_string();
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+        }
+    }
+
+    let header = |name: &str| headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, ref v)| v.clone());
+    let mut body: Vec<u8> = Vec::new();
+    if let Some(length) = header("Content-Length").and_then(|l| l.parse::<usize>().ok()) {
+        body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+    } else if header("Transfer-Encoding").map(|t| t.eq_ignore_ascii_case("chunked")).unwrap_or(false) {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim().split(';').next().unwrap_or("0"), 16).unwrap_or(0);
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    let (status, content_type, response) = {
+        let mut state = state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            ("500 Internal Server Error", "text/plain", "internal_error".to_string())
+        } else {
+            state.requests.push(Request { method: method, path: path.clone(), headers: headers.clone(), body: body });
+            match state.responses.iter().find(|&&(ref p, _, _)| p == &path) {
+                Some(&(_, status, ref json)) => (status, "application/json", json.clone()),
+                None => ("200 OK", "text/plain", "ok".to_string()),
+            }
+        }
+    };
+
+    writer.write_all(format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                             status, content_type, response.len(), response).as_bytes())?;
+    writer.flush()
+}